@@ -0,0 +1,21 @@
+//! Records which commit the server was built from and how, for `/version`.
+//! This only happens with the `git_tag` feature, since it needs `git`.
+
+fn main() {
+    #[cfg(feature = "git_tag")]
+    emit_build_details().expect("Failed to gather build details");
+
+    #[cfg(not(feature = "git_tag"))]
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+#[cfg(feature = "git_tag")]
+fn emit_build_details() -> Result<(), Box<dyn std::error::Error>> {
+    vergen::EmitBuilder::builder()
+        .build_timestamp()
+        .git_sha(false)
+        .rustc_semver()
+        .emit()?;
+
+    Ok(())
+}
@@ -0,0 +1,425 @@
+use std::{
+    net::IpAddr,
+    sync::{Arc, RwLock},
+};
+
+use blake3::Hash;
+use chrono::{DateTime, TimeDelta, Utc};
+use log::info;
+use serde_with::{serde_as, DisplayFromStr};
+use rocket::{
+    get, post,
+    serde::{json::Json, Deserialize, Serialize},
+    FromFormField, State,
+};
+
+use crate::{
+    auth::AdminAuth,
+    database::{
+        reconcile_database, remove_entries, Event, EventKind, Mmid, MochiFile, Mochibase,
+        Visibility,
+    },
+    error::ApiError,
+    metrics::Metrics,
+    settings::{Settings, SharedSettings},
+    storage::StorageBackend,
+    strings::parse_time_string,
+    thumbnail::thumbnail_dir,
+    UploadGate,
+};
+
+/// The most entries which can be returned in a single page
+const MAX_PER_PAGE: usize = 500;
+
+/// The lowest Jaro-Winkler similarity a filename can have to a fuzzy search
+/// query and still be considered a match
+const FUZZY_THRESHOLD: f64 = 0.8;
+
+/// A summary of a file in the database, for administrators
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct FileSummary {
+    mmid: Mmid,
+    name: String,
+    size: u64,
+    mime_type: String,
+    upload_datetime: DateTime<Utc>,
+    expiry_datetime: DateTime<Utc>,
+    visibility: Visibility,
+}
+
+impl From<&MochiFile> for FileSummary {
+    fn from(entry: &MochiFile) -> Self {
+        Self {
+            mmid: entry.mmid().clone(),
+            name: entry.name().clone(),
+            size: entry.size(),
+            mime_type: entry.mime_type().clone(),
+            upload_datetime: entry.upload_datetime(),
+            expiry_datetime: entry.expiry(),
+            visibility: entry.visibility(),
+        }
+    }
+}
+
+/// A single page of a longer list of files
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct FilePage {
+    /// The total number of files across all pages
+    total: usize,
+
+    /// The page number, starting from 1
+    page: usize,
+    per_page: usize,
+    files: Vec<FileSummary>,
+}
+
+impl FilePage {
+    /// Take one page out of a list of files
+    fn paginate(entries: Vec<&MochiFile>, page: Option<usize>, per_page: Option<usize>) -> Self {
+        let page = page.unwrap_or(1).max(1);
+        let per_page = per_page.unwrap_or(50).clamp(1, MAX_PER_PAGE);
+
+        Self {
+            total: entries.len(),
+            page,
+            per_page,
+            files: entries
+                .into_iter()
+                .skip((page - 1).saturating_mul(per_page))
+                .take(per_page)
+                .map(FileSummary::from)
+                .collect(),
+        }
+    }
+}
+
+/// The order to list files in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, FromFormField)]
+pub enum ListSort {
+    /// Most recently uploaded first
+    #[default]
+    #[field(value = "upload")]
+    Upload,
+
+    /// Soonest to expire first
+    #[field(value = "expiry")]
+    Expiry,
+}
+
+/// List every file in the database, or only those with a certain visibility
+#[get("/admin/list?<page>&<per_page>&<sort>&<visibility>")]
+pub fn list(
+    db: &State<Arc<RwLock<Mochibase>>>,
+    _admin: AdminAuth,
+    page: Option<usize>,
+    per_page: Option<usize>,
+    sort: Option<ListSort>,
+    visibility: Option<Visibility>,
+) -> Json<FilePage> {
+    let db = db.read().unwrap();
+    let mut entries: Vec<&MochiFile> = db
+        .entries()
+        .filter(|e| visibility.is_none_or(|v| e.visibility() == v))
+        .collect();
+
+    match sort.unwrap_or_default() {
+        ListSort::Upload => entries.sort_by_key(|e| std::cmp::Reverse(e.upload_datetime())),
+        ListSort::Expiry => entries.sort_by_key(|e| e.expiry()),
+    }
+
+    Json(FilePage::paginate(entries, page, per_page))
+}
+
+/// The most recent events in the activity log, newest first
+#[get("/admin/events?<kind>&<limit>")]
+pub fn events(
+    db: &State<Arc<RwLock<Mochibase>>>,
+    _admin: AdminAuth,
+    kind: Option<EventKind>,
+    limit: Option<usize>,
+) -> Json<Vec<Event>> {
+    let limit = limit.unwrap_or(100).clamp(1, MAX_PER_PAGE);
+
+    Json(
+        db.read()
+            .unwrap()
+            .events()
+            .iter()
+            .rev()
+            .filter(|e| kind.is_none_or(|k| e.kind == k))
+            .take(limit)
+            .cloned()
+            .collect(),
+    )
+}
+
+/// Search for files by name. Matches any name containing the query, ignoring
+/// case. With `fuzzy`, names which are merely similar to the query also match,
+/// and the results are sorted by how similar they are.
+#[get("/admin/search?<q>&<fuzzy>&<page>&<per_page>")]
+pub fn search(
+    db: &State<Arc<RwLock<Mochibase>>>,
+    _admin: AdminAuth,
+    q: &str,
+    fuzzy: bool,
+    page: Option<usize>,
+    per_page: Option<usize>,
+) -> Json<FilePage> {
+    let query = q.to_lowercase();
+    let db = db.read().unwrap();
+
+    let mut matches: Vec<(f64, &MochiFile)> = db
+        .entries()
+        .filter_map(|entry| {
+            let name = entry.name().to_lowercase();
+            let score = if name.contains(&query) {
+                1.0
+            } else if fuzzy {
+                strsim::jaro_winkler(&query, &name)
+            } else {
+                0.0
+            };
+
+            (score >= FUZZY_THRESHOLD).then_some((score, entry))
+        })
+        .collect();
+
+    // Best matches first, then the most recently uploaded
+    matches.sort_by(|(a_score, a), (b_score, b)| {
+        b_score
+            .total_cmp(a_score)
+            .then_with(|| b.upload_datetime().cmp(&a.upload_datetime()))
+    });
+
+    let entries = matches.into_iter().map(|(_, entry)| entry).collect();
+    Json(FilePage::paginate(entries, page, per_page))
+}
+
+/// A stored file and the entries which share it
+#[serde_as]
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct HashSummary {
+    #[serde_as(as = "DisplayFromStr")]
+    hash: Hash,
+
+    /// The size of the stored file in bytes
+    size: u64,
+
+    /// The entries sharing the file, oldest first
+    mmids: Vec<Mmid>,
+}
+
+/// How much storage deduplication is saving, with one page of the stored
+/// files
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct HashPage {
+    /// The number of stored files, which is the total across all pages
+    total: usize,
+
+    /// The size of every stored file added up, which is what is actually
+    /// stored
+    unique_bytes: u64,
+
+    /// The size of every entry added up, which is what would be stored
+    /// without deduplication
+    logical_bytes: u64,
+
+    page: usize,
+    per_page: usize,
+    hashes: Vec<HashSummary>,
+}
+
+/// List the stored files and which entries refer to each of them, with the
+/// most shared first
+#[get("/admin/hashes?<page>&<per_page>")]
+pub fn hashes(
+    db: &State<Arc<RwLock<Mochibase>>>,
+    _admin: AdminAuth,
+    page: Option<usize>,
+    per_page: Option<usize>,
+) -> Json<HashPage> {
+    let page = page.unwrap_or(1).max(1);
+    let per_page = per_page.unwrap_or(50).clamp(1, MAX_PER_PAGE);
+    let db = db.read().unwrap();
+
+    let mut hashes: Vec<HashSummary> = db
+        .hashes()
+        .map(|hash| {
+            let mut mmids: Vec<Mmid> = db.get_hash(hash).into_iter().flatten().cloned().collect();
+            mmids.sort_by_key(|m| db.get(m).map(|e| e.upload_datetime()));
+
+            // Entries sharing a file are all the same size
+            let size = mmids.first().and_then(|m| db.get(m)).map(|e| e.size()).unwrap_or_default();
+
+            HashSummary { hash: *hash, size, mmids }
+        })
+        .collect();
+
+    let unique_bytes = hashes.iter().map(|h| h.size).sum();
+    let logical_bytes = hashes.iter().map(|h| h.size * h.mmids.len() as u64).sum();
+
+    hashes.sort_by_key(|h| std::cmp::Reverse((h.mmids.len(), h.size)));
+
+    Json(HashPage {
+        total: hashes.len(),
+        unique_bytes,
+        logical_bytes,
+        page,
+        per_page,
+        hashes: hashes
+            .into_iter()
+            .skip((page - 1).saturating_mul(per_page))
+            .take(per_page)
+            .collect(),
+    })
+}
+
+/// Remove entries whose file is missing from storage, returning their MMIDs
+#[post("/admin/reconcile")]
+pub async fn reconcile(
+    db: &State<Arc<RwLock<Mochibase>>>,
+    storage: &State<Arc<dyn StorageBackend>>,
+    metrics: &State<Arc<Metrics>>,
+    settings: &Settings,
+    _admin: AdminAuth,
+) -> Json<Vec<Mmid>> {
+    Json(reconcile_database(db, storage.as_ref(), metrics, &thumbnail_dir(settings)).await)
+}
+
+/// Make a file expire now, so it is removed the next time the database is
+/// cleaned. With `delete`, it is removed straight away instead, along with its
+/// file if nothing else shares it.
+#[allow(clippy::too_many_arguments)]
+#[post("/admin/f/<mmid>/expire?<delete>")]
+pub async fn expire(
+    db: &State<Arc<RwLock<Mochibase>>>,
+    storage: &State<Arc<dyn StorageBackend>>,
+    metrics: &State<Arc<Metrics>>,
+    settings: &Settings,
+    admin: AdminAuth,
+    ip: Option<IpAddr>,
+    mmid: &str,
+    delete: bool,
+) -> Result<Json<FileSummary>, ApiError> {
+    let mmid: Mmid = mmid
+        .try_into()
+        .map_err(|_| ApiError::NotFound("Invalid MMID".into()))?;
+
+    let summary = {
+        let mut database = db.write().unwrap();
+        let entry = database
+            .set_expiry(&mmid, Utc::now())
+            .ok_or(ApiError::NotFound("File not found".into()))?;
+        database.record_event(EventKind::Expire, &mmid, ip, settings.max_events);
+        database.save()?;
+
+        FileSummary::from(&entry)
+    };
+    info!("{} made {mmid} expire", admin.username);
+
+    if delete {
+        let thumb_dir = thumbnail_dir(settings);
+        let (_, _, removed_bytes) =
+            remove_entries(db, storage.as_ref(), &thumb_dir, std::slice::from_ref(&mmid)).await;
+        metrics.remove_stored(removed_bytes);
+        info!("{} deleted {mmid}", admin.username);
+    }
+
+    Ok(Json(summary))
+}
+
+/// A request to keep a file for longer
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ExtendRequest {
+    /// How long to keep the file from now, like `6h` or `1d12h`
+    duration: String,
+}
+
+/// Change when a file expires, counting from now. Unlike uploaders,
+/// administrators aren't held to the server's duration limits, and can rescue
+/// files which have expired but not been cleaned up yet.
+#[post("/admin/f/<mmid>/extend", data = "<body>")]
+pub fn extend(
+    db: &State<Arc<RwLock<Mochibase>>>,
+    settings: &Settings,
+    admin: AdminAuth,
+    ip: Option<IpAddr>,
+    mmid: &str,
+    body: Json<ExtendRequest>,
+) -> Result<Json<FileSummary>, ApiError> {
+    let mmid: Mmid = mmid
+        .try_into()
+        .map_err(|_| ApiError::NotFound("Invalid MMID".into()))?;
+
+    let duration = parse_time_string(&body.duration)
+        .map_err(|e| ApiError::Invalid(e.to_string()))?;
+    if duration <= TimeDelta::zero() {
+        return Err(ApiError::Invalid("Duration must be positive".into()));
+    }
+    let expiry = Utc::now()
+        .checked_add_signed(duration)
+        .ok_or(ApiError::Invalid("Duration too large".into()))?;
+
+    let mut database = db.write().unwrap();
+    let entry = database
+        .set_expiry(&mmid, expiry)
+        .ok_or(ApiError::NotFound("File not found".into()))?;
+    database.record_event(EventKind::Extend, &mmid, ip, settings.max_events);
+    database.save()?;
+
+    info!("{} set {mmid} to expire at {expiry}", admin.username);
+
+    Ok(Json(FileSummary::from(&entry)))
+}
+
+/// Whether the server is in read-only mode
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ReadOnlyStatus {
+    read_only: bool,
+}
+
+/// Switch read-only mode on or off, where uploads are refused but downloads
+/// keep working, such as while the server is being backed up
+#[post("/admin/readonly?<enabled>")]
+pub fn read_only(
+    gate: &State<Arc<UploadGate>>,
+    _admin: AdminAuth,
+    enabled: bool,
+) -> Json<ReadOnlyStatus> {
+    gate.set_read_only(enabled);
+    info!("Read-only mode {}", if enabled { "enabled" } else { "disabled" });
+
+    Json(ReadOnlyStatus { read_only: enabled })
+}
+
+/// The settings which changed in the file but can't take effect until the
+/// server is restarted
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ReloadStatus {
+    restart_needed: Vec<&'static str>,
+}
+
+/// Reload the settings file without restarting the server, the same as
+/// sending the process `SIGHUP`
+#[post("/admin/reload")]
+pub fn reload(
+    settings: &State<Arc<SharedSettings>>,
+    gate: &State<Arc<UploadGate>>,
+    admin: AdminAuth,
+) -> Result<Json<ReloadStatus>, ApiError> {
+    let restart_needed = settings.reload(gate).map_err(|e| match e.kind() {
+        std::io::ErrorKind::InvalidData => ApiError::Invalid(e.to_string()),
+        _ => ApiError::Internal(format!("Failed to reload settings: {e}")),
+    })?;
+
+    info!("{} reloaded the settings", admin.username);
+
+    Ok(Json(ReloadStatus { restart_needed }))
+}
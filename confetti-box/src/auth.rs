@@ -0,0 +1,288 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use std::sync::{Arc, OnceLock, RwLock};
+
+use base64::{prelude::BASE64_STANDARD, Engine as _};
+use blake3::Hash;
+use rocket::{
+    catch,
+    http::{Header, Status},
+    request::{FromRequest, Outcome},
+    serde::{json::Json, Serialize},
+    tokio::task,
+    Request, Responder,
+};
+use uuid::Uuid;
+
+use crate::{
+    database::{Chunkbase, MochiFile, Visibility},
+    settings::{Settings, UserSettings},
+};
+
+/// A request guard for upload endpoints, which succeeds if authentication is
+/// disabled, or if the request contains valid Basic auth credentials for a
+/// configured user or a valid API key
+pub struct AuthUser {
+    /// The name of the authenticated user, or [`None`] if authentication is
+    /// disabled or an API key was used
+    pub username: Option<String>,
+
+    /// A hash identifying the credentials the user authenticated with
+    pub credentials: Option<Hash>,
+}
+
+/// A request guard for requests about a chunked upload in progress. Requests
+/// with the same credentials the upload was started with are let through
+/// without checking them again, so uploads sent in many chunks don't run
+/// Argon2 for every one. Other requests are checked like [`AuthUser`].
+pub struct ChunkAuth;
+
+/// A request guard for download endpoints, which only requires valid
+/// credentials if `protect_downloads` is enabled in the auth settings
+pub struct DownloadAuth {
+    /// The name of the authenticated user. Credentials are checked even when
+    /// downloads aren't protected, since private files need them.
+    pub username: Option<String>,
+
+    /// The deletion token from the `token` query parameter, which lets the
+    /// uploader of a private file see it without credentials
+    pub token: Option<String>,
+}
+
+impl DownloadAuth {
+    /// Whether this request is allowed to see a file, given its visibility
+    pub fn can_view(&self, entry: &MochiFile) -> bool {
+        match entry.visibility() {
+            Visibility::Public | Visibility::Unlisted => true,
+            Visibility::Private => {
+                self.username.is_some() || self.token.as_deref().is_some_and(|t| entry.token_matches(t))
+            }
+        }
+    }
+}
+
+/// A request guard for administration endpoints, which succeeds only if the
+/// request contains valid credentials for a user marked as an admin. These
+/// endpoints are unavailable if authentication is disabled.
+pub struct AdminAuth {
+    pub username: String,
+}
+
+/// Check the `Authorization` header of a request against the configured users,
+/// returning the user if the credentials are valid
+async fn check_credentials<'a>(
+    req: &Request<'_>,
+    users: &'a [UserSettings],
+) -> Option<&'a UserSettings> {
+    let header = req.headers().get_one("Authorization")?;
+    let encoded = header.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64_STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+
+    let user = users.iter().find(|u| u.username == username)?;
+
+    if verify_password(password, &user.password_hash).await {
+        Some(user)
+    } else {
+        None
+    }
+}
+
+/// A hash of the `Authorization` header of a request, which identifies the
+/// credentials in it without keeping them around. It's keyed with a secret
+/// which only lasts as long as the process, so it can't be used to guess the
+/// credentials faster than Argon2 allows.
+fn credentials_hash(req: &Request<'_>) -> Option<Hash> {
+    static KEY: OnceLock<[u8; 32]> = OnceLock::new();
+
+    let header = req.headers().get_one("Authorization")?;
+    let key = KEY.get_or_init(rand::random);
+    Some(blake3::keyed_hash(key, header.as_bytes()))
+}
+
+/// Check the `X-Api-Key` header of a request against the configured keys, or
+/// the `key` query parameter for clients which can't set headers, like
+/// websockets in browsers
+fn check_api_key(req: &Request<'_>, keys: &[String]) -> bool {
+    let Some(key) = req
+        .headers()
+        .get_one("X-Api-Key")
+        .or_else(|| req.query_value::<&str>("key").and_then(Result::ok))
+    else {
+        return false;
+    };
+
+    // Hashes are compared in constant time, so how long this takes doesn't
+    // reveal how much of a key was right
+    let key = blake3::hash(key.as_bytes());
+    keys.iter()
+        .fold(false, |found, k| found | (blake3::hash(k.as_bytes()) == key))
+}
+
+/// Hash a password with Argon2 and a random salt, returning a PHC string
+pub async fn hash_password(password: &str) -> Option<String> {
+    let password = password.to_string();
+
+    // Argon2 is intentionally slow, so don't do it on the async runtime
+    task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .ok()
+            .map(|h| h.to_string())
+    })
+    .await
+    .ok()?
+}
+
+/// Check a password against an Argon2 PHC string
+pub async fn verify_password(password: &str, hash: &str) -> bool {
+    let password = password.to_string();
+    let hash = hash.to_string();
+
+    task::spawn_blocking(move || {
+        let Ok(hash) = PasswordHash::new(&hash) else {
+            return false;
+        };
+
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+    .await
+    .unwrap_or(false)
+}
+
+async fn authenticate(req: &Request<'_>, downloading: bool) -> Outcome<Option<String>, ()> {
+    let settings = Settings::for_request(req);
+    let auth = match &settings.auth {
+        Some(a) if !downloading || a.protect_downloads => Some(a),
+        _ => None,
+    };
+
+    // API keys are only for uploading
+    let api_keys: &[String] = if downloading { &[] } else { &settings.api_keys };
+    if auth.is_none() && api_keys.is_empty() {
+        return Outcome::Success(None);
+    }
+    if !api_keys.is_empty() && check_api_key(req, api_keys) {
+        return Outcome::Success(None);
+    }
+
+    match auth {
+        Some(auth) => match check_credentials(req, &auth.users).await {
+            Some(user) => Outcome::Success(Some(user.username.clone())),
+            None => Outcome::Error((Status::Unauthorized, ())),
+        },
+        None => Outcome::Error((Status::Unauthorized, ())),
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthUser {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        authenticate(req, false)
+            .await
+            .map(|username| Self {
+                credentials: username.as_ref().and_then(|_| credentials_hash(req)),
+                username,
+            })
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ChunkAuth {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        // Every chunked upload route has the UUID of the upload third
+        let uuid = req.routed_segment(2).and_then(|s| Uuid::parse_str(s).ok());
+        if let (Some(uuid), Some(credentials)) = (uuid, credentials_hash(req)) {
+            let chunk_db = req.rocket().state::<Arc<RwLock<Chunkbase>>>().unwrap();
+            let started_with = chunk_db
+                .read()
+                .unwrap()
+                .get_file(&uuid)
+                .and_then(|(_, info)| info.credentials);
+            if started_with == Some(credentials) {
+                return Outcome::Success(Self);
+            }
+        }
+
+        authenticate(req, false).await.map(|_| Self)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DownloadAuth {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let mut username = match authenticate(req, true).await {
+            Outcome::Success(username) => username,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(f) => return Outcome::Forward(f),
+        };
+
+        let settings = Settings::for_request(req);
+        if let (None, Some(auth)) = (&username, &settings.auth) {
+            username = check_credentials(req, &auth.users)
+                .await
+                .map(|u| u.username.clone());
+        }
+
+        Outcome::Success(Self {
+            username,
+            token: req.query_value::<String>("token").and_then(Result::ok),
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminAuth {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let settings = Settings::for_request(req);
+        let Some(auth) = &settings.auth else {
+            return Outcome::Error((Status::Forbidden, ()));
+        };
+
+        match check_credentials(req, &auth.users).await {
+            Some(user) if user.admin => Outcome::Success(Self {
+                username: user.username.clone(),
+            }),
+            Some(_) => Outcome::Error((Status::Forbidden, ())),
+            None => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct UnauthorizedResponse {
+    status: bool,
+    message: String,
+}
+
+#[derive(Responder)]
+#[response(status = 401)]
+pub struct Unauthorized {
+    inner: Json<UnauthorizedResponse>,
+    www_authenticate: Header<'static>,
+}
+
+/// Respond to unauthenticated requests, asking the client for Basic auth
+#[catch(401)]
+pub fn unauthorized() -> Unauthorized {
+    Unauthorized {
+        inner: Json(UnauthorizedResponse {
+            status: false,
+            message: "Authentication required".into(),
+        }),
+        www_authenticate: Header::new("WWW-Authenticate", "Basic realm=\"Confetti-Box\", charset=\"UTF-8\""),
+    }
+}
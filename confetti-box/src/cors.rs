@@ -0,0 +1,94 @@
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{Method, Status},
+    Request, Response,
+};
+
+use crate::settings::CorsSettings;
+
+/// The paths, relative to the root path, which browsers on other origins are
+/// allowed to use
+const CORS_PATHS: [&str; 3] = ["/upload", "/info", "/f/"];
+
+/// Adds CORS headers to responses for the API, so browser based clients on the
+/// allowed origins can use it, and answers their preflight requests
+pub struct Cors {
+    settings: CorsSettings,
+    root_path: String,
+}
+
+impl Cors {
+    pub fn new(settings: CorsSettings, root_path: &str) -> Self {
+        Self {
+            settings,
+            root_path: root_path.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Whether a request is for one of the paths CORS is enabled on
+    fn covers(&self, req: &Request<'_>) -> bool {
+        req.uri()
+            .path()
+            .as_str()
+            .strip_prefix(&self.root_path)
+            .is_some_and(|path| CORS_PATHS.iter().any(|p| path.starts_with(p)))
+    }
+
+    /// The value for `Access-Control-Allow-Origin`, if this origin is allowed
+    fn allowed_origin(&self, origin: &str) -> Option<String> {
+        let origins = &self.settings.allowed_origins;
+        if origins.iter().any(|o| o == origin) {
+            Some(origin.to_string())
+        } else if origins.iter().any(|o| o == "*") {
+            // Browsers refuse a wildcard on requests with credentials
+            if self.settings.allow_credentials {
+                Some(origin.to_string())
+            } else {
+                Some("*".to_string())
+            }
+        } else {
+            None
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, resp: &mut Response<'r>) {
+        if !self.covers(req) {
+            return;
+        }
+
+        let Some(origin) = req
+            .headers()
+            .get_one("Origin")
+            .and_then(|o| self.allowed_origin(o))
+        else {
+            return;
+        };
+
+        resp.set_raw_header("Access-Control-Allow-Origin", origin);
+        resp.adjoin_raw_header("Vary", "Origin");
+        if self.settings.allow_credentials {
+            resp.set_raw_header("Access-Control-Allow-Credentials", "true");
+        }
+
+        // There are no routes for preflight requests, so they are answered
+        // here instead of with a 404
+        if req.method() == Method::Options {
+            resp.set_status(Status::NoContent);
+            resp.set_sized_body(0, std::io::Cursor::new(""));
+            resp.remove_header("Content-Type");
+            resp.set_raw_header("Access-Control-Allow-Methods", self.settings.allowed_methods.join(", "));
+            resp.set_raw_header("Access-Control-Allow-Headers", self.settings.allowed_headers.join(", "));
+            resp.set_raw_header("Access-Control-Max-Age", self.settings.max_age_seconds.to_string());
+        }
+    }
+}
@@ -1,12 +1,17 @@
 use std::{
-    collections::{hash_map::Values, HashMap, HashSet},
+    collections::{hash_map::{Keys, Values}, HashMap, HashSet, VecDeque},
     ffi::OsStr,
+    fmt,
     fs::{self, File},
     io::{self, Write},
+    net::IpAddr,
+    ops::RangeInclusive,
     path::{Path, PathBuf},
     sync::{Arc, RwLock},
+    time::Duration,
 };
 
+use bincode::Options as _;
 use blake3::Hash;
 use chrono::{DateTime, TimeDelta, Utc};
 use ciborium::{from_reader, into_writer};
@@ -15,10 +20,18 @@ use rand::distributions::{Alphanumeric, DistString};
 use rocket::{
     form::{self, FromFormField, ValueField},
     serde::{Deserialize, Serialize},
+    tokio::time,
 };
 use serde_with::{serde_as, DisplayFromStr};
 use uuid::Uuid;
 
+use crate::{
+    metrics::Metrics,
+    storage::StorageBackend,
+    thumbnail::remove_thumbnail,
+    utils::{FileHasher, HashAlgorithm},
+};
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Mochibase {
     path: PathBuf,
@@ -28,6 +41,14 @@ pub struct Mochibase {
 
     /// All entries in the database
     entries: HashMap<Mmid, MochiFile>,
+
+    /// Recent activity, oldest first
+    #[serde(default)]
+    events: VecDeque<Event>,
+
+    /// Hashes whose stored data is being added or removed right now
+    #[serde(skip)]
+    claimed: HashSet<Hash>,
 }
 
 impl Mochibase {
@@ -38,6 +59,8 @@ impl Mochibase {
             path: path.as_ref().to_path_buf(),
             entries: HashMap::new(),
             hashes: HashMap::new(),
+            events: VecDeque::new(),
+            claimed: HashSet::new(),
         };
 
         // Save the database initially after creating it
@@ -59,27 +82,98 @@ impl Mochibase {
         if !path.as_ref().exists() {
             Self::new(path)
         } else {
-            Self::open(path)
+            Self::open(path).or_else(|e| {
+                warn!("Could not open the database ({e}), trying older formats");
+                Self::import_from(path, None).map_err(|_| e)
+            })
         }
     }
 
-    /// Save the database to its file
+    /// Read a database stored in `format`, or in any known format if `None`,
+    /// and rewrite it in the current format. The original file is kept next
+    /// to it with the `old` extension.
+    pub fn import_from<P: AsRef<Path>>(path: &P, format: Option<DatabaseFormat>) -> Result<Self, io::Error> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)?;
+
+        let formats = match format {
+            Some(f) => vec![f],
+            None => vec![DatabaseFormat::Cbor, DatabaseFormat::Bincode],
+        };
+        let (mut database, found) = formats
+            .into_iter()
+            .find_map(|f| Self::decode(&bytes, f).map(|db| (db, f)))
+            .ok_or(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "database is not in any known format",
+            ))?;
+
+        let backup = path.with_extension("old");
+        fs::copy(path, &backup)?;
+
+        database.path = path.to_path_buf();
+        database.save()?;
+
+        info!(
+            "Migrated {} entries from a {found} database, the original was kept at {}",
+            database.entries.len(),
+            backup.display(),
+        );
+
+        Ok(database)
+    }
+
+    /// Decode a database stored in `format`, if it is one
+    fn decode(bytes: &[u8], format: DatabaseFormat) -> Option<Self> {
+        match format {
+            DatabaseFormat::Cbor => from_reader(bytes).ok(),
+            DatabaseFormat::Bincode => bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .with_limit(bytes.len() as u64)
+                .deserialize::<LegacyMochibase>(bytes)
+                .ok()
+                .map(Self::from),
+        }
+    }
+
+    /// Save the database to its file.
+    ///
+    /// The database is written to a temporary file next to the real one, which
+    /// then replaces it, so a crash while saving never leaves a partially
+    /// written database behind.
     pub fn save(&self) -> Result<(), io::Error> {
-        // Create a file and write the LZ4 compressed stream into it
-        let mut file = File::create(self.path.with_extension("bkp"))?;
+        let temp_path = self.path.with_extension("bkp");
+
+        let mut file = File::create(&temp_path)?;
         into_writer(self, &mut file)
             .map_err(|e| io::Error::other(format!("failed to save database: {e}")))?;
         file.flush()?;
 
-        fs::rename(self.path.with_extension("bkp"), &self.path).unwrap();
+        // Make sure the data is on disk before it replaces the old database
+        file.sync_all()?;
+        drop(file);
+
+        fs::rename(&temp_path, &self.path)?;
+
+        // Persist the rename itself, where the platform allows it
+        if let Some(dir) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            if let Ok(dir) = File::open(dir) {
+                let _ = dir.sync_all();
+            }
+        }
 
         Ok(())
     }
 
     /// Insert a [`MochiFile`] into the database.
     ///
-    /// If the database already contained this value, then `false` is returned.
+    /// If the database already contained this value, or another entry with
+    /// the same [`Mmid`], then `false` is returned.
     pub fn insert(&mut self, mmid: &Mmid, entry: MochiFile) -> bool {
+        if self.entries.contains_key(mmid) {
+            return false;
+        }
+
         if let Some(s) = self.hashes.get_mut(&entry.hash) {
             // If the database already contains the hash, make sure the file is unique
             if !s.insert(mmid.clone()) {
@@ -114,6 +208,17 @@ impl Mochibase {
         true
     }
 
+    /// Claim the stored data for a hash while it is being removed, returning
+    /// `false` if an upload or another removal already has it
+    pub fn claim_hash(&mut self, hash: &Hash) -> bool {
+        self.claimed.insert(*hash)
+    }
+
+    /// Give back a claim taken with [`Mochibase::claim_hash`]
+    pub fn release_hash(&mut self, hash: &Hash) {
+        self.claimed.remove(hash);
+    }
+
     /// Remove a hash from the database entirely.
     ///
     /// Will not remove (returns [`Some(false)`] if hash contains references.
@@ -140,6 +245,20 @@ impl Mochibase {
         self.entries.get(mmid)
     }
 
+    /// Record a download of an entry by its [`Mmid`], returning a copy of the
+    /// entry. Returns [`None`] if the entry does not exist or has already been
+    /// downloaded as many times as it is allowed.
+    pub fn record_download(&mut self, mmid: &Mmid) -> Option<MochiFile> {
+        let entry = self.entries.get_mut(mmid)?;
+        if entry.downloads_exhausted() {
+            return None;
+        }
+
+        entry.download_count += 1;
+
+        Some(entry.clone())
+    }
+
     pub fn get_hash(&self, hash: &Hash) -> Option<&HashSet<Mmid>> {
         self.hashes.get(hash)
     }
@@ -147,6 +266,197 @@ impl Mochibase {
     pub fn entries(&self) -> Values<'_, Mmid, MochiFile> {
         self.entries.values()
     }
+
+    /// Every unique file hash in the database
+    pub fn hashes(&self) -> Keys<'_, Hash, HashSet<Mmid>> {
+        self.hashes.keys()
+    }
+
+    /// Change the name of an entry, returning a copy of the renamed entry.
+    /// Returns [`None`] if the entry does not exist.
+    pub fn rename(&mut self, mmid: &Mmid, name: String) -> Option<MochiFile> {
+        let entry = self.entries.get_mut(mmid)?;
+        entry.name = name;
+
+        Some(entry.clone())
+    }
+
+    /// Change when an entry expires, returning a copy of the updated entry.
+    /// Returns [`None`] if the entry does not exist.
+    pub fn set_expiry(&mut self, mmid: &Mmid, expiry: DateTime<Utc>) -> Option<MochiFile> {
+        let entry = self.entries.get_mut(mmid)?;
+        entry.expiry_datetime = expiry;
+
+        Some(entry.clone())
+    }
+
+    /// Add an event to the activity log, forgetting the oldest events to keep
+    /// at most `max_events`
+    pub fn record_event(&mut self, kind: EventKind, mmid: &Mmid, ip: Option<IpAddr>, max_events: usize) {
+        if max_events == 0 {
+            return;
+        }
+
+        while self.events.len() >= max_events {
+            self.events.pop_front();
+        }
+
+        self.events.push_back(Event {
+            kind,
+            mmid: mmid.clone(),
+            timestamp: Utc::now(),
+            ip,
+        });
+    }
+
+    /// Every event in the activity log, oldest first
+    pub fn events(&self) -> &VecDeque<Event> {
+        &self.events
+    }
+
+    /// The total size of every unique file in the database, in bytes
+    pub fn stored_bytes(&self) -> u64 {
+        self.hashes
+            .values()
+            .filter_map(|mmids| mmids.iter().find_map(|m| self.entries.get(m)))
+            .map(|entry| entry.size)
+            .sum()
+    }
+
+    /// Set the cached size of every entry with the given hash
+    pub fn set_size(&mut self, hash: &Hash, size: u64) {
+        let Some(mmids) = self.hashes.get(hash) else {
+            return;
+        };
+
+        for mmid in mmids {
+            if let Some(entry) = self.entries.get_mut(mmid) {
+                entry.size = size;
+            }
+        }
+    }
+}
+
+/// The formats a [`Mochibase`] has been stored in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DatabaseFormat {
+    /// The current format
+    Cbor,
+
+    /// The format used by older versions, before files had sizes, tokens,
+    /// or passwords
+    Bincode,
+}
+
+impl fmt::Display for DatabaseFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cbor => write!(f, "CBOR"),
+            Self::Bincode => write!(f, "bincode"),
+        }
+    }
+}
+
+/// A database as stored by older versions in the bincode format
+#[derive(Deserialize, Serialize)]
+struct LegacyMochibase {
+    path: PathBuf,
+    hashes: HashMap<Hash, HashSet<Mmid>>,
+    entries: HashMap<Mmid, LegacyMochiFile>,
+}
+
+#[serde_as]
+#[derive(Deserialize, Serialize)]
+struct LegacyMochiFile {
+    mmid: Mmid,
+    name: String,
+    mime_type: String,
+    #[serde_as(as = "DisplayFromStr")]
+    hash: Hash,
+    upload_datetime: DateTime<Utc>,
+    expiry_datetime: DateTime<Utc>,
+}
+
+impl From<LegacyMochibase> for Mochibase {
+    fn from(legacy: LegacyMochibase) -> Self {
+        // Sizes are filled in from storage on startup
+        let entries = legacy
+            .entries
+            .into_iter()
+            .map(|(mmid, e)| {
+                let entry = MochiFile::new(
+                    e.mmid,
+                    e.name,
+                    e.mime_type,
+                    e.hash,
+                    e.upload_datetime,
+                    e.expiry_datetime,
+                );
+                (mmid, entry)
+            })
+            .collect();
+
+        Self {
+            path: legacy.path,
+            hashes: legacy.hashes,
+            entries,
+            events: VecDeque::new(),
+            claimed: HashSet::new(),
+        }
+    }
+}
+
+/// Something which happened to a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    #[field(value = "upload")]
+    Upload,
+    #[field(value = "download")]
+    Download,
+
+    /// Deleted by the uploader, not expired
+    #[field(value = "delete")]
+    Delete,
+
+    /// Made to expire early by an administrator
+    #[field(value = "expire")]
+    Expire,
+
+    /// Kept for longer by an administrator
+    #[field(value = "extend")]
+    Extend,
+}
+
+/// An entry in the activity log
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Event {
+    pub kind: EventKind,
+    pub mmid: Mmid,
+    pub timestamp: DateTime<Utc>,
+
+    /// The IP address of the client, if known
+    pub ip: Option<IpAddr>,
+}
+
+/// Who is able to see a file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    /// Anyone with the link can download the file, and it is marked as public
+    /// in listings
+    #[field(value = "public")]
+    Public,
+
+    /// Anyone with the link can download the file
+    #[default]
+    #[field(value = "unlisted")]
+    Unlisted,
+
+    /// Only authenticated users, or the uploader with the file's deletion
+    /// token, can download the file
+    #[field(value = "private")]
+    Private,
 }
 
 /// An entry in the database storing metadata about a file
@@ -162,15 +472,44 @@ pub struct MochiFile {
     /// The MIME type of the file
     mime_type: String,
 
-    /// The Blake3 hash of the file
+    /// The hash of the file
     #[serde_as(as = "DisplayFromStr")]
     hash: Hash,
 
+    /// The algorithm `hash` was made with. Entries from before this could be
+    /// chosen were always hashed with Blake3.
+    #[serde(default)]
+    algo: HashAlgorithm,
+
+    /// The size of the file in bytes
+    #[serde(default)]
+    size: u64,
+
     /// The datetime when the file was uploaded
     upload_datetime: DateTime<Utc>,
 
     /// The datetime when the file is set to expire
     expiry_datetime: DateTime<Utc>,
+
+    /// A secret token which allows the uploader to delete the file early
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    delete_token: String,
+
+    /// The number of times the file may be downloaded before it expires
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_downloads: Option<u32>,
+
+    /// The number of times the file has been downloaded
+    #[serde(default)]
+    download_count: u32,
+
+    /// An Argon2 hash of the password required to access the file, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password_hash: Option<String>,
+
+    /// Who is able to see the file
+    #[serde(default)]
+    visibility: Visibility,
 }
 
 impl MochiFile {
@@ -188,8 +527,67 @@ impl MochiFile {
             name,
             mime_type,
             hash,
+            algo: HashAlgorithm::Blake3,
+            size: 0,
             upload_datetime: upload,
             expiry_datetime: expiry,
+            delete_token: Alphanumeric.sample_string(&mut rand::thread_rng(), 32),
+            max_downloads: None,
+            download_count: 0,
+            password_hash: None,
+            visibility: Visibility::Unlisted,
+        }
+    }
+
+    /// Require a password to access this file, with the password already
+    /// hashed using [`crate::auth::hash_password`].
+    pub fn with_password_hash(self, password_hash: Option<String>) -> Self {
+        Self {
+            password_hash,
+            ..self
+        }
+    }
+
+    /// Set the algorithm this file's hash was made with.
+    pub fn with_algo(self, algo: HashAlgorithm) -> Self {
+        Self {
+            algo,
+            ..self
+        }
+    }
+
+    /// Set the size of this file in bytes.
+    pub fn with_size(self, size: u64) -> Self {
+        Self {
+            size,
+            ..self
+        }
+    }
+
+    /// Limit the number of times this file may be downloaded before it
+    /// expires.
+    pub fn with_max_downloads(self, max_downloads: Option<u32>) -> Self {
+        Self {
+            max_downloads,
+            ..self
+        }
+    }
+
+    /// Set who is able to see this file.
+    pub fn with_visibility(self, visibility: Visibility) -> Self {
+        Self {
+            visibility,
+            ..self
+        }
+    }
+
+    /// Get a copy of this entry with any secrets (like the deletion token)
+    /// removed, suitable for showing to anyone who knows the [`Mmid`].
+    pub fn redacted(&self) -> Self {
+        Self {
+            delete_token: String::new(),
+            password_hash: None,
+            ..self.clone()
         }
     }
 
@@ -203,13 +601,47 @@ impl MochiFile {
 
     pub fn is_expired(&self) -> bool {
         let datetime = Utc::now();
-        datetime > self.expiry_datetime
+        datetime > self.expiry_datetime || self.downloads_exhausted()
+    }
+
+    pub fn visibility(&self) -> Visibility {
+        self.visibility
+    }
+
+    /// Check whether a deletion token belongs to this file
+    pub fn token_matches(&self, token: &str) -> bool {
+        // Compared in constant time, so how long this takes doesn't reveal
+        // how much of the token was right
+        !self.delete_token.is_empty()
+            && blake3::hash(token.as_bytes()) == blake3::hash(self.delete_token.as_bytes())
+    }
+
+    pub fn max_downloads(&self) -> Option<u32> {
+        self.max_downloads
+    }
+
+    /// Checks if the file has been downloaded as many times as it is allowed
+    pub fn downloads_exhausted(&self) -> bool {
+        self.max_downloads
+            .is_some_and(|m| self.download_count >= m)
     }
 
     pub fn hash(&self) -> &Hash {
         &self.hash
     }
 
+    pub fn algo(&self) -> HashAlgorithm {
+        self.algo
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn upload_datetime(&self) -> DateTime<Utc> {
+        self.upload_datetime
+    }
+
     pub fn mmid(&self) -> &Mmid {
         &self.mmid
     }
@@ -217,58 +649,275 @@ impl MochiFile {
     pub fn mime_type(&self) -> &String {
         &self.mime_type
     }
+
+    pub fn delete_token(&self) -> &String {
+        &self.delete_token
+    }
+
+    pub fn password_hash(&self) -> Option<&String> {
+        self.password_hash.as_ref()
+    }
 }
 
-/// Clean the database. Removes files which are past their expiry
-/// [`chrono::DateTime`]. Also removes files which no longer exist on the disk.
-pub fn clean_database(db: &Arc<RwLock<Mochibase>>, file_path: &Path) {
-    let mut database = db.write().unwrap();
+/// Fill in the size of entries which were added before sizes were stored in
+/// the database, by looking them up in storage.
+pub async fn fill_missing_sizes(db: &Arc<RwLock<Mochibase>>, storage: &dyn StorageBackend) {
+    let missing: HashSet<Hash> = db
+        .read()
+        .unwrap()
+        .entries()
+        .filter(|e| e.size == 0)
+        .map(|e| e.hash)
+        .collect();
 
+    for hash in missing {
+        match storage.size(&hash).await {
+            Ok(size) => db.write().unwrap().set_size(&hash, size),
+            Err(e) => warn!("Failed to get size of {}: {}", hash, e),
+        }
+    }
+}
+
+/// Clean the database. Removes files which are past their expiry
+/// [`chrono::DateTime`], and removes no longer referenced files from storage.
+pub async fn clean_database(
+    db: &Arc<RwLock<Mochibase>>,
+    storage: &dyn StorageBackend,
+    metrics: &Metrics,
+    thumb_dir: &Path,
+) {
     // Add expired entries to the removal list
-    let files_to_remove: Vec<_> = database
+    let files_to_remove: Vec<_> = db
+        .read()
+        .unwrap()
         .entries()
-        .filter_map(|e| {
-            if e.is_expired() {
-                Some((e.mmid().clone(), *e.hash()))
-            } else {
-                None
-            }
-        })
+        .filter(|e| e.is_expired())
+        .map(|e| e.mmid().clone())
         .collect();
 
-    let mut removed_files = 0;
-    let mut removed_entries = 0;
-    for e in &files_to_remove {
-        if database.remove_mmid(&e.0) {
-            removed_entries += 1;
-        }
-        if database.is_hash_empty(&e.1).is_some_and(|b| b) {
-            database.remove_hash(&e.1);
-            if let Err(e) = fs::remove_file(file_path.join(e.1.to_string())) {
-                warn!("Failed to remove expired hash: {}", e);
-            } else {
-                removed_files += 1;
+    let (removed_entries, removed_files, removed_bytes) =
+        remove_entries(db, storage, thumb_dir, &files_to_remove).await;
+    metrics.record_cleanup(removed_bytes);
+
+    info!("Cleaned database.\n\t| Removed {removed_entries} expired entries.\n\t| Removed {removed_files} no longer referenced files.");
+}
+
+/// Remove entries whose file is missing from storage, such as when it was
+/// deleted by hand, since they can't be downloaded anymore.
+///
+/// Returns the MMIDs of the removed entries.
+pub async fn reconcile_database(
+    db: &Arc<RwLock<Mochibase>>,
+    storage: &dyn StorageBackend,
+    metrics: &Metrics,
+    thumb_dir: &Path,
+) -> Vec<Mmid> {
+    let hashes: Vec<Hash> = db.read().unwrap().hashes().copied().collect();
+
+    let mut missing = Vec::new();
+    for hash in hashes {
+        match storage.exists(&hash).await {
+            Ok(true) => (),
+            Ok(false) => missing.push(hash),
+            Err(e) => warn!("Failed to check whether {hash} is stored: {e}"),
+        }
+    }
+
+    let removed = {
+        let mut database = db.write().unwrap();
+
+        let mut removed = Vec::new();
+        for hash in &missing {
+            let mmids: Vec<Mmid> = database.get_hash(hash).into_iter().flatten().cloned().collect();
+            if let Some(entry) = mmids.first().and_then(|m| database.get(m)) {
+                metrics.remove_stored(entry.size());
             }
+
+            for mmid in mmids {
+                if database.remove_mmid(&mmid) {
+                    warn!("Removed {mmid}, as its file {hash} is missing from storage");
+                    removed.push(mmid);
+                }
+            }
+            database.remove_hash(hash);
         }
+
+        if !removed.is_empty() {
+            if let Err(e) = database.save() {
+                error!("Failed to save database: {e}")
+            }
+        }
+
+        removed
+    };
+
+    for hash in &missing {
+        remove_thumbnail(thumb_dir, hash).await;
     }
 
-    info!("Cleaned database.\n\t| Removed {removed_entries} expired entries.\n\t| Removed {removed_files} no longer referenced files.");
+    removed
+}
+
+/// Remove entries from the database, and remove any files which are no longer
+/// referenced by another entry from storage.
+///
+/// Returns the number of entries removed, the number of files removed from
+/// storage, and the number of bytes those files took up.
+pub(crate) async fn remove_entries(
+    db: &Arc<RwLock<Mochibase>>,
+    storage: &dyn StorageBackend,
+    thumb_dir: &Path,
+    mmids: &[Mmid],
+) -> (usize, usize, u64) {
+    // The lock can't be held while waiting on storage
+    let (removed_entries, hashes_to_remove) = {
+        let mut database = db.write().unwrap();
+
+        let mut removed_entries = 0;
+        let mut hashes_to_remove = Vec::new();
+        for mmid in mmids {
+            let Some(hash) = database.get(mmid).map(|e| *e.hash()) else {
+                continue;
+            };
+
+            if database.remove_mmid(mmid) {
+                removed_entries += 1;
+            }
+            // An upload of the same file may be storing it again, in which
+            // case it is left for that upload to use
+            if database.is_hash_empty(&hash).is_some_and(|b| b) {
+                database.remove_hash(&hash);
+                if database.claim_hash(&hash) {
+                    hashes_to_remove.push(hash);
+                }
+            }
+        }
+
+        if let Err(e) = database.save() {
+            error!("Failed to save database: {e}")
+        }
+
+        (removed_entries, hashes_to_remove)
+    };
 
-    if let Err(e) = database.save() {
-        error!("Failed to save database: {e}")
+    let mut removed_files = 0;
+    let mut removed_bytes = 0;
+    for hash in &hashes_to_remove {
+        let size = storage.size(hash).await.unwrap_or_default();
+        if let Err(e) = storage.delete(hash).await {
+            warn!("Failed to remove unreferenced hash: {}", e);
+        } else {
+            removed_files += 1;
+            removed_bytes += size;
+        }
+        remove_thumbnail(thumb_dir, hash).await;
+        db.write().unwrap().release_hash(hash);
     }
-    drop(database); // Just to be sure
+
+    (removed_entries, removed_files, removed_bytes)
 }
 
-/// A unique identifier for an entry in the database, 8 characters long,
-/// consists of ASCII alphanumeric characters (`a-z`, `A-Z`, and `0-9`).
+/// A claim on the stored data for a hash, held by an upload while it decides
+/// whether to store its file and adds its entry. Nothing else can add or
+/// remove the data for the hash until it's dropped.
+pub struct HashClaim<'a> {
+    db: &'a RwLock<Mochibase>,
+    hash: Hash,
+}
+
+impl<'a> HashClaim<'a> {
+    /// Wait for any other upload or removal of the same hash to finish, then
+    /// claim it
+    pub async fn acquire(db: &'a RwLock<Mochibase>, hash: Hash) -> Self {
+        while !db.write().unwrap().claim_hash(&hash) {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        Self { db, hash }
+    }
+}
+
+impl Drop for HashClaim<'_> {
+    fn drop(&mut self) {
+        self.db.write().unwrap().release_hash(&self.hash);
+    }
+}
+
+/// Make room for `size` more bytes in storage without going over `quota`, by
+/// removing the entries which will expire soonest. Nothing is removed if
+/// there is no way to make enough room.
+///
+/// Returns `false` if there is not enough room for the file.
+pub async fn evict_for(
+    db: &Arc<RwLock<Mochibase>>,
+    storage: &dyn StorageBackend,
+    metrics: &Metrics,
+    thumb_dir: &Path,
+    quota: u64,
+    size: u64,
+) -> bool {
+    let to_evict = {
+        let database = db.read().unwrap();
+        let mut over = (database.stored_bytes() + size).saturating_sub(quota);
+        if over == 0 {
+            return true;
+        }
+
+        let mut entries: Vec<&MochiFile> = database.entries().collect();
+        entries.sort_by_key(|e| e.expiry());
+
+        // A file is only removed from storage once every entry for its hash is
+        // removed, so count how many are left for each hash
+        let mut remaining: HashMap<Hash, usize> = database
+            .hashes
+            .iter()
+            .map(|(hash, mmids)| (*hash, mmids.len()))
+            .collect();
+
+        let mut to_evict = Vec::new();
+        for entry in entries {
+            to_evict.push(entry.mmid().clone());
+
+            let count = remaining.entry(entry.hash).or_default();
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                over = over.saturating_sub(entry.size);
+                if over == 0 {
+                    break;
+                }
+            }
+        }
+
+        if over != 0 {
+            return false;
+        }
+
+        to_evict
+    };
+
+    let (removed_entries, removed_files, removed_bytes) =
+        remove_entries(db, storage, thumb_dir, &to_evict).await;
+    metrics.remove_stored(removed_bytes);
+
+    info!("Evicted files to stay under the storage quota.\n\t| Removed {removed_entries} entries.\n\t| Removed {removed_files} files.");
+
+    true
+}
+
+/// The lengths an [`Mmid`] is allowed to be. New MMIDs are generated with the
+/// configured length, but any length in this range is accepted so that
+/// existing links keep working if that setting changes.
+pub const MMID_LENGTHS: RangeInclusive<usize> = 4..=32;
+
+/// A unique identifier for an entry in the database, 8 characters long by
+/// default, consists of ASCII alphanumeric characters (`a-z`, `A-Z`, and `0-9`).
 #[derive(Debug, PartialEq, Eq, Clone, Hash, Deserialize, Serialize)]
 pub struct Mmid(String);
 
 impl Mmid {
-    /// Create a new random MMID
-    pub fn new_random() -> Self {
-        let string = Alphanumeric.sample_string(&mut rand::thread_rng(), 8);
+    /// Create a new random MMID with the given number of characters
+    pub fn new_random(length: usize) -> Self {
+        let string = Alphanumeric.sample_string(&mut rand::thread_rng(), length);
 
         Self(string)
     }
@@ -278,7 +927,7 @@ impl TryFrom<&str> for Mmid {
     type Error = ();
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        if value.len() != 8 {
+        if !MMID_LENGTHS.contains(&value.len()) {
             return Err(());
         }
 
@@ -307,7 +956,7 @@ impl TryFrom<&OsStr> for Mmid {
             None => return Err(()),
         };
 
-        if string.len() != 8 {
+        if !MMID_LENGTHS.contains(&string.len()) {
             return Err(());
         }
 
@@ -350,8 +999,11 @@ impl Chunkbase {
         Ok(())
     }
 
-    pub fn delete_timed_out(&mut self) -> Result<(), io::Error> {
+    /// Delete all chunk files whose timeout has passed, returning how many
+    /// uploads were removed
+    pub fn delete_timed_out(&mut self) -> usize {
         let now = Utc::now();
+        let before = self.chunks.len();
         self.chunks.retain(|_u, (t, c)| {
             if *t <= now {
                 let _ = fs::remove_file(&c.path);
@@ -361,7 +1013,7 @@ impl Chunkbase {
             }
         });
 
-        Ok(())
+        before - self.chunks.len()
     }
 
     pub fn new_file<P: AsRef<Path>>(&mut self, mut info: ChunkedInfo, temp_dir: &P, timeout: TimeDelta) -> Result<Uuid, io::Error> {
@@ -377,7 +1029,7 @@ impl Chunkbase {
     }
 
     pub fn get_file(&self, uuid: &Uuid) -> Option<&(DateTime<Utc>, ChunkedInfo)> {
-        self.chunks.get(&uuid)
+        self.chunks.get(uuid)
     }
 
     pub fn remove_file(&mut self, uuid: &Uuid) -> Result<bool, io::Error> {
@@ -391,15 +1043,10 @@ impl Chunkbase {
         Ok(true)
     }
 
-    pub fn move_and_remove_file<P: AsRef<Path>>(&mut self, uuid: &Uuid, new_location: &P) -> Result<bool, io::Error> {
-        let item = match self.chunks.remove(uuid) {
-            Some(i) => i,
-            None => return Ok(false),
-        };
-
-        fs::rename(item.1.path, new_location)?;
-
-        Ok(true)
+    /// Remove an upload from the database without deleting its file, so the
+    /// file can be moved into storage
+    pub fn take_file(&mut self, uuid: &Uuid) -> Option<ChunkedInfo> {
+        self.chunks.remove(uuid).map(|i| i.1)
     }
 
     pub fn extend_timeout(&mut self, uuid: &Uuid, timeout: TimeDelta) -> bool {
@@ -413,6 +1060,38 @@ impl Chunkbase {
         true
     }
 
+    /// Get the number of uploads in progress
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Whether as many uploads are in progress as are allowed at once
+    pub fn is_full(&self, limit: usize) -> bool {
+        limit != 0 && self.chunks.len() >= limit
+    }
+
+    /// Get the number of uploads in progress from a particular client IP
+    pub fn count_for_ip(&self, ip: &IpAddr) -> usize {
+        self.chunks
+            .values()
+            .filter(|(_, c)| c.client_ip.as_ref() == Some(ip))
+            .count()
+    }
+
+    /// Get the soonest time an upload in progress from a particular client IP
+    /// will time out
+    pub fn next_timeout_for_ip(&self, ip: &IpAddr) -> Option<DateTime<Utc>> {
+        self.chunks
+            .values()
+            .filter(|(_, c)| c.client_ip.as_ref() == Some(ip))
+            .map(|(t, _)| *t)
+            .min()
+    }
+
     pub fn add_recieved_chunk(&mut self, uuid: &Uuid, chunk: u64) -> bool {
         let item = match self.chunks.get_mut(uuid) {
             Some(i) => i,
@@ -421,6 +1100,47 @@ impl Chunkbase {
 
         item.1.recieved_chunks.insert(chunk)
     }
+
+    /// Mark where the file being uploaded ends, when its size is only a
+    /// maximum
+    pub fn set_end(&mut self, uuid: &Uuid, end: u64) -> bool {
+        let item = match self.chunks.get_mut(uuid) {
+            Some(i) => i,
+            None => return false,
+        };
+
+        item.1.end = Some(end);
+
+        true
+    }
+
+    /// Take the running hash of an upload if `chunk` is the next one it
+    /// needs. Any other chunk means they are arriving out of order, so the
+    /// running hash is dropped and the whole file gets hashed when finished.
+    pub fn take_hasher(&mut self, uuid: &Uuid, chunk: u64) -> Option<FileHasher> {
+        let item = self.chunks.get_mut(uuid)?;
+
+        let hasher = item.1.hasher.take();
+        if item.1.hashed_chunks != chunk {
+            return None;
+        }
+
+        hasher
+    }
+
+    /// Give back a running hash taken with [`Chunkbase::take_hasher`], once
+    /// the chunk it was taken for has been added to it
+    pub fn return_hasher(&mut self, uuid: &Uuid, hasher: FileHasher) -> bool {
+        let item = match self.chunks.get_mut(uuid) {
+            Some(i) => i,
+            None => return false,
+        };
+
+        item.1.hasher = Some(hasher);
+        item.1.hashed_chunks += 1;
+
+        true
+    }
 }
 
 /// Information about how to manage partially uploaded chunks of files
@@ -432,6 +1152,28 @@ pub struct ChunkedInfo {
     #[serde_as(as = "serde_with::DurationSeconds<i64>")]
     pub expire_duration: TimeDelta,
 
+    /// The number of times the file may be downloaded before it expires
+    #[serde(default)]
+    pub max_downloads: Option<u32>,
+
+    /// The MIME type the client says the file is. The real type is only
+    /// known once the whole file is uploaded, but this lets uploads of types
+    /// which aren't allowed be rejected early.
+    #[serde(default, skip_serializing)]
+    pub mime_type: Option<String>,
+
+    /// A password required to access the file, only ever sent by the client
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+
+    /// The hashed form of `password`, which is what gets stored
+    #[serde(skip)]
+    pub password_hash: Option<String>,
+
+    /// Who is able to see the file once it is uploaded
+    #[serde(default)]
+    pub visibility: Visibility,
+
     /// Tracks which chunks have already been recieved, so you can't overwrite
     /// some wrong part of a file
     #[serde(skip)]
@@ -440,4 +1182,171 @@ pub struct ChunkedInfo {
     pub path: PathBuf,
     #[serde(skip)]
     pub offset: u64,
+
+    /// The IP address of the client performing the upload, if known
+    #[serde(skip)]
+    pub client_ip: Option<IpAddr>,
+
+    /// A hash of the chunks recieved so far, which is kept up to date while
+    /// they arrive in order to avoid reading the whole file again at the end
+    #[serde(skip)]
+    pub hasher: Option<FileHasher>,
+
+    /// The number of chunks, from the start of the file, in `hasher`
+    #[serde(skip)]
+    pub hashed_chunks: u64,
+
+    /// Where the file ends, once a chunk shorter than the chunk size has been
+    /// recieved. Only tracked when `size` is a maximum rather than exact.
+    #[serde(skip)]
+    pub end: Option<u64>,
+
+    /// A hash of the credentials the upload was started with, so they don't
+    /// need to be checked in full again for every chunk
+    #[serde(skip)]
+    pub credentials: Option<Hash>,
+}
+
+impl ChunkedInfo {
+    /// Check whether every chunk making up the file has been recieved, given
+    /// the size of each chunk
+    pub fn is_complete(&self, chunk_size: u64) -> bool {
+        if chunk_size == 0 {
+            return false;
+        }
+
+        let expected_chunks = self.size.div_ceil(chunk_size);
+        (0..expected_chunks).all(|c| self.recieved_chunks.contains(&c))
+    }
+
+    /// The size the file ended up being, when `size` is only a maximum. This
+    /// is where the short last chunk ended, or if there wasn't one, the end
+    /// of the furthest chunk recieved.
+    pub fn final_size(&self, chunk_size: u64) -> u64 {
+        match self.end {
+            Some(end) => end,
+            None => self
+                .recieved_chunks
+                .iter()
+                .max()
+                .map_or(0, |c| (c + 1).saturating_mul(chunk_size).min(self.size)),
+        }
+    }
+
+    /// The number of bytes in the chunks which have been recieved
+    pub fn received_bytes(&self, chunk_size: u64) -> u64 {
+        self.recieved_chunks
+            .iter()
+            .map(|c| chunk_size.min(self.size.saturating_sub(c * chunk_size)))
+            .sum()
+    }
+
+    /// The hash of the whole file, if every chunk was added to the running
+    /// hash as it arrived
+    pub fn running_hash(&self, chunk_size: u64) -> Option<Hash> {
+        if chunk_size == 0 || self.hashed_chunks != self.size.div_ceil(chunk_size) {
+            return None;
+        }
+
+        self.hasher.as_ref().map(|h| h.finalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A new, empty temporary directory
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("confetti-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A path in a new, empty temporary directory
+    fn temp_path(name: &str) -> PathBuf {
+        temp_dir().join(name)
+    }
+
+    #[test]
+    fn imports_bincode_database() {
+        let path = temp_path("database.mochi");
+        let mmid = Mmid::new_random(8);
+        let hash = blake3::hash(b"confetti");
+        let legacy = LegacyMochibase {
+            path: path.clone(),
+            hashes: HashMap::from([(hash, HashSet::from([mmid.clone()]))]),
+            entries: HashMap::from([(mmid.clone(), LegacyMochiFile {
+                mmid: mmid.clone(),
+                name: "party.txt".into(),
+                mime_type: "text/plain".into(),
+                hash,
+                upload_datetime: Utc::now(),
+                expiry_datetime: Utc::now() + TimeDelta::hours(1),
+            })]),
+        };
+        fs::write(&path, bincode::serialize(&legacy).unwrap()).unwrap();
+
+        let database = Mochibase::open_or_new(&path).unwrap();
+        assert_eq!(database.get(&mmid).unwrap().name(), "party.txt");
+        assert!(path.with_extension("old").exists());
+
+        // The database was rewritten in the current format
+        assert!(Mochibase::open(&path).is_ok());
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn partial_backup_keeps_database() {
+        let path = temp_path("database.mochi");
+        let mmid = Mmid::new_random(8);
+        let hash = blake3::hash(b"confetti");
+        let mut database = Mochibase::new(&path).unwrap();
+        let file = MochiFile::new(
+            mmid.clone(),
+            "party.txt".into(),
+            "text/plain".into(),
+            hash,
+            Utc::now(),
+            Utc::now() + TimeDelta::hours(1),
+        );
+        database.insert(&mmid, file);
+        database.save().unwrap();
+
+        // A save which stopped partway through writing the temporary file
+        let saved = fs::read(&path).unwrap();
+        fs::write(path.with_extension("bkp"), &saved[..saved.len() / 2]).unwrap();
+
+        let reopened = Mochibase::open_or_new(&path).unwrap();
+        assert_eq!(reopened.get(&mmid).unwrap().name(), "party.txt");
+        assert_eq!(fs::read(&path).unwrap(), saved);
+
+        // The next save replaces the partial file
+        reopened.save().unwrap();
+        assert!(!path.with_extension("bkp").exists());
+        assert!(Mochibase::open(&path).unwrap().get(&mmid).is_some());
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn removes_timed_out_chunks() {
+        let dir = temp_dir();
+        let mut chunk_db = Chunkbase::default();
+        let expired = chunk_db
+            .new_file(ChunkedInfo::default(), &dir, TimeDelta::seconds(-1))
+            .unwrap();
+        let live = chunk_db
+            .new_file(ChunkedInfo::default(), &dir, TimeDelta::hours(1))
+            .unwrap();
+        let expired_path = chunk_db.get_file(&expired).unwrap().1.path.clone();
+
+        assert_eq!(chunk_db.delete_timed_out(), 1);
+        assert!(chunk_db.get_file(&expired).is_none());
+        assert!(!expired_path.exists());
+        assert!(chunk_db.get_file(&live).is_some());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
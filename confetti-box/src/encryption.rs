@@ -0,0 +1,249 @@
+use std::{
+    cmp,
+    fs::File,
+    io::{self, Read, Write},
+    mem,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use blake3::Hash;
+use chacha20poly1305::{
+    aead::{AeadInPlace, KeyInit},
+    ChaCha20Poly1305, Key, Nonce, Tag,
+};
+use rocket::tokio::{
+    fs,
+    io::{AsyncRead, ReadBuf},
+    task,
+};
+
+use crate::storage::{FileReader, StorageBackend};
+
+/// The size of the pieces files are split into to be encrypted. Each one is
+/// authenticated on its own, so part of a file can be read without the rest.
+const SEGMENT_SIZE: u64 = 64 * 1024;
+
+/// The size of the authentication tag stored after each segment
+const TAG_SIZE: u64 = 16;
+
+/// Wraps another storage backend, encrypting files with ChaCha20-Poly1305
+/// before they are stored and decrypting them as they are read.
+///
+/// Files are still keyed by the hash of their unencrypted contents, so
+/// identical uploads are only stored once. Each file is encrypted with its own
+/// key derived from the main key and that hash, and the nonce of each segment
+/// is its position in the file, so segments can't be reordered or cut off.
+pub struct EncryptedStorage {
+    inner: Arc<dyn StorageBackend>,
+    key: [u8; 32],
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn StorageBackend>, key: [u8; 32]) -> Self {
+        Self { inner, key }
+    }
+
+    fn cipher(&self, hash: &Hash) -> ChaCha20Poly1305 {
+        let file_key = blake3::keyed_hash(&self.key, hash.as_bytes());
+        ChaCha20Poly1305::new(Key::from_slice(file_key.as_bytes()))
+    }
+}
+
+/// The nonce for a segment, which is its number and whether it's the last one
+fn nonce(segment: u64, last: bool) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[..8].copy_from_slice(&segment.to_be_bytes());
+    nonce[8] = last as u8;
+    nonce.into()
+}
+
+/// The number of segments a file of `size` bytes is split into. Even an empty
+/// file has one, so that it can be authenticated.
+fn segment_count(size: u64) -> u64 {
+    size.div_ceil(SEGMENT_SIZE).max(1)
+}
+
+/// The size of a file before it was encrypted
+fn plaintext_size(encrypted_size: u64) -> u64 {
+    let segments = encrypted_size.div_ceil(SEGMENT_SIZE + TAG_SIZE);
+    encrypted_size.saturating_sub(segments * TAG_SIZE)
+}
+
+/// Encrypt the file at `source` into a new file at `dest`
+fn encrypt_file(cipher: &ChaCha20Poly1305, source: &Path, dest: &Path) -> io::Result<()> {
+    let mut input = File::open(source)?;
+    let mut output = File::create(dest)?;
+
+    let size = input.metadata()?.len();
+    let segments = segment_count(size);
+    let mut buffer = vec![0; SEGMENT_SIZE as usize];
+    for segment in 0..segments {
+        let len = cmp::min(SEGMENT_SIZE, size - segment * SEGMENT_SIZE) as usize;
+        input.read_exact(&mut buffer[..len])?;
+
+        let tag = cipher
+            .encrypt_in_place_detached(&nonce(segment, segment + 1 == segments), b"", &mut buffer[..len])
+            .map_err(|_| io::Error::other("Failed to encrypt file"))?;
+        output.write_all(&buffer[..len])?;
+        output.write_all(&tag)?;
+    }
+
+    output.flush()
+}
+
+#[rocket::async_trait]
+impl StorageBackend for EncryptedStorage {
+    async fn put(&self, hash: &Hash, source: &Path) -> io::Result<()> {
+        let dest = source.with_extension("enc");
+
+        let cipher = self.cipher(hash);
+        let (input, output) = (source.to_path_buf(), dest.clone());
+        let result = match task::spawn_blocking(move || encrypt_file(&cipher, &input, &output)).await {
+            Ok(Ok(())) => self.inner.put(hash, &dest).await,
+            Ok(Err(e)) => Err(e),
+            Err(e) => Err(io::Error::other(e)),
+        };
+
+        if result.is_err() {
+            let _ = fs::remove_file(&dest).await;
+        }
+        result?;
+
+        fs::remove_file(source).await
+    }
+
+    async fn get(&self, hash: &Hash, range: Option<(u64, u64)>) -> io::Result<FileReader> {
+        let encrypted_size = self.inner.size(hash).await?;
+        let size = plaintext_size(encrypted_size);
+
+        let (start, end) = match range {
+            Some(r) => r,
+            None if size == 0 => return Ok(Box::pin(rocket::tokio::io::empty())),
+            None => (0, size - 1),
+        };
+        if start > end || end >= size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Range out of bounds"));
+        }
+
+        // Only the segments containing the range need to be read
+        let first = start / SEGMENT_SIZE;
+        let last = end / SEGMENT_SIZE;
+        let encrypted_range = (
+            first * (SEGMENT_SIZE + TAG_SIZE),
+            cmp::min((last + 1) * (SEGMENT_SIZE + TAG_SIZE), encrypted_size) - 1,
+        );
+        let inner = self.inner.get(hash, Some(encrypted_range)).await?;
+
+        Ok(Box::pin(DecryptingReader {
+            inner,
+            cipher: self.cipher(hash),
+            encrypted_size,
+            segment: first,
+            last_segment: segment_count(size) - 1,
+            buffer: vec![0; (SEGMENT_SIZE + TAG_SIZE) as usize],
+            filled: 0,
+            position: 0,
+            available: 0,
+            skip: (start - first * SEGMENT_SIZE) as usize,
+            remaining: end - start + 1,
+        }))
+    }
+
+    async fn size(&self, hash: &Hash) -> io::Result<u64> {
+        Ok(plaintext_size(self.inner.size(hash).await?))
+    }
+
+    async fn delete(&self, hash: &Hash) -> io::Result<()> {
+        self.inner.delete(hash).await
+    }
+
+    async fn exists(&self, hash: &Hash) -> io::Result<bool> {
+        self.inner.exists(hash).await
+    }
+}
+
+/// Decrypts a stream of whole encrypted segments, one segment at a time
+struct DecryptingReader {
+    inner: FileReader,
+    cipher: ChaCha20Poly1305,
+
+    /// The size of the whole encrypted file
+    encrypted_size: u64,
+
+    /// The number of the next segment to be read
+    segment: u64,
+    last_segment: u64,
+
+    /// Holds the segment being read, and once it is decrypted, its contents
+    buffer: Vec<u8>,
+
+    /// How much of the segment being read is in the buffer
+    filled: usize,
+
+    /// The part of the decrypted segment in the buffer which hasn't been
+    /// handed out yet
+    position: usize,
+    available: usize,
+
+    /// Bytes at the start of the first segment which are before the range
+    skip: usize,
+
+    /// Bytes left in the range
+    remaining: u64,
+}
+
+impl AsyncRead for DecryptingReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.position < this.available {
+                let len = cmp::min(this.available - this.position, buf.remaining())
+                    .min(this.remaining.try_into().unwrap_or(usize::MAX));
+                buf.put_slice(&this.buffer[this.position..this.position + len]);
+                this.position += len;
+                this.remaining -= len as u64;
+
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.remaining == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            let offset = this.segment * (SEGMENT_SIZE + TAG_SIZE);
+            let segment_len = cmp::min(SEGMENT_SIZE + TAG_SIZE, this.encrypted_size - offset) as usize;
+            while this.filled < segment_len {
+                let mut read_buf = ReadBuf::new(&mut this.buffer[this.filled..segment_len]);
+                ready!(this.inner.as_mut().poll_read(cx, &mut read_buf))?;
+
+                let read = read_buf.filled().len();
+                if read == 0 {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Encrypted file ended early",
+                    )));
+                }
+                this.filled += read;
+            }
+
+            let (data, tag) = this.buffer[..segment_len].split_at_mut(segment_len - TAG_SIZE as usize);
+            let nonce = nonce(this.segment, this.segment == this.last_segment);
+            this.cipher
+                .decrypt_in_place_detached(&nonce, b"", data, Tag::from_slice(tag))
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Failed to decrypt file"))?;
+
+            this.available = data.len();
+            this.position = mem::take(&mut this.skip);
+            this.filled = 0;
+            this.segment += 1;
+        }
+    }
+}
@@ -1,24 +1,45 @@
 use std::{
-    str::FromStr,
+    fs, io,
+    net::IpAddr,
+    path::Path,
     sync::{Arc, RwLock},
 };
 
+use async_compression::{
+    tokio::bufread::{BrotliEncoder, GzipEncoder},
+    Level,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{error, warn};
 use rocket::{
-    get, http::ContentType, response::{self, Redirect, Responder, Response}, serde::{self, json::Json}, tokio::{self, fs::File}, uri, Request, State
+    delete, get, head, patch, post, http::{ContentType, Method, Status}, request::{FromRequest, Outcome}, response::{self, Redirect, Responder, Response}, serde::{self, json::Json}, tokio::io::BufReader, uri, Request, State
 };
-use serde::Serialize;
+use maud::Markup;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 use crate::{
-    database::{Mmid, MochiFile, Mochibase},
+    auth::{verify_password, AuthUser, DownloadAuth},
+    database::{EventKind, Mmid, MochiFile, Mochibase},
+    error::ApiError,
+    metrics::Metrics,
+    pages,
     settings::Settings,
+    storage::{FileReader, StorageBackend},
+    check_duration, unused_mmid, UploadGate,
+    strings::{parse_time_string, sanitize_filename},
+    thumbnail::{remove_thumbnail, thumbnail_dir},
+    utils::HashAlgorithm,
 };
 
 /// An endpoint to obtain information about the server's capabilities
 #[get("/info")]
-pub fn server_info(settings: &State<Settings>) -> Json<ServerInfo> {
+pub fn server_info(settings: &Settings, methods: UploadMethods) -> Json<ServerInfo> {
     Json(ServerInfo {
+        url: settings.base_url(),
         max_filesize: settings.max_filesize,
         max_duration: settings.duration.maximum.num_seconds() as u32,
+        min_duration: settings.duration.minimum.num_seconds() as u32,
         default_duration: settings.duration.default.num_seconds() as u32,
         allowed_durations: settings
             .duration
@@ -27,75 +48,836 @@ pub fn server_info(settings: &State<Settings>) -> Json<ServerInfo> {
             .into_iter()
             .map(|t| t.num_seconds() as u32)
             .collect(),
+        chunk_size: settings.chunk_size,
+        hash_algorithm: settings.hash_algorithm,
+        supports_websocket: methods.websocket,
+        supports_chunked: methods.chunked,
+        supports_multipart: methods.multipart,
     })
 }
 
+/// Which ways of uploading files the server accepts, going by which routes
+/// are mounted
+pub struct UploadMethods {
+    websocket: bool,
+    chunked: bool,
+    multipart: bool,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UploadMethods {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let mounted = |name: &str| req.rocket().routes().any(|r| r.name.as_deref() == Some(name));
+
+        Outcome::Success(Self {
+            websocket: mounted("websocket_upload"),
+            chunked: mounted("chunked_upload_start"),
+            multipart: mounted("form_upload"),
+        })
+    }
+}
+
 /// Get information about a file
-#[get("/info/<mmid>")]
-pub async fn file_info(db: &State<Arc<RwLock<Mochibase>>>, mmid: &str) -> Option<Json<MochiFile>> {
-    let mmid: Mmid = mmid.try_into().ok()?;
-    let entry = db.read().unwrap().get(&mmid).cloned()?;
+#[get("/info/<mmid>?<password>")]
+pub async fn file_info(
+    db: &State<Arc<RwLock<Mochibase>>>,
+    settings: &Settings,
+    user: DownloadAuth,
+    mmid: &str,
+    password: Option<&str>,
+) -> Result<NoIndex<Json<MochiFile>>, Status> {
+    let entry = live_entry(db, mmid)?;
+    check_visible(&entry, &user, settings)?;
+
+    if !unlocked(&entry, password).await {
+        return Err(Status::Forbidden);
+    }
+
+    Ok(NoIndex::new(Json(entry.redacted()), settings))
+}
+
+/// A response which search engines are asked not to index, if the server is
+/// set up to ask them
+pub struct NoIndex<T> {
+    inner: T,
+    noindex: bool,
+}
+
+impl<T> NoIndex<T> {
+    pub fn new(inner: T, settings: &Settings) -> Self {
+        Self {
+            inner,
+            noindex: settings.robots.noindex,
+        }
+    }
+}
+
+impl<'r, 'o: 'r, T: Responder<'r, 'o>> Responder<'r, 'o> for NoIndex<T> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'o> {
+        let mut resp = self.inner.respond_to(req)?;
+        if self.noindex {
+            resp.set_raw_header("X-Robots-Tag", "noindex");
+        }
+
+        Ok(resp)
+    }
+}
+
+/// Tell crawlers which paths not to visit, going by the robots settings
+#[get("/robots.txt")]
+pub fn robots(settings: &Settings) -> String {
+    let mut robots = String::from("User-agent: *\n");
+    if settings.robots.noindex && !settings.robots.disallow.is_empty() {
+        for path in &settings.robots.disallow {
+            robots += &format!("Disallow: {}\n", settings.server.path(path));
+        }
+    } else {
+        // An empty rule allows everything
+        robots += "Disallow:\n";
+    }
+
+    robots
+}
+
+/// Delete a file before it expires, using the deletion token which was
+/// returned when it was uploaded
+#[delete("/f/<mmid>?<token>")]
+pub async fn delete_mmid(
+    db: &State<Arc<RwLock<Mochibase>>>,
+    storage: &State<Arc<dyn StorageBackend>>,
+    metrics: &State<Arc<Metrics>>,
+    settings: &Settings,
+    ip: Option<IpAddr>,
+    mmid: &str,
+    token: &str,
+) -> Status {
+    let mmid: Mmid = match mmid.try_into() {
+        Ok(m) => m,
+        Err(_) => return Status::NotFound,
+    };
+
+    // The lock can't be held while waiting on storage
+    let (hash, hash_empty) = {
+        let mut database = db.write().unwrap();
+        let entry = match database.get(&mmid) {
+            Some(e) if !e.token_matches(token) => {
+                return Status::Forbidden
+            }
+            Some(e) => e.clone(),
+            None => return Status::NotFound,
+        };
+        let hash = *entry.hash();
+
+        database.remove_mmid(&mmid);
+        let hash_empty = database.is_hash_empty(&hash).is_some_and(|b| b);
+        if hash_empty {
+            database.remove_hash(&hash);
+        }
+
+        // Otherwise a crash before the next save would bring the entry back
+        // without its file
+        if let Err(e) = database.save() {
+            error!("Failed to save the database after deleting {mmid}: {e}");
+            database.insert(&mmid, entry);
+            return Status::InternalServerError;
+        }
+        database.record_event(EventKind::Delete, &mmid, ip, settings.max_events);
+
+        // An upload of the same file may be storing it again, in which case
+        // it is left for that upload to use
+        (hash, hash_empty && database.claim_hash(&hash))
+    };
+
+    if hash_empty {
+        let size = storage.size(&hash).await.unwrap_or_default();
+        match storage.delete(&hash).await {
+            Ok(_) => metrics.remove_stored(size),
+            Err(e) => warn!("Failed to remove deleted hash: {}", e),
+        }
+        remove_thumbnail(&thumbnail_dir(settings), &hash).await;
+        db.write().unwrap().release_hash(&hash);
+    }
+
+    Status::Ok
+}
 
-    Some(Json(entry))
+/// A request to rename a file
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct RenameRequest {
+    /// The deletion token which was returned when the file was uploaded
+    token: String,
+
+    /// The new name of the file
+    name: String,
+}
+
+/// Rename a file, using the deletion token which was returned when it was
+/// uploaded
+#[patch("/f/<mmid>", data = "<body>")]
+pub fn rename_mmid(
+    db: &State<Arc<RwLock<Mochibase>>>,
+    settings: &Settings,
+    mmid: &str,
+    body: Json<RenameRequest>,
+) -> Result<Json<MochiFile>, ApiError> {
+    // Names which would be changed when uploading aren't allowed either
+    if sanitize_filename(&body.name, settings.max_filename_length).as_ref() != Some(&body.name) {
+        return Err(ApiError::Invalid("Invalid filename".into()));
+    }
+
+    let entry = live_entry(db, mmid).map_err(|status| {
+        if status == Status::Gone {
+            ApiError::Gone("File has expired".into())
+        } else {
+            ApiError::NotFound("File not found".into())
+        }
+    })?;
+    if !entry.token_matches(&body.token) {
+        return Err(ApiError::Forbidden("Incorrect token".into()));
+    }
+
+    let mut database = db.write().unwrap();
+    let entry = database
+        .rename(entry.mmid(), body.into_inner().name)
+        .ok_or(ApiError::NotFound("File not found".into()))?;
+    database.save()?;
+
+    Ok(Json(entry.with_password_hash(None)))
+}
+
+/// A request to change how long a file is kept
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ExpiryRequest {
+    /// The deletion token which was returned when the file was uploaded
+    token: String,
+
+    /// How long to keep the file from now, like `6h` or `1d12h`
+    duration: String,
+}
+
+/// Change when a file expires, using the deletion token which was returned
+/// when it was uploaded. The new expiry is counted from now, and may be sooner
+/// than the current one.
+#[patch("/f/<mmid>/expiry", data = "<body>")]
+pub fn set_expiry(
+    db: &State<Arc<RwLock<Mochibase>>>,
+    settings: &Settings,
+    mmid: &str,
+    body: Json<ExpiryRequest>,
+) -> Result<Json<MochiFile>, ApiError> {
+    let mmid: Mmid = mmid
+        .try_into()
+        .map_err(|_| ApiError::NotFound("Invalid MMID".into()))?;
+
+    let duration = parse_time_string(&body.duration)
+        .map_err(|e| ApiError::Invalid(e.to_string()))?;
+    if duration <= TimeDelta::zero() {
+        return Err(ApiError::Invalid("Duration must be positive".into()));
+    }
+    check_duration(settings, duration).map_err(ApiError::Invalid)?;
+
+    let mut database = db.write().unwrap();
+    match database.get(&mmid) {
+        Some(e) if !e.token_matches(&body.token) => {
+            return Err(ApiError::Forbidden("Incorrect token".into()))
+        }
+        Some(e) if e.is_expired() => return Err(ApiError::NotFound("File not found".into())),
+        Some(_) => (),
+        None => return Err(ApiError::NotFound("File not found".into())),
+    }
+
+    let entry = database
+        .set_expiry(&mmid, Utc::now() + duration)
+        .ok_or(ApiError::NotFound("File not found".into()))?;
+    database.save()?;
+
+    Ok(Json(entry.with_password_hash(None)))
+}
+
+/// A request to share a file again under a new MMID
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct CopyRequest {
+    /// The deletion token which was returned when the file was uploaded
+    token: String,
+
+    /// How long to keep the copy from now, like `6h` or `1d12h`
+    duration: String,
+}
+
+/// Share a file again under a new MMID with its own expiry, using the
+/// deletion token which was returned when it was uploaded. The copy shares
+/// the stored file like a deduplicated upload, so no data is copied. Responds
+/// with the new entry, including its own deletion token.
+#[allow(clippy::too_many_arguments)]
+#[post("/f/<mmid>/copy", data = "<body>")]
+pub fn copy_mmid(
+    db: &State<Arc<RwLock<Mochibase>>>,
+    metrics: &State<Arc<Metrics>>,
+    settings: &Settings,
+    gate: &State<Arc<UploadGate>>,
+    _user: AuthUser,
+    ip: Option<IpAddr>,
+    mmid: &str,
+    body: Json<CopyRequest>,
+) -> Result<Json<MochiFile>, ApiError> {
+    if let Some(reason) = gate.refusal() {
+        return Err(ApiError::Unavailable(reason.into()));
+    }
+
+    let mmid: Mmid = mmid
+        .try_into()
+        .map_err(|_| ApiError::NotFound("Invalid MMID".into()))?;
+
+    let duration = parse_time_string(&body.duration)
+        .map_err(|e| ApiError::Invalid(e.to_string()))?;
+    if duration <= TimeDelta::zero() {
+        return Err(ApiError::Invalid("Duration must be positive".into()));
+    }
+    check_duration(settings, duration).map_err(ApiError::Invalid)?;
+
+    let mut database = db.write().unwrap();
+    let source = match database.get(&mmid) {
+        Some(e) if !e.token_matches(&body.token) => {
+            return Err(ApiError::Forbidden("Incorrect token".into()))
+        }
+        Some(e) if e.is_expired() => return Err(ApiError::NotFound("File not found".into())),
+        Some(e) => e.clone(),
+        None => return Err(ApiError::NotFound("File not found".into())),
+    };
+
+    let now = Utc::now();
+    let new_mmid = unused_mmid(&database, settings)?;
+    let entry = MochiFile::new(
+        new_mmid.clone(),
+        source.name().clone(),
+        source.mime_type().clone(),
+        *source.hash(),
+        now,
+        now + duration,
+    )
+    .with_algo(source.algo())
+    .with_size(source.size())
+    .with_password_hash(source.password_hash().cloned())
+    .with_visibility(source.visibility());
+
+    database.insert(&new_mmid, entry.clone());
+    database.record_event(EventKind::Upload, &new_mmid, ip, settings.max_events);
+    database.save()?;
+    metrics.record_upload(0);
+
+    Ok(Json(entry.with_password_hash(None)))
+}
+
+/// Check whether the server is able to accept uploads. Responds with
+/// `503 Service Unavailable` if the database is unusable, the storage
+/// directory is not writable, or the disk is nearly full.
+#[get("/health")]
+pub fn health(
+    db: &State<Arc<RwLock<Mochibase>>>,
+    settings: &Settings,
+    gate: &State<Arc<UploadGate>>,
+) -> (Status, Json<HealthInfo>) {
+    let db_ok = db.read().is_ok();
+
+    let dir = settings.local_storage_dir();
+    let probe = dir.join(format!(".health-{}", Uuid::new_v4()));
+    let writable = fs::File::create(&probe).is_ok() && fs::remove_file(&probe).is_ok();
+    let disk_free_bytes = fs4::available_space(dir).unwrap_or_default();
+
+    let accepting_uploads = gate.is_open();
+    let read_only = gate.is_read_only();
+
+    // Downloads still work in read-only mode, so the server stays healthy
+    let ok = db_ok && writable && !gate.is_shutting_down() && disk_free_bytes >= settings.min_free_space;
+    let status = if ok { Status::Ok } else { Status::ServiceUnavailable };
+
+    (
+        status,
+        Json(HealthInfo {
+            status: if ok { "ok" } else { "unavailable" },
+            db_ok,
+            writable,
+            accepting_uploads,
+            read_only,
+            disk_free_bytes,
+        }),
+    )
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct HealthInfo {
+    status: &'static str,
+    db_ok: bool,
+    writable: bool,
+
+    /// Whether new uploads are accepted, which they aren't while the server
+    /// is shutting down or in read-only mode
+    accepting_uploads: bool,
+    read_only: bool,
+    disk_free_bytes: u64,
 }
 
 #[derive(Serialize, Debug)]
 #[serde(crate = "rocket::serde")]
 pub struct ServerInfo {
+    /// The URL clients use to reach the server
+    url: String,
     max_filesize: u64,
     max_duration: u32,
+    min_duration: u32,
     default_duration: u32,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     allowed_durations: Vec<u32>,
+    /// The size of the chunks chunked uploads are split into
+    chunk_size: u64,
+    /// The algorithm files are hashed with, which expected hashes sent with
+    /// uploads have to use
+    hash_algorithm: HashAlgorithm,
+    supports_websocket: bool,
+    supports_chunked: bool,
+    supports_multipart: bool,
+}
+
+/// Get which version of the server is running, and how it was built
+#[get("/version")]
+pub fn version() -> Json<VersionInfo> {
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: build_detail(option_env!("VERGEN_GIT_SHA")),
+        build_timestamp: build_detail(option_env!("VERGEN_BUILD_TIMESTAMP")),
+        rustc_version: build_detail(option_env!("VERGEN_RUSTC_SEMVER")),
+    })
+}
+
+/// A detail about the build, which is only known when built with the
+/// `git_tag` feature
+fn build_detail(value: Option<&'static str>) -> Option<&'static str> {
+    // This is used in place of details which couldn't be found, like the
+    // commit when building outside of a git repository
+    value.filter(|v| *v != "VERGEN_IDEMPOTENT_OUTPUT")
 }
 
-#[get("/f/<mmid>")]
-pub async fn lookup_mmid(db: &State<Arc<RwLock<Mochibase>>>, mmid: &str) -> Option<Redirect> {
-    let mmid: Mmid = mmid.try_into().ok()?;
-    let entry = db.read().unwrap().get(&mmid).cloned()?;
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct VersionInfo {
+    version: &'static str,
+    git_sha: Option<&'static str>,
+    build_timestamp: Option<&'static str>,
+    rustc_version: Option<&'static str>,
+}
+
+/// A response for a file which may be password protected. If the password
+/// was missing or incorrect, a page asking for it is returned instead.
+#[derive(Responder)]
+pub enum Gated<T> {
+    Open(T),
+    #[response(status = 403)]
+    Locked(Markup),
+
+    /// The file was asked for by the wrong name, so the client is sent to the
+    /// right one
+    Moved(Box<Redirect>),
+}
+
+/// Look up an entry which can still be downloaded. Expired entries may not
+/// have been cleaned up yet, so they are gone rather than missing.
+pub(crate) fn live_entry(db: &RwLock<Mochibase>, mmid: &str) -> Result<MochiFile, Status> {
+    let mmid: Mmid = mmid.try_into().map_err(|_| Status::NotFound)?;
+    let entry = db.read().unwrap().get(&mmid).cloned().ok_or(Status::NotFound)?;
+    if entry.is_expired() {
+        return Err(Status::Gone);
+    }
+
+    Ok(entry)
+}
+
+/// Check that a request may see an entry. For private entries, credentials
+/// are asked for if there are users who could give them.
+pub(crate) fn check_visible(entry: &MochiFile, user: &DownloadAuth, settings: &Settings) -> Result<(), Status> {
+    match (user.can_view(entry), &settings.auth) {
+        (true, _) => Ok(()),
+        (false, Some(_)) => Err(Status::Unauthorized),
+        (false, None) => Err(Status::NotFound),
+    }
+}
+
+/// Add the deletion token a file was requested with to a link, so links to
+/// private files keep working for their uploader
+pub(crate) fn with_token(url: String, user: &DownloadAuth) -> String {
+    match &user.token {
+        Some(token) => {
+            let separator = if url.contains('?') { '&' } else { '?' };
+            format!("{url}{separator}token={}", urlencoding::encode(token))
+        }
+        None => url,
+    }
+}
+
+/// Check whether the given password unlocks an entry. Entries without a
+/// password are always unlocked.
+pub(crate) async fn unlocked(entry: &MochiFile, password: Option<&str>) -> bool {
+    match (entry.password_hash(), password) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(hash), Some(password)) => verify_password(password, hash).await,
+    }
+}
+
+/// Whether a request was made by a link preview bot, going by its user agent
+pub struct PreviewBot(bool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PreviewBot {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let settings = Settings::for_request(req);
+        let is_bot = req.headers().get_one("User-Agent").is_some_and(|agent| {
+            let agent = agent.to_lowercase();
+            settings
+                .link_preview_bots
+                .iter()
+                .any(|bot| agent.contains(&bot.to_lowercase()))
+        });
+
+        Outcome::Success(Self(is_bot))
+    }
+}
+
+/// Either a redirect to a file, or a page describing it for link previews
+#[derive(Responder)]
+pub enum FileLink {
+    Redirect(Box<Redirect>),
+    Preview(Markup),
+}
+
+#[get("/f/<mmid>?<password>")]
+pub async fn lookup_mmid(
+    db: &State<Arc<RwLock<Mochibase>>>,
+    storage: &State<Arc<dyn StorageBackend>>,
+    settings: &Settings,
+    bot: PreviewBot,
+    user: DownloadAuth,
+    mmid: &str,
+    password: Option<&str>,
+) -> Result<Gated<FileLink>, Status> {
+    let entry = live_entry(db, mmid)?;
+    let mmid = entry.mmid();
+    check_visible(&entry, &user, settings)?;
+
+    if !unlocked(&entry, password).await {
+        return Ok(Gated::Locked(pages::password_gate(
+            settings,
+            &user,
+            &format!("/f/{mmid}"),
+            &[],
+            password.is_some(),
+        )));
+    }
+
+    // Bots would only get the file, which doesn't make for a good preview
+    if bot.0 {
+        let preview = pages::link_preview(&entry, storage.inner().as_ref(), settings)
+            .await
+            .ok_or(Status::NotFound)?;
+        return Ok(Gated::Open(FileLink::Preview(preview)));
+    }
 
-    Some(Redirect::to(uri!(lookup_mmid_name(
-        mmid.to_string(),
-        entry.name()
-    ))))
+    let file_path = uri!(lookup_mmid_name(mmid.to_string(), entry.name(), None::<bool>, password));
+    Ok(Gated::Open(FileLink::Redirect(Box::new(Redirect::to(with_token(
+        settings.server.path(&file_path.to_string()),
+        &user,
+    ))))))
 }
 
-#[get("/f/<mmid>?noredir&<download>")]
+#[allow(clippy::too_many_arguments)]
+#[get("/f/<mmid>?noredir&<download>&<password>")]
 pub async fn lookup_mmid_noredir(
     db: &State<Arc<RwLock<Mochibase>>>,
-    settings: &State<Settings>,
+    storage: &State<Arc<dyn StorageBackend>>,
+    metrics: &State<Arc<Metrics>>,
+    settings: &Settings,
+    headers: DownloadHeaders<'_>,
+    ip: Option<IpAddr>,
+    user: DownloadAuth,
     mmid: &str,
     download: bool,
-) -> Option<FileDownloader> {
-    let mmid: Mmid = mmid.try_into().ok()?;
-    let entry = db.read().unwrap().get(&mmid).cloned()?;
-
-    let file = File::open(settings.file_dir.join(entry.hash().to_string()))
-        .await
-        .ok()?;
-
-    Some(FileDownloader {
-        inner: file,
-        filename: entry.name().clone(),
-        content_type: ContentType::from_str(entry.mime_type()).unwrap_or(ContentType::Binary),
-        disposition: download
-    })
+    password: Option<&str>,
+) -> Result<Gated<FileDownloader>, Status> {
+    let files = FileSource { db, storage, metrics, settings, user: &user, ip, count_download: true };
+    mmid_noredir(files, headers, mmid, download, password).await
+}
+
+/// Get the headers [`lookup_mmid_noredir`] would send, without counting a
+/// download
+#[allow(clippy::too_many_arguments)]
+#[head("/f/<mmid>?noredir&<download>&<password>")]
+pub async fn head_mmid_noredir(
+    db: &State<Arc<RwLock<Mochibase>>>,
+    storage: &State<Arc<dyn StorageBackend>>,
+    metrics: &State<Arc<Metrics>>,
+    settings: &Settings,
+    headers: DownloadHeaders<'_>,
+    ip: Option<IpAddr>,
+    user: DownloadAuth,
+    mmid: &str,
+    download: bool,
+    password: Option<&str>,
+) -> Result<Gated<FileDownloader>, Status> {
+    let files = FileSource { db, storage, metrics, settings, user: &user, ip, count_download: false };
+    mmid_noredir(files, headers, mmid, download, password).await
+}
+
+async fn mmid_noredir(
+    files: FileSource<'_>,
+    headers: DownloadHeaders<'_>,
+    mmid: &str,
+    download: bool,
+    password: Option<&str>,
+) -> Result<Gated<FileDownloader>, Status> {
+    let entry = live_entry(files.db, mmid)?;
+    let mmid = entry.mmid();
+    check_visible(&entry, files.user, files.settings)?;
+
+    if !unlocked(&entry, password).await {
+        let download = download.to_string();
+        return Ok(Gated::Locked(pages::password_gate(
+            files.settings,
+            files.user,
+            &format!("/f/{mmid}"),
+            &[("noredir", ""), ("download", &download)],
+            password.is_some(),
+        )));
+    }
+
+    files.open(mmid, headers, download).await.map(Gated::Open).ok_or(Status::NotFound)
+}
+
+/// Everything needed to send a file to a client
+struct FileSource<'a> {
+    db: &'a Arc<RwLock<Mochibase>>,
+    storage: &'a Arc<dyn StorageBackend>,
+    metrics: &'a Metrics,
+    settings: &'a Settings,
+    user: &'a DownloadAuth,
+
+    /// The IP address of the client, for the activity log
+    ip: Option<IpAddr>,
+
+    /// Whether sending the file counts as a download, which it doesn't for
+    /// HEAD requests
+    count_download: bool,
+}
+
+impl FileSource<'_> {
+    /// Open the file for an entry which the client is allowed to download.
+    /// Only responses which send the file count as a download, so clients
+    /// checking their cached copy or asking for a range past the end of the
+    /// file don't use up its downloads.
+    async fn open(&self, mmid: &Mmid, headers: DownloadHeaders<'_>, download: bool) -> Option<FileDownloader> {
+        let entry = self.db.read().unwrap().get(mmid).cloned()?;
+        if entry.downloads_exhausted() {
+            return None;
+        }
+
+        let downloader = FileDownloader::open(&entry, self.storage.as_ref(), self.settings, headers, download).await?;
+        if self.count_download && downloader.sends_file() {
+            // Checked again while counting, as another client may have used
+            // up the last download since
+            let mut database = self.db.write().unwrap();
+            database.record_download(mmid)?;
+            database.record_event(EventKind::Download, mmid, self.ip, self.settings.max_events);
+            self.metrics.record_download();
+        }
+
+        Some(downloader)
+    }
+}
+
+/// The headers of a download request which decide what is sent back
+pub struct DownloadHeaders<'r> {
+    range: Option<&'r str>,
+    if_none_match: Option<&'r str>,
+    if_modified_since: Option<&'r str>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DownloadHeaders<'r> {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = req.headers();
+        Outcome::Success(Self {
+            range: headers.get_one("Range"),
+            if_none_match: headers.get_one("If-None-Match"),
+            if_modified_since: headers.get_one("If-Modified-Since"),
+        })
+    }
+}
+
+impl DownloadHeaders<'_> {
+    /// Whether the client's cached copy, as described by its conditional
+    /// request headers, is still current
+    fn is_cached(&self, etag: &str, upload_datetime: DateTime<Utc>) -> bool {
+        // When both are given, only the entity tags are checked
+        if let Some(tags) = self.if_none_match {
+            return tags
+                .split(',')
+                .map(|t| t.trim().trim_start_matches("W/"))
+                .any(|t| t == "*" || t == etag);
+        }
+
+        self.if_modified_since
+            .and_then(|d| DateTime::parse_from_rfc2822(d).ok())
+            .is_some_and(|since| upload_datetime.timestamp() <= since.timestamp())
+    }
+}
+
+/// Which part of a file is being sent to the client
+enum Content {
+    /// The whole file
+    Full(FileReader),
+
+    /// An inclusive byte range of the file
+    Partial(FileReader, u64, u64),
+
+    /// The requested range could not be satisfied
+    Unsatisfiable,
+
+    /// The client's cached copy is current, so nothing is sent
+    NotModified,
 }
 
 pub struct FileDownloader {
-    inner: tokio::fs::File,
+    content: Content,
     filename: String,
     content_type: ContentType,
     disposition: bool,
+
+    /// Whether the file could run scripts if it was shown by a browser
+    unsafe_type: bool,
+    size: u64,
+
+    /// Files are stored by their hash, so the hash identifies the exact
+    /// content being sent
+    etag: String,
+    upload_datetime: DateTime<Utc>,
+    expiry: DateTime<Utc>,
+
+    /// Whether only the client should cache the file, as with password
+    /// protected files
+    private: bool,
+
+    /// Whether search engines are asked not to index the file
+    noindex: bool,
+}
+
+impl FileDownloader {
+    /// Open the file backing a database entry so it can be sent to a client,
+    /// reading only the requested range if there is one
+    pub async fn open(
+        entry: &MochiFile,
+        storage: &dyn StorageBackend,
+        settings: &Settings,
+        headers: DownloadHeaders<'_>,
+        disposition: bool,
+    ) -> Option<Self> {
+        let size = storage.size(entry.hash()).await.ok()?;
+
+        // The content behind a hash never changes, so it can be cached until
+        // the file expires. The algorithm is included, as the same digest
+        // from another algorithm would be different content.
+        let etag = format!("\"{}-{}\"", entry.algo(), entry.hash());
+
+        // Only byte ranges are supported, any other kind of range is ignored
+        // and the full file is sent instead
+        let content = if headers.is_cached(&etag, entry.upload_datetime()) {
+            Content::NotModified
+        } else {
+            match headers.range.filter(|r| r.starts_with("bytes=")) {
+                None => Content::Full(storage.get(entry.hash(), None).await.ok()?),
+                Some(range) => match parse_range(range, size) {
+                    Some((start, end)) => Content::Partial(
+                        storage.get(entry.hash(), Some((start, end))).await.ok()?,
+                        start,
+                        end,
+                    ),
+                    None => Content::Unsatisfiable,
+                },
+            }
+        };
+
+        let content_type = content_type_for(entry, settings);
+        let unsafe_type = settings
+            .attachment_only_types
+            .iter()
+            .filter_map(|t| ContentType::parse_flexible(t))
+            .any(|t| t == content_type);
+
+        Some(Self {
+            content,
+            filename: entry.name().clone(),
+            content_type,
+            disposition,
+            unsafe_type,
+            size,
+            etag,
+            upload_datetime: entry.upload_datetime(),
+            expiry: entry.expiry(),
+            private: entry.password_hash().is_some(),
+            noindex: settings.robots.noindex,
+        })
+    }
+}
+
+impl FileDownloader {
+    /// Whether the response sends any of the file
+    fn sends_file(&self) -> bool {
+        matches!(self.content, Content::Full(_) | Content::Partial(..))
+    }
 }
 
 impl<'r> Responder<'r, 'r> for FileDownloader {
-    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'r> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+        let compressible = is_compressible(&self.content_type);
+
+        let max_age = (self.expiry - Utc::now()).num_seconds().max(0);
+        let visibility = if self.private { "private" } else { "public" };
+
         let mut resp = Response::build();
-        resp.streamed_body(self.inner)
-            .header(self.content_type);
+        resp.raw_header("ETag", self.etag)
+            .raw_header(
+                "Last-Modified",
+                self.upload_datetime.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+            )
+            .raw_header("Cache-Control", format!("{visibility}, max-age={max_age}, immutable"));
+        if self.noindex {
+            resp.raw_header("X-Robots-Tag", "noindex");
+        }
 
-        if self.disposition {
+        if let Content::NotModified = self.content {
+            return resp.status(Status::NotModified).ok();
+        }
+
+        // Rocket's shield sends `X-Content-Type-Options: nosniff` with every
+        // response, so browsers won't guess a different type for uploads and,
+        // for example, run a file sent as text as HTML
+        resp.header(self.content_type)
+            .raw_header("Accept-Ranges", "bytes");
+
+        // Files like HTML pages would run their scripts as this site, so they
+        // are always downloaded, and can't run scripts even if opened directly
+        if self.unsafe_type {
+            resp.raw_header("Content-Security-Policy", "sandbox");
+        }
+
+        if self.disposition || self.unsafe_type {
             resp.raw_header(
                 "Content-Disposition",
                 format!(
@@ -106,32 +888,438 @@ impl<'r> Responder<'r, 'r> for FileDownloader {
             );
         }
 
+        if compressible {
+            resp.raw_header("Vary", "Accept-Encoding");
+        }
+
+        // Responses to HEAD requests only have headers, and compressing would
+        // hide the length of the file, which is what they're usually after
+        let head = req.method() == Method::Head;
+
+        match self.content {
+            Content::Full(reader) if compressible && !head => {
+                let accept = req.headers().get_one("Accept-Encoding").unwrap_or_default();
+                match choose_encoding(accept) {
+                    Some(Encoding::Brotli) => {
+                        let encoder = BrotliEncoder::with_quality(BufReader::new(reader), Level::Precise(4));
+                        resp.raw_header("Content-Encoding", "br").streamed_body(encoder);
+                    }
+                    Some(Encoding::Gzip) => {
+                        let encoder = GzipEncoder::new(BufReader::new(reader));
+                        resp.raw_header("Content-Encoding", "gzip").streamed_body(encoder);
+                    }
+                    None => set_body(&mut resp, reader, self.size, head),
+                }
+            }
+            Content::Full(reader) => set_body(&mut resp, reader, self.size, head),
+            Content::Partial(reader, start, end) => {
+                resp.status(Status::PartialContent)
+                    .raw_header("Content-Range", format!("bytes {start}-{end}/{}", self.size));
+                set_body(&mut resp, reader, end - start + 1, head);
+            }
+            Content::Unsatisfiable => {
+                resp.status(Status::RangeNotSatisfiable)
+                    .raw_header("Content-Range", format!("bytes */{}", self.size));
+            }
+            Content::NotModified => unreachable!(),
+        }
+
         resp.ok()
     }
 }
 
+/// Send `length` bytes from `reader` as the body of a response. For HEAD
+/// requests nothing is read, but the length is still sent.
+fn set_body(resp: &mut response::Builder<'_>, reader: FileReader, length: u64, head: bool) {
+    if head {
+        // Rocket drops the body of responses to HEAD requests, but a body
+        // with a known size keeps its `Content-Length`
+        resp.sized_body(length as usize, io::Cursor::new(Vec::new()));
+    } else {
+        resp.raw_header("Content-Length", length.to_string())
+            .streamed_body(reader);
+    }
+}
 
-#[get("/f/<mmid>/<name>")]
+/// A compression algorithm which a download may be sent with
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+/// Pick a supported encoding from an HTTP `Accept-Encoding` header, preferring
+/// Brotli. Encodings with a quality value of zero are not accepted.
+fn choose_encoding(header: &str) -> Option<Encoding> {
+    let accepted: Vec<&str> = header
+        .split(',')
+        .filter_map(|e| {
+            let mut parts = e.split(';').map(str::trim);
+            let name = parts.next()?;
+            let refused = parts.any(|p| {
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            });
+            (!refused).then_some(name)
+        })
+        .collect();
+
+    if accepted.contains(&"br") {
+        Some(Encoding::Brotli)
+    } else if accepted.contains(&"gzip") {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+/// Whether a file of this type is worth compressing. Formats which are
+/// already compressed, like most images and video, are not.
+fn is_compressible(content_type: &ContentType) -> bool {
+    let sub = content_type.sub().as_str();
+
+    content_type.top() == "text"
+        || sub.ends_with("+xml")
+        || sub.ends_with("+json")
+        || matches!(
+            sub,
+            "json" | "xml" | "javascript" | "x-javascript" | "ecmascript" | "wasm"
+                | "x-sh" | "x-tar" | "rtf" | "x-subrip" | "toml" | "yaml"
+        )
+}
+
+/// Types for extensions which Rocket doesn't know, for files whose type
+/// couldn't be found from their contents. Mostly text formats, which can be
+/// shown in the browser rather than downloaded.
+const EXTENSION_TYPES: &[(&str, &str)] = &[
+    ("log", "text/plain"),
+    ("conf", "text/plain"),
+    ("cfg", "text/plain"),
+    ("ini", "text/plain"),
+    ("rs", "text/plain"),
+    ("py", "text/plain"),
+    ("c", "text/plain"),
+    ("h", "text/plain"),
+    ("cpp", "text/plain"),
+    ("go", "text/plain"),
+    ("java", "text/plain"),
+    ("ts", "text/plain"),
+    ("sh", "text/plain"),
+    ("sql", "text/plain"),
+    ("diff", "text/x-diff"),
+    ("patch", "text/x-diff"),
+    ("toml", "application/toml"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("srt", "application/x-subrip"),
+    ("vtt", "text/vtt"),
+];
+
+/// The type to send a file as. The stored MIME type is used if Rocket can
+/// understand it, otherwise the type is guessed from the file's extension,
+/// falling back to the default type in the settings.
+fn content_type_for(entry: &MochiFile, settings: &Settings) -> ContentType {
+    if let Some(content_type) = ContentType::parse_flexible(entry.mime_type())
+        .map(normalize_content_type)
+        .filter(|t| *t != ContentType::Binary)
+    {
+        return content_type;
+    }
+
+    let extension = Path::new(entry.name())
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+
+    ContentType::from_extension(&extension)
+        .or_else(|| {
+            EXTENSION_TYPES
+                .iter()
+                .find(|(e, _)| *e == extension)
+                .and_then(|(_, t)| ContentType::parse_flexible(t))
+        })
+        .or_else(|| ContentType::parse_flexible(&settings.default_content_type))
+        .map(normalize_content_type)
+        .unwrap_or(ContentType::Binary)
+}
+
+/// Replace legacy aliases with their standard types, and make sure text is
+/// decoded as UTF-8 when no other charset is given
+fn normalize_content_type(content_type: ContentType) -> ContentType {
+    match (content_type.top().as_str(), content_type.sub().as_str()) {
+        ("application" | "text", "x-javascript" | "ecmascript") => ContentType::JavaScript,
+        ("audio", "x-wav" | "wave") => ContentType::WAV,
+        ("audio", "x-flac") => ContentType::FLAC,
+        ("image", "x-ms-bmp") => ContentType::BMP,
+        ("image", "x-icon") => ContentType::Icon,
+        ("text", sub) if content_type.param("charset").is_none() => {
+            ContentType::new("text", sub.to_string()).with_params(("charset", "utf-8"))
+        }
+        _ => content_type,
+    }
+}
+
+/// Parse the first range of an HTTP `Range` header into an inclusive
+/// `(start, end)` byte pair. Returns [`None`] if the range is invalid or
+/// cannot be satisfied for a file of `size` bytes.
+///
+/// Requests for multiple ranges are served only the first range.
+fn parse_range(header: &str, size: u64) -> Option<(u64, u64)> {
+    if size == 0 {
+        return None;
+    }
+
+    let range = header.strip_prefix("bytes=")?.split(',').next()?.trim();
+    let (start, end) = range.split_once('-')?;
+
+    let (start, end) = if start.is_empty() {
+        // A suffix range, which requests the last `end` bytes of the file
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 {
+            return None;
+        }
+        (size.saturating_sub(suffix), size - 1)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = if end.is_empty() {
+            size - 1
+        } else {
+            end.parse::<u64>().ok()?.min(size - 1)
+        };
+        (start, end)
+    };
+
+    if start > end || start >= size {
+        return None;
+    }
+
+    Some((start, end))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/f/<mmid>/<name>?<download>&<password>")]
 pub async fn lookup_mmid_name(
     db: &State<Arc<RwLock<Mochibase>>>,
-    settings: &State<Settings>,
+    storage: &State<Arc<dyn StorageBackend>>,
+    metrics: &State<Arc<Metrics>>,
+    settings: &Settings,
+    headers: DownloadHeaders<'_>,
+    ip: Option<IpAddr>,
+    user: DownloadAuth,
     mmid: &str,
     name: &str,
-) -> Option<(ContentType, File)> {
-    let mmid: Mmid = mmid.try_into().ok()?;
-    let entry = db.read().unwrap().get(&mmid).cloned()?;
+    download: Option<bool>,
+    password: Option<&str>,
+) -> Result<Gated<FileDownloader>, Status> {
+    let files = FileSource { db, storage, metrics, settings, user: &user, ip, count_download: true };
+    mmid_name(files, headers, mmid, name, download, password).await
+}
 
-    // If the name does not match, then this is invalid
-    if name != entry.name() {
-        return None;
+/// Get the headers [`lookup_mmid_name`] would send, without counting a
+/// download
+#[allow(clippy::too_many_arguments)]
+#[head("/f/<mmid>/<name>?<download>&<password>")]
+pub async fn head_mmid_name(
+    db: &State<Arc<RwLock<Mochibase>>>,
+    storage: &State<Arc<dyn StorageBackend>>,
+    metrics: &State<Arc<Metrics>>,
+    settings: &Settings,
+    headers: DownloadHeaders<'_>,
+    ip: Option<IpAddr>,
+    user: DownloadAuth,
+    mmid: &str,
+    name: &str,
+    download: Option<bool>,
+    password: Option<&str>,
+) -> Result<Gated<FileDownloader>, Status> {
+    let files = FileSource { db, storage, metrics, settings, user: &user, ip, count_download: false };
+    mmid_name(files, headers, mmid, name, download, password).await
+}
+
+async fn mmid_name(
+    files: FileSource<'_>,
+    headers: DownloadHeaders<'_>,
+    mmid: &str,
+    name: &str,
+    download: Option<bool>,
+    password: Option<&str>,
+) -> Result<Gated<FileDownloader>, Status> {
+    let entry = live_entry(files.db, mmid)?;
+    let mmid = entry.mmid();
+
+    // If the name does not match, then this is invalid unless the client may
+    // be sent to the right name. That would reveal the name, so it's only
+    // done once the client is known to be able to see the file.
+    if entry.name() != name && files.settings.strict_file_names {
+        return Err(Status::NotFound);
+    }
+    check_visible(&entry, files.user, files.settings)?;
+    if entry.name() != name {
+        let file_path = uri!(lookup_mmid_name(mmid.to_string(), entry.name(), download, password));
+        return Ok(Gated::Moved(Box::new(Redirect::moved(with_token(
+            files.settings.server.path(&file_path.to_string()),
+            files.user,
+        )))));
+    }
+
+    // Files are shown inline unless a download is asked for
+    let download = download.unwrap_or(false);
+    if !unlocked(&entry, password).await {
+        let fields: &[(&str, &str)] = if download { &[("download", "true")] } else { &[] };
+        return Ok(Gated::Locked(pages::password_gate(
+            files.settings,
+            files.user,
+            &format!("/f/{mmid}/{}", urlencoding::encode(name)),
+            fields,
+            password.is_some(),
+        )));
+    }
+
+    files.open(mmid, headers, download).await.map(Gated::Open).ok_or(Status::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_entry_is_gone() {
+        let dir = std::env::temp_dir().join(format!("confetti-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut database = Mochibase::new(&dir.join("database.mochi")).unwrap();
+
+        let hash = blake3::hash(b"confetti");
+        let now = Utc::now();
+        let expired = Mmid::new_random(8);
+        let live = Mmid::new_random(8);
+        for (mmid, expiry) in [(&expired, now - TimeDelta::minutes(1)), (&live, now + TimeDelta::hours(1))] {
+            let file = MochiFile::new(mmid.clone(), "party.txt".into(), "text/plain".into(), hash, now, expiry);
+            database.insert(mmid, file);
+        }
+        let database = RwLock::new(database);
+
+        // It hasn't been cleaned up yet, but it still can't be downloaded
+        assert_eq!(live_entry(&database, &expired.to_string()).unwrap_err(), Status::Gone);
+        assert!(live_entry(&database, &live.to_string()).is_ok());
+        assert_eq!(
+            live_entry(&database, &Mmid::new_random(8).to_string()).unwrap_err(),
+            Status::NotFound
+        );
+
+        fs::remove_dir_all(dir).unwrap();
     }
 
-    let file = File::open(settings.file_dir.join(entry.hash().to_string()))
-        .await
-        .ok()?;
+    #[test]
+    fn renaming_expired_entry_is_gone() {
+        let dir = std::env::temp_dir().join(format!("confetti-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let mmid = Mmid::new_random(8);
+        let now = Utc::now();
+        let file = MochiFile::new(
+            mmid.clone(),
+            "party.txt".into(),
+            "text/plain".into(),
+            blake3::hash(b"confetti"),
+            now - TimeDelta::hours(2),
+            now - TimeDelta::hours(1),
+        );
+        let token = file.delete_token().clone();
+        let mut database = Mochibase::new(&dir.join("database.mochi")).unwrap();
+        database.insert(&mmid, file);
 
-    Some((
-        ContentType::from_str(entry.mime_type()).unwrap_or(ContentType::Binary),
-        file,
-    ))
+        let rocket = rocket::build().manage(Arc::new(RwLock::new(database)));
+        let db = State::<Arc<RwLock<Mochibase>>>::get(&rocket).unwrap();
+        let body = Json(RenameRequest { token, name: "cake.txt".into() });
+        let result = rename_mmid(db, &Settings::default(), &mmid.to_string(), body);
+        assert!(matches!(result, Err(ApiError::Gone(_))));
+        assert_eq!(db.read().unwrap().get(&mmid).unwrap().name(), "party.txt");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn deletion_is_saved() {
+        let dir = std::env::temp_dir().join(format!("confetti-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let settings = Settings::default();
+        let storage: Arc<dyn StorageBackend> = Arc::new(crate::storage::LocalStorage::new(&dir));
+
+        let hash = blake3::hash(b"confetti");
+        let upload = dir.join("upload");
+        fs::write(&upload, b"confetti").unwrap();
+        storage.put(&hash, &upload).await.unwrap();
+
+        let path = dir.join("database.mochi");
+        let mmid = Mmid::new_random(8);
+        let now = Utc::now();
+        let file = MochiFile::new(mmid.clone(), "party.txt".into(), "text/plain".into(), hash, now, now + TimeDelta::hours(1));
+        let token = file.delete_token().clone();
+        let mut database = Mochibase::new(&path).unwrap();
+        database.insert(&mmid, file);
+        database.save().unwrap();
+        let db = Arc::new(RwLock::new(database));
+        let metrics = Arc::new(Metrics::new(&db, storage.as_ref()).await);
+
+        let rocket = rocket::build().manage(db).manage(storage).manage(metrics);
+        let status = delete_mmid(
+            State::get(&rocket).unwrap(),
+            State::get(&rocket).unwrap(),
+            State::get(&rocket).unwrap(),
+            &settings,
+            None,
+            &mmid.to_string(),
+            &token,
+        )
+        .await;
+        assert_eq!(status, Status::Ok);
+
+        // The entry is gone from the database on disk, not just in memory
+        assert!(Mochibase::open(&path).unwrap().get(&mmid).is_none());
+        let storage = State::<Arc<dyn StorageBackend>>::get(&rocket).unwrap();
+        assert!(!storage.exists(&hash).await.unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn only_sending_the_file_counts_as_a_download() {
+        let dir = std::env::temp_dir().join(format!("confetti-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let settings = Settings::default();
+        let storage: Arc<dyn StorageBackend> = Arc::new(crate::storage::LocalStorage::new(&dir));
+
+        let contents = b"confetti";
+        let hash = blake3::hash(contents);
+        let upload = dir.join("upload");
+        fs::write(&upload, contents).unwrap();
+        storage.put(&hash, &upload).await.unwrap();
+
+        let mmid = Mmid::new_random(8);
+        let now = Utc::now();
+        let file = MochiFile::new(mmid.clone(), "party.txt".into(), "text/plain".into(), hash, now, now + TimeDelta::hours(1))
+            .with_max_downloads(Some(1));
+        let etag = format!("\"{}-{hash}\"", file.algo());
+        let mut database = Mochibase::new(&dir.join("database.mochi")).unwrap();
+        database.insert(&mmid, file);
+        let db = Arc::new(RwLock::new(database));
+        let metrics = Metrics::new(&db, storage.as_ref()).await;
+
+        let user = DownloadAuth { username: None, token: None };
+        let files = FileSource { db: &db, storage: &storage, metrics: &metrics, settings: &settings, user: &user, ip: None, count_download: true };
+        let headers = |range, if_none_match| DownloadHeaders { range, if_none_match, if_modified_since: None };
+
+        // Neither a cached copy nor a range past the end of the file sends it
+        let cached = files.open(&mmid, headers(None, Some(&etag)), false).await.unwrap();
+        assert!(matches!(cached.content, Content::NotModified));
+        let unsatisfiable = files.open(&mmid, headers(Some("bytes=100-200"), None), false).await.unwrap();
+        assert!(matches!(unsatisfiable.content, Content::Unsatisfiable));
+        assert!(!db.read().unwrap().get(&mmid).unwrap().downloads_exhausted());
+
+        assert!(files.open(&mmid, headers(None, None), false).await.unwrap().sends_file());
+        assert!(db.read().unwrap().get(&mmid).unwrap().downloads_exhausted());
+        assert!(files.open(&mmid, headers(None, None), false).await.is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,165 @@
+use std::io;
+
+use log::error;
+use rocket::{
+    http::Status,
+    response::{self, Responder},
+    serde::{
+        json::{self, Json},
+        Serialize,
+    },
+    Request,
+};
+
+/// An error returned from an API endpoint, which is sent to the client as JSON
+/// along with an appropriate status code.
+#[derive(Debug)]
+pub enum ApiError {
+    /// The request was invalid, such as having a disallowed duration
+    Invalid(String),
+
+    /// The requested upload or file does not exist
+    NotFound(String),
+
+    /// The requested file has expired, but may not have been removed yet
+    Gone(String),
+
+    /// The token or credentials given do not allow this action
+    Forbidden(String),
+
+    /// A chunk was invalid, such as being sent twice or being out of bounds
+    BadChunk(String),
+
+    /// The upload was larger than allowed
+    TooLarge(String),
+
+    /// The upload is of a type which the server doesn't allow
+    UnsupportedType(String),
+
+    /// An upload was finished before all of its data was sent
+    Incomplete(String),
+
+    /// The uploaded data did not match the hash the client expected
+    HashMismatch(String),
+
+    /// The server isn't accepting this request right now, such as while it
+    /// is shutting down
+    Unavailable(String),
+
+    /// The server doesn't have enough disk space left for the upload
+    InsufficientStorage(String),
+
+    /// Something went wrong on the server
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct ApiErrorBody<'a> {
+    error: &'a str,
+    message: &'a str,
+}
+
+impl ApiError {
+    /// The HTTP status code for this error
+    pub fn status(&self) -> Status {
+        match self {
+            Self::Invalid(_) => Status::BadRequest,
+            Self::NotFound(_) => Status::NotFound,
+            Self::Gone(_) => Status::Gone,
+            Self::Forbidden(_) => Status::Forbidden,
+            Self::BadChunk(_) => Status::BadRequest,
+            Self::TooLarge(_) => Status::PayloadTooLarge,
+            Self::UnsupportedType(_) => Status::UnsupportedMediaType,
+            Self::Incomplete(_) => Status::Conflict,
+            Self::HashMismatch(_) => Status::UnprocessableEntity,
+            Self::Unavailable(_) => Status::ServiceUnavailable,
+            Self::InsufficientStorage(_) => Status::InsufficientStorage,
+            Self::Internal(_) => Status::InternalServerError,
+        }
+    }
+
+    /// A short machine readable name for this kind of error
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Invalid(_) => "invalid",
+            Self::NotFound(_) => "not_found",
+            Self::Gone(_) => "gone",
+            Self::Forbidden(_) => "forbidden",
+            Self::BadChunk(_) => "bad_chunk",
+            Self::TooLarge(_) => "too_large",
+            Self::UnsupportedType(_) => "unsupported_type",
+            Self::Incomplete(_) => "incomplete",
+            Self::HashMismatch(_) => "hash_mismatch",
+            Self::Unavailable(_) => "unavailable",
+            Self::InsufficientStorage(_) => "insufficient_storage",
+            Self::Internal(_) => "internal",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Self::Invalid(m)
+            | Self::NotFound(m)
+            | Self::Gone(m)
+            | Self::Forbidden(m)
+            | Self::BadChunk(m)
+            | Self::TooLarge(m)
+            | Self::UnsupportedType(m)
+            | Self::Incomplete(m)
+            | Self::HashMismatch(m)
+            | Self::Unavailable(m)
+            | Self::InsufficientStorage(m)
+            | Self::Internal(m) => m,
+        }
+    }
+
+    /// The message sent to the client for this error. Internal errors are
+    /// logged instead, since their details can include paths and other
+    /// information about the server.
+    pub fn client_message(&self) -> &str {
+        match self {
+            Self::Internal(m) => {
+                error!("Internal error: {m}");
+                "Internal server error"
+            }
+            _ => self.message(),
+        }
+    }
+
+    /// The JSON body sent to the client for this error, for responses which
+    /// aren't sent through Rocket, like websocket messages
+    pub fn to_json(&self) -> String {
+        json::to_string(&ApiErrorBody {
+            error: self.kind(),
+            message: self.client_message(),
+        })
+        .unwrap_or_default()
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.kind(), self.message())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<io::Error> for ApiError {
+    fn from(value: io::Error) -> Self {
+        Self::Internal(value.to_string())
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let body = Json(ApiErrorBody {
+            error: self.kind(),
+            message: self.client_message(),
+        });
+
+        response::Response::build_from(body.respond_to(req)?)
+            .status(self.status())
+            .ok()
+    }
+}
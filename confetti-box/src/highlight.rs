@@ -0,0 +1,47 @@
+use std::{path::Path, sync::LazyLock};
+
+use rocket::{FromFormField, UriDisplayQuery};
+use syntect::{
+    highlighting::ThemeSet,
+    html::highlighted_html_for_string,
+    parsing::{SyntaxReference, SyntaxSet},
+};
+
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+static THEMES: LazyLock<ThemeSet> = LazyLock::new(ThemeSet::load_defaults);
+
+/// The color theme used to highlight source code
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, FromFormField, UriDisplayQuery)]
+pub enum HighlightTheme {
+    #[field(value = "light")]
+    Light,
+    #[default]
+    #[field(value = "dark")]
+    Dark,
+}
+
+impl HighlightTheme {
+    fn name(&self) -> &'static str {
+        match self {
+            HighlightTheme::Light => "InspiredGitHub",
+            HighlightTheme::Dark => "base16-ocean.dark",
+        }
+    }
+}
+
+/// Find the language of a file from its name, if it looks like source code
+pub fn detect_language(name: &str) -> Option<&'static SyntaxReference> {
+    let extension = Path::new(name).extension()?.to_str()?;
+    SYNTAXES
+        .find_syntax_by_extension(extension)
+        .filter(|syntax| syntax.name != "Plain Text")
+}
+
+/// Render source code as syntax highlighted HTML, wrapped in a `<pre>`
+pub fn highlight(
+    text: &str,
+    syntax: &SyntaxReference,
+    theme: HighlightTheme,
+) -> Option<String> {
+    highlighted_html_for_string(text, &SYNTAXES, syntax, &THEMES.themes[theme.name()]).ok()
+}
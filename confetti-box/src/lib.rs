@@ -1,40 +1,87 @@
+pub mod admin;
+pub mod auth;
+pub mod cors;
 pub mod database;
+pub mod encryption;
 pub mod endpoints;
+pub mod error;
+pub mod highlight;
+pub mod metrics;
 pub mod pages;
+pub mod ratelimit;
+pub mod remote;
 pub mod resources;
 pub mod settings;
+pub mod stats;
+pub mod storage;
 pub mod strings;
+pub mod thumbnail;
+#[cfg(unix)]
+pub mod unix_socket;
 pub mod utils;
 
 use std::{
-    io::{self, ErrorKind},
-    sync::{Arc, RwLock},
+    io,
+    net::IpAddr,
+    sync::{atomic::{AtomicBool, Ordering}, Arc, RwLock},
+    time::Duration,
 };
 
 use crate::{
+    auth::{hash_password, AuthUser, ChunkAuth},
+    error::ApiError,
+    metrics::Metrics,
     pages::{footer, head},
-    settings::Settings,
-    strings::to_pretty_time,
+    ratelimit::{RateLimit, UploadRateLimit},
+    settings::{EvictionPolicy, Settings},
+    storage::StorageBackend,
+    strings::{parse_time_string, sanitize_filename, to_pretty_size, to_pretty_time, SizeUnits},
+    utils::{has_space_for, hash_file, BackgroundHasher, FileHasher},
 };
 use chrono::{TimeDelta, Utc};
-use database::{Chunkbase, ChunkedInfo, Mmid, MochiFile, Mochibase};
+use database::{
+    evict_for, Chunkbase, ChunkedInfo, EventKind, HashClaim, Mmid, MochiFile, Mochibase, Visibility,
+};
+use log::warn;
 use maud::{html, Markup, PreEscaped};
 use rocket::{
-    data::ToByteUnit, futures::{SinkExt as _, StreamExt as _}, get, post, serde::{json::{self, Json}, Serialize}, tokio::{
-        fs, io::{AsyncSeekExt, AsyncWriteExt}
-    }, Data, State
+    data::ToByteUnit, form::Form, fs::TempFile, futures::{SinkExt as _, StreamExt as _}, get, post, response::stream::{Event, EventStream}, serde::{json::{self, Json}, Deserialize, Serialize}, tokio::{
+        fs, io::{AsyncSeekExt, AsyncWriteExt}, select, time
+    }, Data, FromForm, Shutdown, State
 };
 use uuid::Uuid;
 
+/// How many random MMIDs to try before giving up on finding an unused one
+const MMID_ATTEMPTS: usize = 16;
+
+/// Why uploads are refused while the server is in read-only mode
+const READ_ONLY_MESSAGE: &str = "Server is in read-only mode for maintenance, try again later";
+
+/// How often the progress of a chunked upload is checked for changes
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
 #[get("/")]
-pub fn home(settings: &State<Settings>) -> Markup {
+pub fn home(settings: &Settings) -> Markup {
+    let (durations, selected) = settings.duration.offered();
+
     html! {
-        (head("Confetti-Box"))
-        script src="/resources/request.js" { }
+        (head(settings, settings.server.site_title()))
+        script src=(settings.server.path("/resources/request.js"))
+            data-root-path=(settings.server.path(""))
+            data-max-frame-size=(settings.websocket_max_frame_size) { }
 
         center {
-            h1 { "Confetti-Box 🎉" }
-            h2 { "Files up to " (settings.max_filesize.bytes()) " in size are allowed!" }
+            h1 {
+                @if let Some(title) = &settings.server.site_title {
+                    (title)
+                } @else {
+                    "Confetti-Box 🎉"
+                }
+            }
+            @if let Some(tagline) = &settings.server.tagline {
+                p { (tagline) }
+            }
+            h2 { "Files up to " (to_pretty_size(settings.max_filesize, SizeUnits::Decimal)) " in size are allowed!" }
             hr;
             button.main_file_upload #fileButton onclick="document.getElementById('fileInput').click()" {
                 h4 { "Upload File(s)" }
@@ -42,8 +89,8 @@ pub fn home(settings: &State<Settings>) -> Markup {
             }
             h3 { "Expire after:" }
             div id="durationBox" {
-                @for d in &settings.duration.allowed {
-                    button.button.{@if settings.duration.default == *d { "selected" }}
+                @for d in &durations {
+                    button.button.{@if selected == *d { "selected" }}
                     data-duration-seconds=(d.num_seconds())
                     {
                         (PreEscaped(to_pretty_time(d.num_seconds() as u32)))
@@ -53,7 +100,7 @@ pub fn home(settings: &State<Settings>) -> Markup {
             form #uploadForm {
                 // It's stupid how these can't be styled so they're just hidden here...
                 input #fileDuration type="text" name="duration" minlength="2"
-                maxlength="7" value=(settings.duration.default.num_seconds().to_string()) style="display:none;";
+                maxlength="7" value=(selected.num_seconds().to_string()) style="display:none;";
                 input #fileInput type="file" name="fileUpload" multiple
                 onchange="formSubmit(this.parentNode)" data-max-filesize=(settings.max_filesize) style="display:none;";
             }
@@ -65,7 +112,7 @@ pub fn home(settings: &State<Settings>) -> Markup {
             }
 
             hr;
-            (footer())
+            (footer(settings))
         }
     }
 }
@@ -82,6 +129,11 @@ pub struct ChunkedResponse {
     /// Valid max chunk size in bytes
     #[serde(skip_serializing_if = "Option::is_none")]
     chunk_size: Option<u64>,
+
+    /// The allowed duration in seconds closest to the one requested, if the
+    /// requested one isn't allowed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suggested_duration: Option<i64>,
 }
 
 impl ChunkedResponse {
@@ -92,6 +144,251 @@ impl ChunkedResponse {
             ..Default::default()
         }
     }
+
+    /// Suggest the closest allowed duration if `duration` isn't allowed
+    fn with_duration_hint(mut self, settings: &Settings, duration: TimeDelta) -> Self {
+        if settings.duration.restrict_to_allowed && !settings.duration.allowed.contains(&duration) {
+            self.suggested_duration = settings
+                .duration
+                .closest_allowed(duration)
+                .map(|d| d.num_seconds());
+        }
+
+        self
+    }
+}
+
+/// Whether the server is accepting new uploads. This is switched off while the
+/// server shuts down, so uploads which are already in progress can finish.
+///
+/// Uploads can also be stopped by putting the server in read-only mode, such
+/// as while it is being backed up. Unlike shutting down, this also stops
+/// uploads which are already in progress, so nothing is written.
+#[derive(Debug)]
+pub struct UploadGate {
+    open: AtomicBool,
+    read_only: AtomicBool,
+}
+
+impl UploadGate {
+    pub fn new(read_only: bool) -> Self {
+        Self {
+            open: AtomicBool::new(true),
+            read_only: AtomicBool::new(read_only),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::Relaxed) && !self.is_read_only()
+    }
+
+    /// Stop accepting new uploads
+    pub fn close(&self) {
+        self.open.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        !self.open.load(Ordering::Relaxed)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Relaxed);
+    }
+
+    /// Why new uploads are being refused, if they are
+    pub fn refusal(&self) -> Option<&'static str> {
+        if self.is_read_only() {
+            Some(READ_ONLY_MESSAGE)
+        } else if self.is_shutting_down() {
+            Some("Server is shutting down")
+        } else {
+            None
+        }
+    }
+}
+
+
+/// Check the size and duration of a new upload against the server's limits,
+/// returning an error explaining the problem if it isn't allowed
+fn check_upload(settings: &Settings, size: u64, duration: TimeDelta) -> Result<(), ApiError> {
+    if size == 0 {
+        return Err(ApiError::Invalid("File is empty".into()));
+    }
+    if size > settings.max_filesize {
+        return Err(ApiError::TooLarge("File too large".into()));
+    }
+    check_duration(settings, duration).map_err(ApiError::Invalid)?;
+    if !has_space_for(settings, size) {
+        return Err(ApiError::InsufficientStorage("Not enough space".into()));
+    }
+
+    Ok(())
+}
+
+/// Check how long a file will be kept against the server's limits, returning a
+/// message explaining the problem if it isn't allowed
+pub(crate) fn check_duration(settings: &Settings, duration: TimeDelta) -> Result<(), String> {
+    if settings.duration.restrict_to_allowed && !settings.duration.allowed.contains(&duration) {
+        return Err(match settings.duration.closest_allowed(duration) {
+            Some(d) => format!(
+                "Duration not allowed, the closest allowed duration is {}",
+                to_pretty_time(d.num_seconds() as u32).replace("<br>", " ")
+            ),
+            None => "Duration not allowed".into(),
+        });
+    }
+    if duration > settings.duration.maximum {
+        return Err("Duration too large".into());
+    }
+    if duration < settings.duration.minimum {
+        return Err(format!(
+            "Duration too short, files must be kept for at least {}",
+            to_pretty_time(settings.duration.minimum.num_seconds() as u32).replace("<br>", " ")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Clean up the name of a file being uploaded, falling back to `file` if
+/// nothing usable is left
+fn upload_name(settings: &Settings, name: &str) -> String {
+    sanitize_filename(name, settings.max_filename_length).unwrap_or_else(|| "file".into())
+}
+
+/// Check that a new file of `size` bytes fits in the storage quota, evicting
+/// other files if the eviction policy allows it
+async fn make_room(
+    main_db: &Arc<RwLock<Mochibase>>,
+    storage: &dyn StorageBackend,
+    metrics: &Metrics,
+    settings: &Settings,
+    size: u64,
+) -> bool {
+    let Some(quota) = settings.max_total_storage_bytes else {
+        return true;
+    };
+
+    match settings.eviction_policy {
+        EvictionPolicy::Reject => main_db.read().unwrap().stored_bytes() + size <= quota,
+        EvictionPolicy::EarliestExpiry => {
+            let thumb_dir = thumbnail::thumbnail_dir(settings);
+            evict_for(main_db, storage, metrics, &thumb_dir, quota, size).await
+        }
+    }
+}
+
+/// The response to a finished upload
+#[derive(Serialize)]
+pub struct UploadedFile {
+    #[serde(flatten)]
+    file: MochiFile,
+
+    /// Whether an identical file was already stored, so the upload was
+    /// discarded and the new entry points at the existing copy
+    deduplicated: bool,
+}
+
+/// Pick a random MMID which isn't in use yet. The database should stay locked
+/// until the new entry is inserted, so nothing else can take it meanwhile.
+pub(crate) fn unused_mmid(main_db: &Mochibase, settings: &Settings) -> Result<Mmid, ApiError> {
+    (0..MMID_ATTEMPTS)
+        .map(|_| Mmid::new_random(settings.mmid_length))
+        .find(|mmid| main_db.get(mmid).is_none())
+        .ok_or(ApiError::Internal("Failed to generate a unique MMID".into()))
+}
+
+/// Move a fully uploaded file into storage, or discard it if an identical
+/// file is already stored, then add an entry for it to the database. The
+/// returned entry has the password hash removed, so it can be sent to the
+/// uploader, who needs the deletion token.
+async fn commit_upload(
+    main_db: &Arc<RwLock<Mochibase>>,
+    storage: &dyn StorageBackend,
+    metrics: &Metrics,
+    settings: &Settings,
+    info: ChunkedInfo,
+    hash: blake3::Hash,
+) -> Result<UploadedFile, ApiError> {
+    let now = Utc::now();
+
+    // A file which doesn't match its declared size is missing data, and storing
+    // it would hand out a corrupt download
+    let size = fs::metadata(&info.path).await?.len();
+    if size == 0 || size != info.size {
+        fs::remove_file(&info.path).await?;
+        return Err(ApiError::Incomplete(format!(
+            "Received {size} bytes, but the file is {} bytes",
+            info.size
+        )));
+    }
+
+    let file_type = file_format::FileFormat::from_file(&info.path)?;
+    let mime_type = file_type.media_type();
+    if !settings.mime_type_allowed(mime_type) {
+        fs::remove_file(&info.path).await?;
+        return Err(ApiError::UnsupportedType(format!("Files of type {mime_type} are not allowed")));
+    }
+
+    // Removing the last entry for this hash while the file is stored would
+    // delete it out from under the new entry, so removals wait until it's in
+    let _claim = HashClaim::acquire(main_db, hash).await;
+
+    let mut new_bytes = 0;
+    let deduplicated = main_db.read().unwrap().get_hash(&hash).is_some();
+    if !deduplicated {
+        new_bytes = size;
+        if !make_room(main_db, storage, metrics, settings, size).await {
+            fs::remove_file(&info.path).await?;
+            return Err(ApiError::TooLarge("Not enough storage space for this file".into()));
+        }
+
+        if let Err(e) = storage.put(&hash, &info.path).await {
+            let _ = fs::remove_file(&info.path).await;
+            return Err(e.into());
+        }
+    } else if settings.overwrite {
+        // The stored copy is still there if replacing it fails, so the upload
+        // can go ahead
+        if let Err(e) = storage.put(&hash, &info.path).await {
+            warn!("Failed to overwrite stored file {hash}: {e}");
+            let _ = fs::remove_file(&info.path).await;
+        }
+    } else {
+        fs::remove_file(&info.path).await?;
+    }
+    metrics.record_upload(new_bytes);
+
+    // Hold the lock while picking an MMID, so nothing else can take it before
+    // the entry is inserted
+    let mut main_db = main_db.write().unwrap();
+    let mmid = unused_mmid(&main_db, settings)?;
+
+    let constructed_file = MochiFile::new(
+        mmid.clone(),
+        info.name,
+        mime_type.to_string(),
+        hash,
+        now,
+        now + info.expire_duration,
+    )
+    .with_algo(settings.hash_algorithm)
+    .with_size(size)
+    .with_max_downloads(info.max_downloads)
+    .with_password_hash(info.password_hash)
+    .with_visibility(info.visibility);
+
+    main_db.insert(&mmid, constructed_file.clone());
+    main_db.record_event(EventKind::Upload, &mmid, info.client_ip, settings.max_events);
+
+    Ok(UploadedFile {
+        file: constructed_file.with_password_hash(None),
+        deduplicated,
+    })
 }
 
 /// Start a chunked upload. Response contains all the info you need to continue
@@ -99,57 +396,160 @@ impl ChunkedResponse {
 #[post("/upload/chunked", data = "<file_info>")]
 pub async fn chunked_upload_start(
     db: &State<Arc<RwLock<Chunkbase>>>,
-    settings: &State<Settings>,
+    settings: &Settings,
+    gate: &State<Arc<UploadGate>>,
+    limit: UploadRateLimit,
+    user: AuthUser,
     file_info: Json<ChunkedInfo>,
-) -> Result<Json<ChunkedResponse>, std::io::Error> {
+) -> Result<Json<ChunkedResponse>, ApiError> {
+    if let Some(reason) = gate.refusal() {
+        return Err(ApiError::Unavailable(reason.into()));
+    }
+
+    start_upload(db, settings, limit.ip, user.credentials, file_info.into_inner()).await.map(Json)
+}
+
+/// Start several chunked uploads at once. Each file is checked on its own, so
+/// one which isn't allowed doesn't stop the others from starting.
+#[post("/upload/chunked/batch", data = "<files>")]
+pub async fn chunked_upload_batch(
+    db: &State<Arc<RwLock<Chunkbase>>>,
+    settings: &Settings,
+    gate: &State<Arc<UploadGate>>,
+    limit: UploadRateLimit,
+    user: AuthUser,
+    files: Json<Vec<ChunkedInfo>>,
+) -> Result<Json<Vec<ChunkedResponse>>, ApiError> {
+    if let Some(reason) = gate.refusal() {
+        return Err(ApiError::Unavailable(reason.into()));
+    }
+
+    let mut responses = Vec::with_capacity(files.len());
+    for file_info in files.into_inner() {
+        let response = start_upload(db, settings, limit.ip, user.credentials, file_info)
+            .await
+            .unwrap_or_else(|e| ChunkedResponse::failure(e.client_message()));
+        responses.push(response);
+    }
+
+    Ok(Json(responses))
+}
+
+/// Check that a file may be uploaded, and if so, set up a chunked upload for
+/// it. Files which aren't allowed get a failed response rather than an error.
+async fn start_upload(
+    db: &RwLock<Chunkbase>,
+    settings: &Settings,
+    ip: Option<IpAddr>,
+    credentials: Option<blake3::Hash>,
+    mut file_info: ChunkedInfo,
+) -> Result<ChunkedResponse, ApiError> {
     // Perform some sanity checks
-    if file_info.size > settings.max_filesize {
-        return Ok(Json(ChunkedResponse::failure("File too large")));
+    if let Err(e) = check_upload(settings, file_info.size, file_info.expire_duration) {
+        return Ok(ChunkedResponse::failure(e.client_message()).with_duration_hint(settings, file_info.expire_duration));
     }
-    if settings.duration.restrict_to_allowed
-        && !settings
-            .duration
-            .allowed
-            .contains(&file_info.expire_duration)
-    {
-        return Ok(Json(ChunkedResponse::failure("Duration not allowed")));
+    if file_info.mime_type.as_ref().is_some_and(|t| !settings.mime_type_allowed(t)) {
+        return Ok(ChunkedResponse::failure("File type not allowed"));
     }
-    if file_info.expire_duration > settings.duration.maximum {
-        return Ok(Json(ChunkedResponse::failure("Duration too large")));
+
+    file_info.name = upload_name(settings, &file_info.name);
+    file_info.client_ip = ip;
+    file_info.credentials = credentials;
+    file_info.hasher = Some(FileHasher::new(settings.hash_algorithm));
+    if let Some(password) = file_info.password.take() {
+        file_info.password_hash = Some(
+            hash_password(&password)
+                .await
+                .ok_or(ApiError::Internal("Failed to hash password".into()))?,
+        );
     }
 
-    let uuid = db.write().unwrap().new_file(
-        file_info.0,
-        &settings.temp_dir,
-        TimeDelta::seconds(30)
-    )?;
+    let uuid = {
+        let mut db = db.write().unwrap();
+        if db.is_full(settings.max_concurrent_chunks) {
+            return Ok(ChunkedResponse::failure("Too many uploads in progress, try again later"));
+        }
 
-    Ok(Json(ChunkedResponse {
+        // The rate limit only checks this once per request, which isn't
+        // enough when starting several uploads
+        let limited = settings.rate_limit.enabled
+            && ip.is_some_and(|ip| db.count_for_ip(&ip) >= settings.rate_limit.concurrent_uploads);
+        if limited {
+            return Ok(ChunkedResponse::failure("Too many uploads in progress from this address"));
+        }
+
+        db.new_file(file_info, &settings.temp_dir, settings.chunk_timeout())?
+    };
+
+    Ok(ChunkedResponse {
         status: true,
         message: "".into(),
         uuid: Some(uuid),
         chunk_size: Some(settings.chunk_size),
-    }))
+        ..Default::default()
+    })
 }
 
+#[allow(clippy::too_many_arguments)]
 #[post("/upload/chunked/<uuid>?<chunk>", data = "<data>")]
 pub async fn chunked_upload_continue(
     chunk_db: &State<Arc<RwLock<Chunkbase>>>,
-    settings: &State<Settings>,
+    settings: &Settings,
+    gate: &State<Arc<UploadGate>>,
+    _limit: RateLimit,
+    _user: ChunkAuth,
     data: Data<'_>,
     uuid: &str,
     chunk: u64,
-) -> Result<(), io::Error> {
-    let uuid = Uuid::parse_str(uuid).map_err(io::Error::other)?;
-    let data_stream = data.open((settings.chunk_size + 100).bytes());
+) -> Result<(), ApiError> {
+    if gate.is_read_only() {
+        return Err(ApiError::Unavailable(READ_ONLY_MESSAGE.into()));
+    }
+
+    let uuid = Uuid::parse_str(uuid).map_err(|_| ApiError::NotFound("Invalid UUID".into()))?;
+    let data_stream = data.open(settings.chunk_size.bytes());
 
     let chunked_info = match chunk_db.read().unwrap().get_file(&uuid) {
         Some(s) => s.clone(),
-        None => return Err(io::Error::other("Invalid UUID")),
+        None => return Err(ApiError::NotFound("Invalid UUID".into())),
     };
 
     if chunked_info.1.recieved_chunks.contains(&chunk) {
-        return Err(io::Error::new(ErrorKind::Other, "Chunk already uploaded"));
+        return Err(ApiError::BadChunk("Chunk already uploaded".into()));
+    }
+
+    let offset = chunk * settings.chunk_size;
+    if (offset > chunked_info.1.size) | (offset > settings.max_filesize) {
+        return Err(ApiError::BadChunk("Invalid chunk number for file".into()));
+    }
+
+    // The chunk is kept in memory so it can be added to the running hash
+    // without reading it back from the file. Only one chunk's worth is read,
+    // and anything larger is rejected before writing, so the upload is left
+    // as it was and the chunk can be sent again.
+    let data = data_stream.into_bytes().await?;
+    if !data.is_complete() {
+        return Err(ApiError::TooLarge(format!(
+            "Chunk is larger than the chunk size of {} bytes",
+            settings.chunk_size
+        )));
+    }
+    let data = data.into_inner();
+    let written = data.len() as u64;
+    if offset + written > chunked_info.1.size {
+        return Err(ApiError::TooLarge("Chunk goes past the end of the file".into()));
+    }
+
+    // When the size is only a maximum, a short chunk marks the end of the
+    // file, so it has to be the last one
+    let short = written < settings.chunk_size;
+    if settings.upload_size_is_maximum {
+        if chunked_info.1.end.is_some_and(|end| short || offset >= end) {
+            return Err(ApiError::BadChunk("Chunk is past the end of the file".into()));
+        }
+        if short && chunked_info.1.recieved_chunks.iter().any(|c| *c > chunk) {
+            return Err(ApiError::BadChunk("Only the last chunk may be shorter than the chunk size".into()));
+        }
     }
 
     let mut file = fs::File::options()
@@ -159,193 +559,728 @@ pub async fn chunked_upload_continue(
         .open(&chunked_info.1.path)
         .await?;
 
-    let offset = chunk * settings.chunk_size;
-    if (offset > chunked_info.1.size) | (offset > settings.max_filesize) {
-        return Err(io::Error::new(
-            ErrorKind::InvalidInput,
-            "Invalid chunk number for file",
-        ));
-    }
-
     file.seek(io::SeekFrom::Start(offset)).await?;
-    let written = data_stream.stream_to(&mut file).await?.written;
+    file.write_all(&data).await?;
     file.flush().await?;
-    let position = file.stream_position().await?;
-
-    if written > settings.chunk_size {
-        chunk_db.write().unwrap().remove_file(&uuid)?;
-        return Err(io::Error::other("Wrote more than one chunk"));
+    let position = offset + written;
+    let is_end = position == chunked_info.1.size || (settings.upload_size_is_maximum && short);
+    if settings.upload_size_is_maximum && short {
+        chunk_db.write().unwrap().set_end(&uuid, position);
     }
-    if position > chunked_info.1.size {
-        chunk_db.write().unwrap().remove_file(&uuid)?;
-        return Err(io::Error::other("File larger than expected"));
+
+    // A short chunk before the end leaves a gap in the file, which the
+    // running hash wouldn't include, so only full chunks or the last one count
+    let hasher = chunk_db.write().unwrap().take_hasher(&uuid, chunk);
+    if let Some(mut hasher) = hasher {
+        if !short || is_end {
+            hasher.update(&data);
+            chunk_db.write().unwrap().return_hasher(&uuid, hasher);
+        }
     }
 
     chunk_db.write().unwrap().add_recieved_chunk(&uuid, chunk);
-    chunk_db.write().unwrap().extend_timeout(&uuid, TimeDelta::seconds(30));
+    chunk_db.write().unwrap().extend_timeout(&uuid, settings.chunk_timeout());
 
     Ok(())
 }
 
+#[derive(Serialize)]
+pub struct ChunkedStatus {
+    /// The total size of the file in bytes
+    size: u64,
+
+    /// Valid max chunk size in bytes
+    chunk_size: u64,
+
+    /// The chunk numbers which the server has already recieved
+    received_chunks: Vec<u64>,
+}
+
+/// Get the status of an in progress chunked upload, so that a client can
+/// resume it by only sending the missing chunks.
+#[get("/upload/chunked/<uuid>?status", rank = 2)]
+pub async fn chunked_upload_status(
+    chunk_db: &State<Arc<RwLock<Chunkbase>>>,
+    settings: &Settings,
+    _user: ChunkAuth,
+    uuid: &str,
+) -> Result<Json<ChunkedStatus>, ApiError> {
+    let uuid = Uuid::parse_str(uuid).map_err(|_| ApiError::NotFound("Invalid UUID".into()))?;
+    let chunked_info = match chunk_db.read().unwrap().get_file(&uuid) {
+        Some(s) => s.clone(),
+        None => return Err(ApiError::NotFound("Invalid UUID".into())),
+    };
+
+    let mut received_chunks: Vec<u64> = chunked_info.1.recieved_chunks.into_iter().collect();
+    received_chunks.sort_unstable();
+
+    Ok(Json(ChunkedStatus {
+        size: chunked_info.1.size,
+        chunk_size: settings.chunk_size,
+        received_chunks,
+    }))
+}
+
+#[derive(Serialize, PartialEq)]
+pub struct ChunkedProgress {
+    /// The number of bytes the server has recieved
+    received_bytes: u64,
+
+    /// The total size of the file in bytes
+    total: u64,
+}
+
+/// Follow the progress of a chunked upload as Server-Sent Events, so it can
+/// be shown while the chunks are being sent over other connections. A
+/// `progress` event is sent whenever more of the file arrives, and an `end`
+/// event once the upload is finished, times out, or is cancelled.
+#[get("/upload/chunked/<uuid>/progress")]
+pub async fn chunked_upload_progress(
+    chunk_db: &State<Arc<RwLock<Chunkbase>>>,
+    settings: &Settings,
+    _user: ChunkAuth,
+    mut shutdown: Shutdown,
+    uuid: &str,
+) -> Result<EventStream![], ApiError> {
+    let uuid = Uuid::parse_str(uuid).map_err(|_| ApiError::NotFound("Invalid UUID".into()))?;
+    if chunk_db.read().unwrap().get_file(&uuid).is_none() {
+        return Err(ApiError::NotFound("Invalid UUID".into()));
+    }
+
+    let chunk_db = Arc::clone(chunk_db);
+    let chunk_size = settings.chunk_size;
+
+    Ok(EventStream! {
+        let mut interval = time::interval(PROGRESS_INTERVAL);
+        let mut last = None;
+        loop {
+            select! {
+                _ = interval.tick() => (),
+                _ = &mut shutdown => break,
+            }
+
+            let progress = match chunk_db.read().unwrap().get_file(&uuid) {
+                Some((_, info)) => ChunkedProgress {
+                    received_bytes: info.received_bytes(chunk_size),
+                    total: info.size,
+                },
+                None => break,
+            };
+
+            if last.as_ref() != Some(&progress) {
+                yield Event::json(&progress).event("progress");
+                last = Some(progress);
+            }
+        }
+
+        yield Event::empty().event("end");
+    })
+}
+
 /// Finalize a chunked upload
-#[get("/upload/chunked/<uuid>?finish")]
+#[allow(clippy::too_many_arguments)]
+#[get("/upload/chunked/<uuid>?finish&<expected_hash>")]
 pub async fn chunked_upload_finish(
     main_db: &State<Arc<RwLock<Mochibase>>>,
     chunk_db: &State<Arc<RwLock<Chunkbase>>>,
-    settings: &State<Settings>,
+    storage: &State<Arc<dyn StorageBackend>>,
+    metrics: &State<Arc<Metrics>>,
+    settings: &Settings,
+    gate: &State<Arc<UploadGate>>,
+    _user: ChunkAuth,
     uuid: &str,
-) -> Result<Json<MochiFile>, io::Error> {
-    let now = Utc::now();
-    let uuid = Uuid::parse_str(uuid).map_err(io::Error::other)?;
-    let chunked_info = match chunk_db.read().unwrap().get_file(&uuid) {
+    expected_hash: Option<&str>,
+) -> Result<Json<UploadedFile>, ApiError> {
+    if gate.is_read_only() {
+        return Err(ApiError::Unavailable(READ_ONLY_MESSAGE.into()));
+    }
+
+    let uuid = Uuid::parse_str(uuid).map_err(|_| ApiError::NotFound("Invalid UUID".into()))?;
+    let expected_hash = expected_hash
+        .map(blake3::Hash::from_hex)
+        .transpose()
+        .map_err(|_| ApiError::HashMismatch(format!("Expected hash is not a valid {} hash", settings.hash_algorithm)))?;
+    let mut chunked_info = match chunk_db.read().unwrap().get_file(&uuid) {
         Some(s) => s.clone(),
-        None => return Err(io::Error::other("Invalid UUID")),
+        None => return Err(ApiError::NotFound("Invalid UUID".into())),
     };
 
     if !chunked_info.1.path.try_exists().is_ok_and(|e| e) {
-        return Err(io::Error::other("File does not exist"));
+        return Err(ApiError::Internal("File does not exist".into()));
     }
 
-    // Get file hash
-    let mut hasher = blake3::Hasher::new();
-    hasher.update_mmap_rayon(&chunked_info.1.path).unwrap();
-    let hash = hasher.finalize();
-    let new_filename = settings.file_dir.join(hash.to_string());
+    // The file may have ended up smaller than the size it was started with,
+    // in which case only the chunks up to where it ended are needed
+    if settings.upload_size_is_maximum {
+        chunked_info.1.size = chunked_info.1.final_size(settings.chunk_size);
+    }
 
-    // If the hash does not exist in the database,
-    // move the file to the backend, else, delete it
-    // This also removes it from the chunk database
-    if main_db.read().unwrap().get_hash(&hash).is_none() {
-        chunk_db.write().unwrap().move_and_remove_file(&uuid, &new_filename)?;
-    } else {
+    // Finishing early would store a corrupt file, so keep the upload around
+    // to let the client send the missing chunks
+    if !chunked_info.1.is_complete(settings.chunk_size) {
+        return Err(ApiError::Incomplete("Not all chunks have been uploaded".into()));
+    }
+
+    // Get file hash, only reading the file again if the chunks didn't all
+    // arrive in order
+    let hash = match chunked_info.1.running_hash(settings.chunk_size) {
+        Some(h) => h,
+        None => hash_file(&chunked_info.1.path, settings.hash_algorithm).await?,
+    };
+    if expected_hash.is_some_and(|h| h != hash) {
         chunk_db.write().unwrap().remove_file(&uuid)?;
+        return Err(ApiError::HashMismatch("File hash does not match the expected hash".into()));
     }
 
-    let mmid = Mmid::new_random();
-    let file_type = file_format::FileFormat::from_file(&new_filename).unwrap();
+    // Take the upload out of the chunk database, so it can't time out while
+    // it's being stored
+    let mut info = chunk_db
+        .write()
+        .unwrap()
+        .take_file(&uuid)
+        .ok_or(ApiError::NotFound("Invalid UUID".into()))?;
+    info.size = chunked_info.1.size;
 
-    let constructed_file = MochiFile::new(
-        mmid.clone(),
-        chunked_info.1.name,
-        file_type.media_type().to_string(),
-        hash,
-        now,
-        now + chunked_info.1.expire_duration,
+    Ok(Json(commit_upload(main_db, storage.as_ref(), metrics, settings, info, hash).await?))
+}
+
+/// A simple file upload using a standard multipart form, for clients which
+/// can't easily perform a chunked upload
+#[derive(FromForm)]
+pub struct FormUpload<'r> {
+    file: TempFile<'r>,
+
+    /// The duration in seconds, or as a time string like `6h` or `1d12h`
+    duration: Option<String>,
+    max_downloads: Option<u32>,
+    password: Option<String>,
+    visibility: Option<Visibility>,
+}
+
+/// Upload a file in a single request, like `curl -F file=@example.png`
+#[allow(clippy::too_many_arguments)]
+#[post("/upload", data = "<form>")]
+pub async fn form_upload(
+    main_db: &State<Arc<RwLock<Mochibase>>>,
+    storage: &State<Arc<dyn StorageBackend>>,
+    metrics: &State<Arc<Metrics>>,
+    settings: &Settings,
+    gate: &State<Arc<UploadGate>>,
+    limit: UploadRateLimit,
+    _user: AuthUser,
+    mut form: Form<FormUpload<'_>>,
+) -> Result<Json<UploadedFile>, ApiError> {
+    if let Some(reason) = gate.refusal() {
+        return Err(ApiError::Unavailable(reason.into()));
+    }
+
+    let expire_duration = match &form.duration {
+        Some(d) => d
+            .parse::<i64>()
+            .map(TimeDelta::seconds)
+            .or_else(|_| parse_time_string(d))
+            .map_err(|_| ApiError::Invalid("Duration invalid".into()))?,
+        None => settings.duration.default,
+    };
+
+    let size = form.file.len();
+    check_upload(settings, size, expire_duration)?;
+
+    let name = upload_name(
+        settings,
+        form.file
+            .raw_name()
+            .map(|n| n.dangerous_unsafe_unsanitized_raw().as_str())
+            .unwrap_or_default(),
     );
 
-    main_db
-        .write()
-        .unwrap()
-        .insert(&mmid, constructed_file.clone());
+    let password_hash = match &form.password {
+        Some(p) => Some(
+            hash_password(p)
+                .await
+                .ok_or(ApiError::Internal("Failed to hash password".into()))?,
+        ),
+        None => None,
+    };
+
+    let path = settings.temp_dir.join(Uuid::new_v4().to_string());
+    form.file.move_copy_to(&path).await?;
+    let hash = hash_file(&path, settings.hash_algorithm).await?;
+
+    let info = ChunkedInfo {
+        name,
+        size,
+        expire_duration,
+        max_downloads: form.max_downloads,
+        password_hash,
+        visibility: form.visibility.unwrap_or_default(),
+        path,
+        client_ip: limit.ip,
+        ..Default::default()
+    };
+
+    Ok(Json(commit_upload(main_db, storage.as_ref(), metrics, settings, info, hash).await?))
+}
+
+/// A request to upload a file from another server
+#[derive(Deserialize)]
+pub struct RemoteUpload {
+    url: String,
+
+    /// The duration in seconds
+    duration: Option<i64>,
+    max_downloads: Option<u32>,
+    password: Option<String>,
+    #[serde(default)]
+    visibility: Visibility,
+}
+
+/// Upload a file by having the server fetch it from a URL, so it doesn't have
+/// to be downloaded by the client first
+#[allow(clippy::too_many_arguments)]
+#[post("/upload/remote", data = "<body>")]
+pub async fn remote_upload(
+    main_db: &State<Arc<RwLock<Mochibase>>>,
+    storage: &State<Arc<dyn StorageBackend>>,
+    metrics: &State<Arc<Metrics>>,
+    settings: &Settings,
+    gate: &State<Arc<UploadGate>>,
+    limit: UploadRateLimit,
+    _user: AuthUser,
+    body: Json<RemoteUpload>,
+) -> Result<Json<UploadedFile>, ApiError> {
+    if !settings.remote_upload.enabled {
+        return Err(ApiError::Forbidden("Remote uploads are disabled".into()));
+    }
+    if let Some(reason) = gate.refusal() {
+        return Err(ApiError::Unavailable(reason.into()));
+    }
+
+    let expire_duration = body
+        .duration
+        .map(TimeDelta::seconds)
+        .unwrap_or(settings.duration.default);
+    check_duration(settings, expire_duration).map_err(ApiError::Invalid)?;
+
+    let url = reqwest::Url::parse(&body.url).map_err(|_| ApiError::Invalid("Invalid URL".into()))?;
+    if !remote::url_allowed(&settings.remote_upload, &url) {
+        return Err(ApiError::Forbidden("Fetching from this address is not allowed".into()));
+    }
+
+    let client = remote::client(&settings.remote_upload)
+        .map_err(|e| ApiError::Internal(remote::describe_error(&e)))?;
+    let mut response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| ApiError::Invalid(format!("Failed to fetch URL: {}", remote::describe_error(&e))))?;
+    if response.content_length().is_some_and(|l| l > settings.max_filesize) {
+        return Err(ApiError::TooLarge("File too large".into()));
+    }
+
+    // Redirects may have led to a URL with a better name
+    let name = upload_name(settings, &remote::file_name(response.url()));
+
+    // Write the file as it arrives, so it doesn't have to fit in memory
+    let path = settings.temp_dir.join(Uuid::new_v4().to_string());
+    let mut file = fs::File::create(&path).await?;
+    let hasher = BackgroundHasher::new(settings.hash_algorithm);
+    let fetched: Result<u64, ApiError> = async {
+        let mut size = 0;
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| ApiError::Invalid(format!("Failed to fetch URL: {}", remote::describe_error(&e))))?
+        {
+            size += chunk.len() as u64;
+            if size > settings.max_filesize {
+                return Err(ApiError::TooLarge("File too large".into()));
+            }
+
+            file.write_all(&chunk).await?;
+            hasher.update(chunk.to_vec()).await?;
+        }
+        file.flush().await?;
+
+        check_upload(settings, size, expire_duration)?;
+
+        Ok(size)
+    }
+    .await;
+    drop(file);
+
+    let size = match fetched {
+        Ok(s) => s,
+        Err(e) => {
+            let _ = fs::remove_file(&path).await;
+            return Err(e);
+        }
+    };
+    let hash = hasher.finalize().await?;
+
+    let password_hash = match &body.password {
+        Some(p) => Some(
+            hash_password(p)
+                .await
+                .ok_or(ApiError::Internal("Failed to hash password".into()))?,
+        ),
+        None => None,
+    };
+
+    let info = ChunkedInfo {
+        name,
+        size,
+        expire_duration,
+        max_downloads: body.max_downloads,
+        visibility: body.visibility,
+        password_hash,
+        path,
+        client_ip: limit.ip,
+        ..Default::default()
+    };
 
-    Ok(Json(constructed_file))
+    Ok(Json(commit_upload(main_db, storage.as_ref(), metrics, settings, info, hash).await?))
 }
 
-#[get("/upload/websocket?<name>&<size>&<duration>")]
+#[allow(clippy::too_many_arguments)]
+#[get("/upload/websocket?<name>&<size>&<duration>&<max_downloads>&<password>&<visibility>&<expected_hash>")]
 pub async fn websocket_upload(
     ws: rocket_ws::WebSocket,
     main_db: &State<Arc<RwLock<Mochibase>>>,
     chunk_db: &State<Arc<RwLock<Chunkbase>>>,
-    settings: &State<Settings>,
+    storage: &State<Arc<dyn StorageBackend>>,
+    metrics: &State<Arc<Metrics>>,
+    settings: &Settings,
+    gate: &State<Arc<UploadGate>>,
+    limit: UploadRateLimit,
+    _user: AuthUser,
     name: String,
     size: u64,
     duration: i64, // Duration in seconds
+    max_downloads: Option<u32>,
+    password: Option<&str>,
+    visibility: Option<Visibility>,
+    expected_hash: Option<&str>,
 ) -> Result<rocket_ws::Channel<'static>, Json<ChunkedResponse>> {
+    if let Some(reason) = gate.refusal() {
+        return Err(Json(ChunkedResponse::failure(reason)));
+    }
+
     let max_filesize = settings.max_filesize;
     let expire_duration = TimeDelta::seconds(duration);
-    if size > max_filesize {
-        return Err(Json(ChunkedResponse::failure("File too large")));
-    }
-    if settings.duration.restrict_to_allowed
-        && !settings
-            .duration
-            .allowed
-            .contains(&expire_duration)
-    {
-        return Err(Json(ChunkedResponse::failure("Duration not allowed")));
-    }
-    if expire_duration > settings.duration.maximum {
-        return Err(Json(ChunkedResponse::failure("Duration too large")));
-    }
+    check_upload(settings, size, expire_duration).map_err(|e| {
+        Json(ChunkedResponse::failure(e.client_message()).with_duration_hint(settings, expire_duration))
+    })?;
+    let expected_hash = expected_hash
+        .map(blake3::Hash::from_hex)
+        .transpose()
+        .map_err(|_| {
+            let message = format!("Expected hash is not a valid {} hash", settings.hash_algorithm);
+            Json(ChunkedResponse::failure(&message))
+        })?;
+
+    let password_hash = match password {
+        Some(p) => Some(
+            hash_password(p)
+                .await
+                .ok_or(Json(ChunkedResponse::failure("Failed to hash password")))?,
+        ),
+        None => None,
+    };
 
     let file_info = ChunkedInfo {
-        name,
+        name: upload_name(settings, &name),
         size,
         expire_duration,
+        max_downloads,
+        password_hash,
+        visibility: visibility.unwrap_or_default(),
+        client_ip: limit.ip,
         ..Default::default()
     };
 
-    let uuid = chunk_db.write().unwrap().new_file(
-        file_info,
-        &settings.temp_dir,
-        TimeDelta::seconds(30)
-    ).map_err(|e| Json(ChunkedResponse::failure(e.to_string().as_str())))?;
+    let uuid = {
+        let mut chunk_db = chunk_db.write().unwrap();
+        if chunk_db.is_full(settings.max_concurrent_chunks) {
+            return Err(Json(ChunkedResponse::failure("Too many uploads in progress, try again later")));
+        }
+
+        chunk_db.new_file(file_info, &settings.temp_dir, settings.chunk_timeout())
+            .map_err(|e| Json(ChunkedResponse::failure(ApiError::from(e).client_message())))?
+    };
     let info = chunk_db.read().unwrap().get_file(&uuid).unwrap().clone();
 
+    let mut file = match fs::File::create(&info.1.path).await {
+        Ok(f) => f,
+        Err(e) => {
+            let _ = chunk_db.write().unwrap().remove_file(&uuid);
+            return Err(Json(ChunkedResponse::failure(ApiError::from(e).client_message())));
+        }
+    };
+
+    // Messages are read whole before they can be written, so larger ones are
+    // refused rather than filling up memory
+    let max_frame_size = settings.websocket_max_frame_size as usize;
+    let ws = ws.config(rocket_ws::Config {
+        max_message_size: Some(max_frame_size),
+        max_frame_size: Some(max_frame_size),
+        ..Default::default()
+    });
+
     let chunk_db = Arc::clone(chunk_db);
     let main_db = Arc::clone(main_db);
-    let file_dir = settings.file_dir.clone();
-    let mut file = fs::File::create(&info.1.path).await.unwrap();
+    let storage = Arc::clone(storage);
+    let metrics = Arc::clone(metrics);
+    let settings = settings.clone();
 
     Ok(ws.channel(move |mut stream| Box::pin(async move {
-        let mut offset = 0;
-        let mut hasher = blake3::Hasher::new();
-        while let Some(message) = stream.next().await {
-            if let Ok(m) = message.as_ref() {
-                if m.is_empty() {
+        let result: Result<UploadedFile, ApiError> = async {
+            let mut offset = 0;
+            let hasher = BackgroundHasher::new(settings.hash_algorithm);
+            while let Some(message) = stream.next().await {
+                let message = message.map_err(|e| match e {
+                    rocket_ws::result::Error::Capacity(_) => ApiError::TooLarge(format!(
+                        "Messages may be at most {max_frame_size} bytes"
+                    )),
+                    e => ApiError::Internal(e.to_string()),
+                })?;
+                if message.is_empty() {
                     // We're finished here
                     break;
                 }
+
+                let message = message.into_data();
+                offset += message.len() as u64;
+                if (offset > info.1.size) | (offset > max_filesize) {
+                    return Err(ApiError::TooLarge("Received more data than the file size".into()));
+                }
+
+                file.write_all(&message).await?;
+                file.flush().await?;
+                hasher.update(message).await?;
+
+                stream
+                    .send(rocket_ws::Message::Text(offset.to_string()))
+                    .await
+                    .map_err(|e| ApiError::Internal(e.to_string()))?;
+
+                chunk_db.write().unwrap().extend_timeout(&uuid, settings.chunk_timeout());
             }
 
-            let message = message.unwrap().into_data();
-            offset += message.len() as u64;
-            if (offset > info.1.size) | (offset > max_filesize) {
-                break
+            if offset != info.1.size && !settings.upload_size_is_maximum {
+                return Err(ApiError::Incomplete("Upload ended before the whole file was sent".into()));
             }
 
-            hasher.update(&message);
+            let hash = hasher.finalize().await?;
+            if expected_hash.is_some_and(|h| h != hash) {
+                return Err(ApiError::HashMismatch("File hash does not match the expected hash".into()));
+            }
 
-            stream.send(rocket_ws::Message::Text(json::serde_json::ser::to_string(&offset).unwrap())).await.unwrap();
+            // Take the upload out of the chunk database, so it can't time out
+            // while it's being stored
+            let mut info = chunk_db
+                .write()
+                .unwrap()
+                .take_file(&uuid)
+                .ok_or(ApiError::NotFound("Upload timed out".into()))?;
+            info.size = offset;
 
-            file.write_all(&message).await.unwrap();
-            file.flush().await?;
+            commit_upload(&main_db, storage.as_ref(), &metrics, &settings, info, hash).await
+        }
+        .await;
 
-            chunk_db.write().unwrap().extend_timeout(&uuid, TimeDelta::seconds(30));
+        match result {
+            Ok(constructed_file) => {
+                let response = json::to_string(&constructed_file)
+                    .map_err(|e| rocket_ws::result::Error::Io(io::Error::other(e)))?;
+                stream.send(rocket_ws::Message::Text(response)).await?;
+            }
+            Err(e) => {
+                // Don't leave the partial upload around
+                let _ = chunk_db.write().unwrap().remove_file(&uuid);
+                stream.send(rocket_ws::Message::Text(e.to_json())).await?;
+            }
         }
 
-        let now = Utc::now();
-        let hash = hasher.finalize();
-        let new_filename = file_dir.join(hash.to_string());
+        stream.close(None).await
+    })))
+}
 
-        // If the hash does not exist in the database,
-        // move the file to the backend, else, delete it
-        // This also removes it from the chunk database
-        if main_db.read().unwrap().get_hash(&hash).is_none() {
-            chunk_db.write().unwrap().move_and_remove_file(&uuid, &new_filename)?;
-        } else {
-            chunk_db.write().unwrap().remove_file(&uuid)?;
-        }
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
 
-        let mmid = Mmid::new_random();
-        let file_type = file_format::FileFormat::from_file(&new_filename).unwrap();
+    use rocket::{Build, Rocket};
 
-        let constructed_file = MochiFile::new(
-            mmid.clone(),
-            info.1.name,
-            file_type.media_type().to_string(),
-            hash,
-            now,
-            now + info.1.expire_duration,
-        );
+    use super::*;
+
+    /// Settings which keep everything in a new, empty temporary directory
+    #[allow(clippy::field_reassign_with_default)]
+    fn test_settings() -> Settings {
+        let dir = std::env::temp_dir().join(format!("confetti-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("files")).unwrap();
 
-        main_db
+        // Not all of the settings are public, so they can't be made in one go
+        let mut settings = Settings::default();
+        settings.database_path = dir.join("database.mochi");
+        settings.temp_dir = dir.clone();
+        settings.file_dir = dir.join("files");
+        settings.min_free_space = 0;
+        settings.chunk_size = 4;
+        settings
+    }
+
+    /// A server with all of the state the upload endpoints need
+    async fn test_rocket(settings: &Settings) -> Rocket<Build> {
+        let database = Arc::new(RwLock::new(Mochibase::new(&settings.database_path).unwrap()));
+        let storage = storage::from_settings(settings);
+        let metrics = Arc::new(Metrics::new(&database, storage.as_ref()).await);
+
+        rocket::build()
+            .manage(database)
+            .manage(Arc::new(RwLock::new(Chunkbase::default())))
+            .manage(storage)
+            .manage(metrics)
+            .manage(Arc::new(UploadGate::new(false)))
+    }
+
+    #[rocket::async_test]
+    async fn finish_with_missing_chunk_is_incomplete() {
+        let settings = test_settings();
+        let rocket = test_rocket(&settings).await;
+        let chunk_db = State::<Arc<RwLock<Chunkbase>>>::get(&rocket).unwrap();
+
+        // Three chunks, with the middle one missing
+        let info = ChunkedInfo {
+            name: "party.txt".into(),
+            size: settings.chunk_size * 3,
+            recieved_chunks: HashSet::from([0, 2]),
+            ..Default::default()
+        };
+        let uuid = chunk_db
             .write()
             .unwrap()
-            .insert(&mmid, constructed_file.clone());
+            .new_file(info, &settings.temp_dir, TimeDelta::hours(1))
+            .unwrap();
 
-        stream.send(rocket_ws::Message::Text(json::serde_json::ser::to_string(&constructed_file).unwrap())).await?;
+        let result = chunked_upload_finish(
+            State::get(&rocket).unwrap(),
+            chunk_db,
+            State::get(&rocket).unwrap(),
+            State::get(&rocket).unwrap(),
+            &settings,
+            State::get(&rocket).unwrap(),
+            ChunkAuth,
+            &uuid.to_string(),
+            None,
+        )
+        .await;
+        assert!(matches!(result, Err(ApiError::Incomplete(_))));
 
-        Ok(())
-    })))
+        // The upload is kept, so the missing chunk can still be sent
+        assert!(chunk_db.read().unwrap().get_file(&uuid).is_some());
+
+        std::fs::remove_dir_all(&settings.temp_dir).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn start_rejects_uploads_past_the_limit() {
+        let mut settings = test_settings();
+        settings.max_concurrent_chunks = 2;
+        let chunk_db = RwLock::new(Chunkbase::default());
+
+        let info = ChunkedInfo {
+            name: "party.txt".into(),
+            size: 10,
+            expire_duration: settings.duration.default,
+            ..Default::default()
+        };
+        for _ in 0..settings.max_concurrent_chunks {
+            let response = start_upload(&chunk_db, &settings, None, None, info.clone()).await.unwrap();
+            assert!(response.status, "{}", response.message);
+        }
+
+        let response = start_upload(&chunk_db, &settings, None, None, info).await.unwrap();
+        assert!(!response.status);
+        assert!(response.uuid.is_none());
+
+        std::fs::remove_dir_all(&settings.temp_dir).unwrap();
+    }
+
+    /// Upload the same file twice, removing the stored copy in between, and
+    /// return whether the second upload put it back
+    async fn stored_after_duplicate(overwrite: bool) -> bool {
+        let mut settings = test_settings();
+        settings.overwrite = overwrite;
+        let database = Arc::new(RwLock::new(Mochibase::new(&settings.database_path).unwrap()));
+        let storage = storage::from_settings(&settings);
+        let metrics = Metrics::new(&database, storage.as_ref()).await;
+
+        let contents = b"confetti everywhere";
+        let hash = blake3::hash(contents);
+        let mut uploads = Vec::new();
+        for i in 0..2 {
+            let path = settings.temp_dir.join(format!("upload-{i}"));
+            std::fs::write(&path, contents).unwrap();
+            let info = ChunkedInfo {
+                name: "party.txt".into(),
+                size: contents.len() as u64,
+                expire_duration: settings.duration.default,
+                path,
+                ..Default::default()
+            };
+
+            let uploaded = commit_upload(&database, storage.as_ref(), &metrics, &settings, info, hash)
+                .await
+                .unwrap();
+            uploads.push(uploaded);
+
+            if i == 0 {
+                storage.delete(&hash).await.unwrap();
+            }
+        }
+        assert!(!uploads[0].deduplicated);
+        assert!(uploads[1].deduplicated);
+
+        let stored = storage.exists(&hash).await.unwrap();
+        std::fs::remove_dir_all(&settings.temp_dir).unwrap();
+        stored
+    }
+
+    #[rocket::async_test]
+    async fn duplicate_overwrites_stored_file() {
+        assert!(stored_after_duplicate(true).await);
+    }
+
+    #[rocket::async_test]
+    async fn duplicate_keeps_stored_file() {
+        assert!(!stored_after_duplicate(false).await);
+    }
+
+    #[test]
+    fn home_links_under_root_path() {
+        let mut settings = Settings::default();
+        settings.server.root_path = "/filehost/".into();
+
+        let page = home(&settings).into_string();
+        for asset in ["request.js", "main.css", "favicon.svg"] {
+            assert!(page.contains(&format!("\"/filehost/resources/{asset}\"")), "{asset} isn't under the root path");
+            assert!(!page.contains(&format!("\"/resources/{asset}\"")));
+        }
+        assert!(page.contains("data-root-path=\"/filehost\""));
+    }
+
+    #[test]
+    fn low_space_is_a_server_error() {
+        let mut settings = test_settings();
+        settings.min_free_space = u64::MAX;
+
+        let result = check_upload(&settings, 10, settings.duration.default);
+        let error = result.unwrap_err();
+        assert!(matches!(error, ApiError::InsufficientStorage(_)));
+        assert!(error.status().code >= 500);
+
+        std::fs::remove_dir_all(&settings.temp_dir).unwrap();
+    }
 }
@@ -1,40 +1,86 @@
 use std::{
+    collections::HashSet,
     fs,
+    net::Ipv4Addr,
     path::PathBuf,
     sync::{Arc, RwLock},
+    time::Duration,
 };
 
 use chrono::TimeDelta;
 use confetti_box::{
-    database::{clean_database, Chunkbase, Mochibase},
-    endpoints, pages, resources,
-    settings::Settings,
+    admin, auth,
+    cors::Cors,
+    database::{clean_database, fill_missing_sizes, reconcile_database, Chunkbase, Mochibase},
+    endpoints, metrics::{self, Metrics}, pages, ratelimit::{self, RateLimiter}, resources,
+    settings::{ListenAddress, Settings, SharedSettings, StorageSettings},
+    stats::{self, StatsCache},
+    storage::{self, StorageBackend},
+    thumbnail, UploadGate,
 };
-use log::info;
+#[cfg(unix)]
+use confetti_box::unix_socket::UnixSocket;
+use log::{error, info};
 use rocket::{
+    catchers,
+    config::TlsConfig,
     data::ToByteUnit as _,
     routes,
-    tokio::{self, select, sync::broadcast::Receiver, time},
+    tokio::{self, select, signal, sync::broadcast::Receiver, time},
 };
 
 #[rocket::main]
 async fn main() {
     // Get or create config file
     let config = Settings::open(&"./settings.toml").expect("Could not open settings file");
+    if let Err(e) = config.validate() {
+        panic!("{e}");
+    }
 
     if !config.temp_dir.try_exists().is_ok_and(|e| e) {
         fs::create_dir_all(config.temp_dir.clone()).expect("Failed to create temp directory");
     }
 
-    if !config.file_dir.try_exists().is_ok_and(|e| e) {
+    if matches!(config.storage, StorageSettings::Local) && !config.file_dir.try_exists().is_ok_and(|e| e) {
         fs::create_dir_all(config.file_dir.clone()).expect("Failed to create file directory");
     }
 
+    let listen_address = config
+        .server
+        .listen_address()
+        .unwrap_or_else(|e| panic!("Invalid server address: {e}"));
+
+    // Rocket only listens locally when serving a Unix socket, on any free port
+    #[cfg(unix)]
+    let unix_socket = match &listen_address {
+        ListenAddress::Unix(path) => Some(
+            UnixSocket::bind(path)
+                .unwrap_or_else(|e| panic!("Failed to listen on Unix socket {}: {e}", path.display())),
+        ),
+        ListenAddress::Ip(_) => None,
+    };
+    let (address, port) = match listen_address {
+        ListenAddress::Ip(ip) => (ip, config.server.port),
+        ListenAddress::Unix(_) => (Ipv4Addr::LOCALHOST.into(), 0),
+    };
+
     // Set rocket configuration settings
     let rocket_config = rocket::Config {
-        address: config.server.address.parse().expect("IP address invalid"),
-        port: config.server.port,
+        // Shutdown signals are handled by `drain_uploads` instead, so uploads
+        // can finish before Rocket stops accepting connections
+        shutdown: rocket::config::Shutdown {
+            ctrlc: false,
+            #[cfg(unix)]
+            signals: HashSet::new(),
+            ..Default::default()
+        },
+        address,
+        port,
         temp_dir: config.temp_dir.clone().into(),
+        tls: config
+            .tls
+            .as_ref()
+            .map(|tls| TlsConfig::from_paths(&tls.cert_path, &tls.key_path)),
         limits: rocket::data::Limits::default()
             .limit("data-form", config.max_filesize.bytes())
             .limit("file", config.max_filesize.bytes()),
@@ -45,15 +91,25 @@ async fn main() {
         Mochibase::open_or_new(&config.database_path).expect("Failed to open or create database"),
     ));
     let chunkbase = Arc::new(RwLock::new(Chunkbase::default()));
+    let upload_gate = Arc::new(UploadGate::new(config.read_only));
+    let storage = storage::from_settings(&config);
+    fill_missing_sizes(&database, storage.as_ref()).await;
+    let metrics = Arc::new(Metrics::new(&database, storage.as_ref()).await);
     let local_db = database.clone();
     let local_chunk = chunkbase.clone();
+    let chunkbase_handle = chunkbase.clone();
+    let shutdown_grace = Duration::from_secs(config.shutdown_grace_seconds);
 
     let (shutdown, rx) = tokio::sync::broadcast::channel(1);
-    // Clean the database every 2 minutes
+    // Clean the database periodically
     tokio::spawn({
         let cleaner_db = database.clone();
-        let file_path = config.file_dir.clone();
-        async move { clean_loop(cleaner_db, file_path, rx).await }
+        let storage = storage.clone();
+        let metrics = metrics.clone();
+        let thumb_dir = thumbnail::thumbnail_dir(&config);
+        let interval = TimeDelta::seconds(config.cleanup_interval_seconds.max(1) as i64);
+        let reconcile = config.reconcile_on_cleanup;
+        async move { clean_loop(cleaner_db, storage, metrics, thumb_dir, interval, reconcile, rx).await }
     });
     tokio::spawn({
         let chunk_db = local_chunk.clone();
@@ -61,13 +117,32 @@ async fn main() {
         async move { clean_chunks(chunk_db, rx2).await }
     });
 
-    let rocket = rocket::build()
+    let shared_settings = Arc::new(SharedSettings::new(config.clone()));
+
+    let rocket = rocket::build();
+    #[cfg(unix)]
+    let rocket = match unix_socket {
+        Some(socket) => rocket.attach(socket.fairing()),
+        None => rocket,
+    };
+
+    let rocket = rocket
         .mount(
             config.server.root_path.clone() + "/",
             routes![
                 confetti_box::home,
                 pages::api_info,
                 pages::about,
+                pages::preview,
+                admin::list,
+                admin::search,
+                admin::hashes,
+                admin::reconcile,
+                admin::read_only,
+                admin::reload,
+                admin::expire,
+                admin::extend,
+                admin::events,
                 resources::favicon,
                 resources::form_handler_js,
                 resources::stylesheet,
@@ -77,23 +152,62 @@ async fn main() {
         .mount(
             config.server.root_path.clone() + "/",
             routes![
+                confetti_box::form_upload,
+                confetti_box::remote_upload,
                 confetti_box::chunked_upload_start,
+                confetti_box::chunked_upload_batch,
                 confetti_box::chunked_upload_continue,
                 confetti_box::chunked_upload_finish,
+                confetti_box::chunked_upload_status,
+                confetti_box::chunked_upload_progress,
                 confetti_box::websocket_upload,
                 endpoints::server_info,
+                endpoints::version,
+                endpoints::robots,
+                endpoints::health,
                 endpoints::file_info,
                 endpoints::lookup_mmid,
                 endpoints::lookup_mmid_noredir,
                 endpoints::lookup_mmid_name,
+                endpoints::head_mmid_noredir,
+                endpoints::head_mmid_name,
+                endpoints::delete_mmid,
+                endpoints::rename_mmid,
+                endpoints::set_expiry,
+                endpoints::copy_mmid,
+                metrics::metrics,
+                stats::stats,
+                thumbnail::thumbnail,
             ],
         )
+        .register(
+            config.server.root_path.clone() + "/",
+            catchers![auth::unauthorized, ratelimit::too_many_requests],
+        )
+        .attach(Cors::new(config.cors.clone(), &config.server.root_path))
         .manage(database)
         .manage(chunkbase)
-        .manage(config)
+        .manage(storage)
+        .manage(metrics)
+        .manage(StatsCache::default())
+        .manage(RateLimiter::default())
+        .manage(upload_gate.clone())
+        .manage(shared_settings.clone())
         .configure(rocket_config)
-        .launch()
-        .await;
+        .ignite()
+        .await
+        .expect("Failed to start server");
+
+    tokio::spawn(drain_uploads(
+        rocket.shutdown(),
+        upload_gate.clone(),
+        chunkbase_handle,
+        shutdown_grace,
+    ));
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(shared_settings, upload_gate));
+
+    let rocket = rocket.launch().await;
 
     // Ensure the server gracefully shuts down
     rocket.expect("Server failed to shutdown gracefully");
@@ -119,16 +233,87 @@ async fn main() {
     info!("Deleting chunk data completed successfully.");
 }
 
+/// Wait for a signal to shut down the server
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM");
+        select! {
+            _ = signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = signal::ctrl_c().await;
+}
+
+/// Reload the settings file whenever `SIGHUP` is received
+#[cfg(unix)]
+async fn reload_on_hangup(settings: Arc<SharedSettings>, gate: Arc<UploadGate>) {
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())
+        .expect("Failed to listen for SIGHUP");
+    while hangup.recv().await.is_some() {
+        if let Err(e) = settings.reload(&gate) {
+            error!("Failed to reload settings: {e}");
+        }
+    }
+}
+
+/// Shut down the server when a signal is received, after first waiting up to
+/// `grace` for chunked uploads which are in progress to finish. New uploads
+/// are refused in the meantime. A second signal shuts down immediately.
+async fn drain_uploads(
+    shutdown: rocket::Shutdown,
+    gate: Arc<UploadGate>,
+    chunk_db: Arc<RwLock<Chunkbase>>,
+    grace: Duration,
+) {
+    shutdown_signal().await;
+    gate.close();
+    info!("Shutting down, waiting up to {}s for uploads to finish...", grace.as_secs());
+
+    let deadline = time::Instant::now() + grace;
+    let mut interval = time::interval(Duration::from_secs(1));
+    loop {
+        let remaining = chunk_db.read().unwrap().len();
+        if remaining == 0 {
+            break;
+        }
+        if time::Instant::now() >= deadline {
+            info!("{remaining} uploads did not finish in time.");
+            break;
+        }
+
+        select! {
+            _ = interval.tick() => (),
+            _ = shutdown_signal() => break,
+        }
+    }
+
+    shutdown.notify();
+}
+
 /// A loop to clean the database periodically.
 pub async fn clean_loop(
     main_db: Arc<RwLock<Mochibase>>,
-    file_path: PathBuf,
+    storage: Arc<dyn StorageBackend>,
+    metrics: Arc<Metrics>,
+    thumb_dir: PathBuf,
+    interval: TimeDelta,
+    reconcile: bool,
     mut shutdown_signal: Receiver<()>,
 ) {
-    let mut interval = time::interval(TimeDelta::minutes(2).to_std().unwrap());
+    let mut interval = time::interval(interval.to_std().unwrap());
     loop {
         select! {
-            _ = interval.tick() => clean_database(&main_db, &file_path),
+            _ = interval.tick() => {
+                clean_database(&main_db, storage.as_ref(), &metrics, &thumb_dir).await;
+                if reconcile {
+                    reconcile_database(&main_db, storage.as_ref(), &metrics, &thumb_dir).await;
+                }
+            },
             _ = shutdown_signal.recv() => break,
         };
     }
@@ -138,7 +323,12 @@ pub async fn clean_chunks(chunk_db: Arc<RwLock<Chunkbase>>, mut shutdown_signal:
     let mut interval = time::interval(TimeDelta::seconds(30).to_std().unwrap());
     loop {
         select! {
-            _ = interval.tick() => {let _ = chunk_db.write().unwrap().delete_timed_out();},
+            _ = interval.tick() => {
+                let removed = chunk_db.write().unwrap().delete_timed_out();
+                if removed > 0 {
+                    info!("Removed {removed} timed out chunked uploads.");
+                }
+            },
             _ = shutdown_signal.recv() => break,
         };
     }
@@ -0,0 +1,141 @@
+use std::{
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use rocket::{get, http::ContentType, State};
+
+use crate::{
+    database::{Chunkbase, Mochibase},
+    settings::Settings,
+    storage::StorageBackend,
+};
+
+/// Counters describing the activity of the server, exposed in the Prometheus
+/// text format at `/metrics`
+#[derive(Default, Debug)]
+pub struct Metrics {
+    uploads: AtomicU64,
+    downloads: AtomicU64,
+    bytes_stored: AtomicU64,
+    cleanup_runs: AtomicU64,
+}
+
+impl Metrics {
+    /// Create a new set of metrics, counting the size of the files which are
+    /// already in storage
+    pub async fn new(db: &Arc<RwLock<Mochibase>>, storage: &dyn StorageBackend) -> Self {
+        let hashes: Vec<_> = db.read().unwrap().hashes().copied().collect();
+
+        let mut bytes_stored = 0;
+        for hash in &hashes {
+            bytes_stored += storage.size(hash).await.unwrap_or_default();
+        }
+
+        Self {
+            bytes_stored: AtomicU64::new(bytes_stored),
+            ..Default::default()
+        }
+    }
+
+    /// Record a completed upload. `new_bytes` is the number of bytes added to
+    /// storage, which is zero if the file was already stored.
+    pub fn record_upload(&self, new_bytes: u64) {
+        self.uploads.fetch_add(1, Ordering::Relaxed);
+        self.bytes_stored.fetch_add(new_bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_download(&self) {
+        self.downloads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a run of the database cleaner, which removed `removed_bytes`
+    /// from storage
+    pub fn record_cleanup(&self, removed_bytes: u64) {
+        self.cleanup_runs.fetch_add(1, Ordering::Relaxed);
+        self.remove_stored(removed_bytes);
+    }
+
+    /// Record files being removed from storage outside of the cleaner
+    pub fn remove_stored(&self, bytes: u64) {
+        let _ = self
+            .bytes_stored
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |b| {
+                Some(b.saturating_sub(bytes))
+            });
+    }
+
+    /// Render the metrics in the Prometheus text exposition format
+    pub fn render(&self, database_entries: usize, active_uploads: usize) -> String {
+        let mut out = String::new();
+
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            let _ = writeln!(out, "{name} {value}");
+        };
+
+        metric(
+            "confetti_uploads_total",
+            "counter",
+            "Number of completed uploads",
+            self.uploads.load(Ordering::Relaxed),
+        );
+        metric(
+            "confetti_downloads_total",
+            "counter",
+            "Number of file downloads",
+            self.downloads.load(Ordering::Relaxed),
+        );
+        metric(
+            "confetti_bytes_stored",
+            "gauge",
+            "Total size of all stored files in bytes",
+            self.bytes_stored.load(Ordering::Relaxed),
+        );
+        metric(
+            "confetti_active_chunked_uploads",
+            "gauge",
+            "Number of uploads in progress",
+            active_uploads as u64,
+        );
+        metric(
+            "confetti_database_entries",
+            "gauge",
+            "Number of files in the database",
+            database_entries as u64,
+        );
+        metric(
+            "confetti_cleanup_runs_total",
+            "counter",
+            "Number of times the database has been cleaned",
+            self.cleanup_runs.load(Ordering::Relaxed),
+        );
+
+        out
+    }
+}
+
+/// Expose server metrics for Prometheus to scrape
+#[get("/metrics")]
+pub fn metrics(
+    settings: &Settings,
+    metrics: &State<Arc<Metrics>>,
+    db: &State<Arc<RwLock<Mochibase>>>,
+    chunk_db: &State<Arc<RwLock<Chunkbase>>>,
+) -> Option<(ContentType, String)> {
+    if !settings.metrics_enabled {
+        return None;
+    }
+
+    let database_entries = db.read().unwrap().entries().len();
+    let active_uploads = chunk_db.read().unwrap().len();
+
+    Some((
+        ContentType::new("text", "plain").with_params(("version", "0.0.4")),
+        metrics.render(database_entries, active_uploads),
+    ))
+}
@@ -1,42 +1,323 @@
-use maud::{html, Markup, DOCTYPE};
-use rocket::{get, State};
+use std::{
+    io::Cursor,
+    sync::{Arc, RwLock},
+};
 
-use crate::settings::Settings;
+use blake3::Hash;
+use chrono::Utc;
+use image::ImageReader;
+use maud::{html, Markup, PreEscaped, DOCTYPE};
+use rocket::{
+    get,
+    http::Status,
+    tokio::{io::AsyncReadExt, task},
+    uri, State,
+};
 
-pub fn head(page_title: &str) -> Markup {
+use crate::{
+    auth::DownloadAuth,
+    database::{MochiFile, Mochibase, Visibility},
+    endpoints::{self, check_visible, live_entry, unlocked, with_token, Gated},
+    highlight::{detect_language, highlight, HighlightTheme},
+    settings::Settings,
+    storage::StorageBackend,
+    strings::{to_pretty_size, to_pretty_time, SizeUnits},
+};
+
+/// The most text which will be shown on a preview page, in bytes
+const PREVIEW_TEXT_LIMIT: u64 = 512 * 1024;
+
+/// How much of an image to read looking for its dimensions, in bytes
+const IMAGE_HEADER_LIMIT: u64 = 64 * 1024;
+
+pub fn head(settings: &Settings, page_title: &str) -> Markup {
+    let server = &settings.server;
     html! {
         (DOCTYPE)
         meta charset="UTF-8";
         meta name="viewport" content="width=device-width, initial-scale=1";
         title { (page_title) }
-        link rel="icon" type="image/svg+xml" href="/resources/favicon.svg";
-        link rel="stylesheet" href="/resources/main.css";
-        link rel="preload" href="/resources/fonts/Roboto.woff2" as="font" type="font/woff2" crossorigin;
-        link rel="preload" href="/resources/fonts/FiraCode.woff2" as="font" type="font/woff2" crossorigin;
+        link rel="icon" type="image/svg+xml" href=(server.path("/resources/favicon.svg"));
+        link rel="stylesheet" href=(server.path("/resources/main.css"));
+        link rel="preload" href=(server.path("/resources/fonts/Roboto.woff2")) as="font" type="font/woff2" crossorigin;
+        link rel="preload" href=(server.path("/resources/fonts/FiraCode.woff2")) as="font" type="font/woff2" crossorigin;
     }
 }
 
-pub fn footer() -> Markup {
+pub fn footer(settings: &Settings) -> Markup {
+    let server = &settings.server;
     html! {
         footer {
             div {
-                p {a href="/" {"Home"}}
-                p {a href="/about" {"About"}}
-                p {a href="/api" {"API"}}
+                p {a href=(server.path("/")) {"Home"}}
+                p {a href=(server.path("/about")) {"About"}}
+                p {a href=(server.path("/api")) {"API"}}
                 p {a href="https://github.com/Dangoware/confetti-box" {"Source"}}
                 p {a href="https://github.com/Dangoware/" {"Dangoware"}}
             }
+            @if let Some(custom) = &server.custom_footer_html {
+                (PreEscaped(custom))
+            }
             p.version { "Running Confetti-Box v" (env!("CARGO_PKG_VERSION")) }
         }
     }
 }
 
+/// A page asking for the password to a protected file. `action` is the path
+/// to send the password to, under the `root_path`, and `hidden` contains
+/// extra query parameters to send along with it.
+pub fn password_gate(
+    settings: &Settings,
+    user: &DownloadAuth,
+    action: &str,
+    hidden: &[(&str, &str)],
+    incorrect: bool,
+) -> Markup {
+    html! {
+        (head(settings, &settings.server.page_title("Password Required")))
+
+        center {
+            h1 { "Password Required" }
+            hr;
+
+            p { "This file is protected by a password." }
+            @if incorrect {
+                p { "Incorrect password, please try again." }
+            }
+            form method="get" action=(settings.server.path(action)) {
+                @for (name, value) in hidden {
+                    input type="hidden" name=(name) value=(value);
+                }
+                @if let Some(token) = &user.token {
+                    input type="hidden" name="token" value=(token);
+                }
+                input type="password" name="password" placeholder="Password" autofocus;
+                " "
+                button.button type="submit" { "Unlock" }
+            }
+
+            hr;
+            (footer(settings))
+        }
+    }
+}
+
+/// A page showing a file embedded in the browser, along with some
+/// information about it and a button to download it
+#[get("/p/<mmid>?<password>&<theme>")]
+pub async fn preview(
+    db: &State<Arc<RwLock<Mochibase>>>,
+    storage: &State<Arc<dyn StorageBackend>>,
+    settings: &Settings,
+    user: DownloadAuth,
+    mmid: &str,
+    password: Option<&str>,
+    theme: Option<HighlightTheme>,
+) -> Result<Gated<Markup>, Status> {
+    let entry = live_entry(db, mmid)?;
+    let mmid = entry.mmid();
+    check_visible(&entry, &user, settings)?;
+
+    if !unlocked(&entry, password).await {
+        return Ok(Gated::Locked(password_gate(
+            settings,
+            &user,
+            &format!("/p/{mmid}"),
+            &[],
+            password.is_some(),
+        )));
+    }
+
+    let size = storage.size(entry.hash()).await.map_err(|_| Status::NotFound)?;
+    let remaining = (entry.expiry() - Utc::now()).num_seconds().max(0) as u32;
+    let mime = entry.mime_type();
+
+    let server = &settings.server;
+    let link = |path: String| with_token(server.path(&path), &user);
+    let view_url = link(uri!(endpoints::lookup_mmid_noredir(mmid.to_string(), false, password)).to_string());
+    let download_url = link(uri!(endpoints::lookup_mmid_noredir(mmid.to_string(), true, password)).to_string());
+
+    // Only read the start of text files, so huge logs don't make huge pages
+    let language = detect_language(entry.name());
+    let text = if mime.starts_with("text/") || language.is_some() {
+        let mut data = Vec::new();
+        storage
+            .get(entry.hash(), None)
+            .await
+            .map_err(|_| Status::NotFound)?
+            .take(PREVIEW_TEXT_LIMIT)
+            .read_to_end(&mut data)
+            .await
+            .map_err(|_| Status::NotFound)?;
+        Some(String::from_utf8_lossy(&data).into_owned())
+    } else {
+        None
+    };
+
+    // Highlighting is slow for big files, so only do it for small ones
+    let theme = theme.unwrap_or_default();
+    let highlighted = match (&text, language) {
+        (Some(text), Some(language)) if size <= settings.highlight_max_size => {
+            let text = text.clone();
+            task::spawn_blocking(move || highlight(&text, language, theme))
+                .await
+                .ok()
+                .flatten()
+        }
+        _ => None,
+    };
+    let theme_url = |theme| link(uri!(preview(mmid.to_string(), password, Some(theme))).to_string());
+
+    let opengraph = opengraph(&entry, storage.inner().as_ref(), settings, size).await;
+
+    Ok(Gated::Open(html! {
+        (head(settings, &settings.server.page_title(entry.name())))
+        (opengraph)
+
+        center {
+            h1 { (entry.name()) }
+            p {
+                (to_pretty_size(size, SizeUnits::Decimal)) " · expires in "
+                (PreEscaped(to_pretty_time(remaining).replace("<br>", " ")))
+            }
+            hr;
+
+            @if mime.starts_with("image/") {
+                img src=(view_url) alt=(entry.name()) style="max-width: 100%;";
+            } @else if mime.starts_with("video/") {
+                video src=(view_url) controls style="max-width: 100%;" {}
+            } @else if mime.starts_with("audio/") {
+                audio src=(view_url) controls {}
+            } @else if let Some(text) = text {
+                @if let Some(highlighted) = highlighted {
+                    p {
+                        a href=(theme_url(HighlightTheme::Light)) { "Light" }
+                        " · "
+                        a href=(theme_url(HighlightTheme::Dark)) { "Dark" }
+                    }
+                    div style="text-align: left;" { (PreEscaped(highlighted)) }
+                } @else {
+                    pre style="text-align: left;" { (text) }
+                }
+                @if size > PREVIEW_TEXT_LIMIT {
+                    p { "Only the start of this file is shown." }
+                }
+            } @else {
+                p { "This file can't be previewed." }
+            }
+
+            p {
+                a href=(download_url) {
+                    button.button { "Download" }
+                }
+            }
+
+            hr;
+            (footer(settings))
+        }
+    }))
+}
+
+/// Tags describing a file for link previews in chat apps and social media
+pub async fn opengraph(
+    entry: &MochiFile,
+    storage: &dyn StorageBackend,
+    settings: &Settings,
+    size: u64,
+) -> Markup {
+    let mmid = entry.mmid();
+    let mime = entry.mime_type();
+    let remaining = (entry.expiry() - Utc::now()).num_seconds().max(0) as u32;
+
+    // Link previews show the file itself, but they can't unlock protected
+    // files, and shouldn't use up a limited number of downloads
+    let base_url = settings.base_url();
+    let base_url = base_url.trim_end_matches('/');
+    let media_url = format!(
+        "{base_url}{}",
+        uri!(endpoints::lookup_mmid_name(mmid.to_string(), entry.name(), None::<bool>, None::<&str>))
+    );
+    let embed = entry.password_hash().is_none()
+        && entry.max_downloads().is_none()
+        && entry.visibility() != Visibility::Private;
+    let dimensions = if embed && mime.starts_with("image/") {
+        image_dimensions(storage, entry.hash(), size).await
+    } else {
+        None
+    };
+
+    html! {
+        meta property="og:site_name" content=(settings.server.site_title());
+        meta property="og:title" content=(entry.name());
+        meta property="og:description" content={
+            (to_pretty_size(size, SizeUnits::Decimal)) ", expires in "
+            (to_pretty_time(remaining).replace("<br>", " "))
+        };
+        meta property="og:url" content=(format!("{base_url}/p/{mmid}"));
+        @if embed && mime.starts_with("image/") {
+            meta property="og:type" content="website";
+            meta property="og:image" content=(media_url);
+            meta property="og:image:type" content=(mime);
+            @if let Some((width, height)) = dimensions {
+                meta property="og:image:width" content=(width);
+                meta property="og:image:height" content=(height);
+            }
+            meta name="twitter:card" content="summary_large_image";
+            meta name="twitter:image" content=(media_url);
+        } @else if embed && mime.starts_with("video/") {
+            meta property="og:type" content="video.other";
+            meta property="og:video" content=(media_url);
+            meta property="og:video:type" content=(mime);
+            meta name="twitter:card" content="player";
+        } @else {
+            meta property="og:type" content="website";
+            meta name="twitter:card" content="summary";
+        }
+    }
+}
+
+/// A page for link preview bots, which only describes a file instead of
+/// showing it
+pub async fn link_preview(entry: &MochiFile, storage: &dyn StorageBackend, settings: &Settings) -> Option<Markup> {
+    let size = storage.size(entry.hash()).await.ok()?;
+    let file_url = settings
+        .server
+        .path(&uri!(endpoints::lookup_mmid_name(entry.mmid().to_string(), entry.name(), None::<bool>, None::<&str>)).to_string());
+
+    Some(html! {
+        (head(settings, &settings.server.page_title(entry.name())))
+        (opengraph(entry, storage, settings, size).await)
+
+        center {
+            h1 { (entry.name()) }
+            p { a href=(file_url) { "Open file" } }
+        }
+    })
+}
+
+/// Read the width and height of a stored image from the start of the file
+async fn image_dimensions(storage: &dyn StorageBackend, hash: &Hash, size: u64) -> Option<(u32, u32)> {
+    let mut data = Vec::new();
+    storage
+        .get(hash, Some((0, size.min(IMAGE_HEADER_LIMIT).checked_sub(1)?)))
+        .await
+        .ok()?
+        .read_to_end(&mut data)
+        .await
+        .ok()?;
+
+    ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
 #[get("/api")]
-pub fn api_info(settings: &State<Settings>) -> Markup {
-    let domain = &settings.server.domain;
-    let root = &settings.server.root_path;
+pub fn api_info(settings: &Settings) -> Markup {
+    let base_url = settings.base_url();
     html! {
-        (head("Confetti-Box | API"))
+        (head(settings, &settings.server.page_title("API")))
 
         center {
             h1 { "API Information" }
@@ -46,23 +327,80 @@ pub fn api_info(settings: &State<Settings>) -> Markup {
                 p {
                     "Confetti-Box is designed to be simple to access using its
                     API. All endpoints are accessed following "
-                    code{"https://"(domain) (root)} ". All responses are encoded
+                    code{(base_url)} ". All responses are encoded
                     in JSON. MMIDs are a unique identifier for a file returned
                     by the server after a successful upload. All datetimes are
                     in UTC."
                 }
+                p {
+                    "Errors from the upload endpoints are returned as
+                    JSON with an appropriate status code, containing a
+                    machine readable " code{"error"} " (one of "
+                    code{"not_found"} ", " code{"gone"} ", " code{"forbidden"} ", "
+                    code{"bad_chunk"} ", "
+                    code{"too_large"} ", " code{"unsupported_type"} ", " code{"incomplete"} ", "
+                    code{"hash_mismatch"} ", " code{"invalid"} ", "
+                    code{"unavailable"} ", " code{"insufficient_storage"} ", or "
+                    code{"internal"} ") and a
+                    human readable "
+                    code{"message"} "."
+                }
+                p {
+                    "A server may require HTTP Basic authentication for the
+                    upload endpoints, and optionally for the download and file
+                    information endpoints as well. Requests without valid
+                    credentials will recieve a " code{"401"} " response."
+                }
+                p {
+                    "A server may also accept API keys for the upload
+                    endpoints, sent in the " code{"X-Api-Key"} " header, or
+                    as the " code{"key"} " query parameter for clients which
+                    can't set headers, like websockets in browsers."
+                }
+                p {
+                    "The upload endpoints may be rate limited per client. If a
+                    client makes too many requests, or has too many uploads in
+                    progress at once, it will recieve a " code{"429"} " response
+                    with a " code{"Retry-After"} " header and a JSON body
+                    containing " code{"retry_after"} " in seconds."
+                }
                 p {
                     "The following endpoints are supported:"
                 }
 
                 hr;
                 h2 { code {"/upload/chunked"} }
-                pre { r#"POST JSON{"name":string, "size":int, "expire_duration":int} -> JSON"# }
+                pre { r#"POST JSON{"name":string, "size":int, "expire_duration":int, "max_downloads":int?, "password":string?, "visibility":string?, "mime_type":string?} -> JSON"# }
                 p {
                     "Start here to upload a file. POST some JSON containing the
                     required variables to this endpoint, and you will recieve a
                     UUID and a few other items which you can use to send the
-                    follow up requests to actually complete the upload."
+                    follow up requests to actually complete the upload. If "
+                    code{"max_downloads"} " is set, the file will expire after
+                    being downloaded that many times. If " code{"password"} "
+                    is set, the file and its information can only be accessed
+                    by appending " code{"?password=<password>"} " to the
+                    request, and browsers will be shown a page asking for it."
+                }
+                p {
+                    "The " code{"visibility"} " decides who can see the file. "
+                    code{"unlisted"} " files, the default, can be seen by
+                    anyone with the link, as can " code{"public"} " files,
+                    which are also marked as public in listings. "
+                    code{"private"} " files need the credentials of a user, or
+                    the file's " code{"delete_token"} " in a "
+                    code{"?token=<delete_token>"} " query parameter. Otherwise
+                    they respond with " code{"401 Unauthorized"} ", or "
+                    code{"404"} " if the server has no users."
+                }
+                p {
+                    "A server may only allow some types of files. The type is
+                    detected from the file's contents once the upload is
+                    finished, so a file of a type which isn't allowed is still
+                    sent in full before it is rejected with "
+                    code{"415 Unsupported Media Type"} ". Clients can avoid
+                    this by setting " code{"mime_type"} " to what they believe
+                    the type to be, which is checked when the upload starts."
                 }
                 p {
                     "Example successful response:"
@@ -70,9 +408,30 @@ pub fn api_info(settings: &State<Settings>) -> Markup {
                 pre {
                     "{\n\t\"status\": true,\n\t\"message\": \"\",\n\t\"uuid\": \"ca4614b1-04d5-457b-89af-a4e00576f701\",\n\t\"chunk_size\": 20000000\n}"
                 }
+                p {
+                    "If the duration isn't one the server allows, "
+                    code{"suggested_duration"} " is the closest allowed
+                    duration in seconds, which the client MAY offer instead."
+                }
                 p {"Example failure response:"}
                 pre {
-                    "{\n\t\"status\": false,\n\t\"message\": \"Duration invalid\",\n}"
+                    "{\n\t\"status\": false,\n\t\"message\": \"Duration not allowed, the closest allowed duration is 6 hours\",\n\t\"suggested_duration\": 21600\n}"
+                }
+
+                hr;
+                h2 { code {"/upload/chunked/batch"} }
+                pre { r#"POST JSON[{"name":string, "size":int, "expire_duration":int, ...}] -> JSON"# }
+                p {
+                    "Start several chunked uploads in one request. Takes an
+                    array of the same objects as " code{"/upload/chunked"}
+                    ", and responds with an array of the same responses, in the
+                    same order. Each file is checked on its own, so one which
+                    fails doesn't stop the others from starting. The chunks of
+                    each file are then uploaded as usual."
+                }
+                p {"Example response:"}
+                pre {
+                    "[\n\t{\n\t\t\"status\": true,\n\t\t\"message\": \"\",\n\t\t\"uuid\": \"ca4614b1-04d5-457b-89af-a4e00576f701\",\n\t\t\"chunk_size\": 20000000\n\t},\n\t{\n\t\t\"status\": false,\n\t\t\"message\": \"File too large\"\n\t}\n]"
                 }
 
                 hr;
@@ -85,45 +444,148 @@ pub fn api_info(settings: &State<Settings>) -> Markup {
                     The chunk number is the position in the file in chunks.
                     The client MUST perform as many of these transfers as it
                     takes to upload the entire file. Any duplicated chunks will
-                    be rejected. Any rejection means that the file will be
-                    deleted and the client SHOULD restart the transaction from
-                    the beginning. The client SHOULD retry sending the chunk on
+                    be rejected. The client SHOULD retry sending the chunk on
                     network errors."
                 }
+                p {
+                    "Chunks larger than " code{"chunk_size"} ", or which go
+                    past the end of the file, are rejected with "
+                    code{"413 Payload Too Large"} " before any of them is
+                    written. The upload is kept, so the client MAY send the
+                    chunk again correctly."
+                }
+
+                hr;
+                h2 { code {"/upload/chunked/<uuid>?status"} }
+                pre { r#"GET -> JSON"# }
+                p {
+                    "Returns the chunks of an in progress upload which the
+                    server has already recieved. If a transfer is interrupted,
+                    the client MAY use this to resume the upload by sending only
+                    the missing chunks."
+                }
+                p {"Example response:"}
+                pre {
+                    "{\n\t\"size\": 54000000,\n\t\"chunk_size\": 20000000,\n\t\"received_chunks\": [\n\t\t0,\n\t\t2\n\t]\n}"
+                }
+
+                hr;
+                h2 { code {"/upload/chunked/<uuid>/progress"} }
+                pre { r#"GET -> Server-Sent Events"# }
+                p {
+                    "Follows the progress of an in progress upload, so it can
+                    be shown while the chunks are sent over other connections.
+                    A " code{"progress"} " event is sent whenever more of the
+                    file arrives, and an " code{"end"} " event once the upload
+                    has been finished, timed out, or been cancelled, after
+                    which the stream closes."
+                }
+                p {"Example event:"}
+                pre {
+                    "event: progress\ndata: {\"received_bytes\":20000000,\"total\":54000000}"
+                }
 
                 hr;
-                h2 { code {"/upload/chunked/<uuid>?finish"} }
+                h2 { code {"/upload/chunked/<uuid>?finish&expected_hash=<hash>"} }
                 pre { r#"GET -> JSON"# }
                 p {
                     "Once all the chunks have been uploaded, finish the upload
-                    by sending a GET request to this endpoint."
+                    by sending a GET request to this endpoint. If any chunks
+                    are missing, the server responds with "
+                    code{"409 Conflict"} " and keeps the upload, so the client
+                    MAY send the missing chunks and try again."
+                }
+                p {
+                    "Servers may be set up to treat the size the upload was
+                    started with as a maximum. Then the file ends at the first
+                    chunk shorter than " code{"chunk_size"} ", which MUST be
+                    the last chunk sent, or after the furthest chunk if they
+                    are all full."
+                }
+                p {
+                    "The client MAY send the hex encoded hash of the file as "
+                    code{"expected_hash"} ", made with the server's "
+                    code{"hash_algorithm"} " from " code{"/info"} ". If the uploaded data
+                    does not match it, the upload is deleted and the server
+                    responds with " code{"422 Unprocessable Entity"} "."
+                }
+                p {
+                    code{"deduplicated"} " is true if an identical file was
+                    already on the server, in which case the new upload is
+                    discarded and the file shares the existing copy."
                 }
                 p {"Example successful response:"}
                 pre {
-                    "{\n\t\"mmid\": \"uVFNeajm\",\n\t\"name\": \"1600-1200.jpg\",\n\t\"mime_type\": \"image/jpeg\",\n\t\"hash\": \"8f92924d52e796a82fd7709b43f5e907949e7098f5b4bc94b314c0bd831e7719\",\n\t\"upload_datetime\": \"2024-11-04T13:23:20.592090428Z\",\n\t\"expiry_datetime\": \"2024-11-04T19:23:20.592090428Z\"\n}"
+                    "{\n\t\"mmid\": \"uVFNeajm\",\n\t\"name\": \"1600-1200.jpg\",\n\t\"mime_type\": \"image/jpeg\",\n\t\"hash\": \"8f92924d52e796a82fd7709b43f5e907949e7098f5b4bc94b314c0bd831e7719\",\n\t\"algo\": \"blake3\",\n\t\"size\": 248192,\n\t\"upload_datetime\": \"2024-11-04T13:23:20.592090428Z\",\n\t\"expiry_datetime\": \"2024-11-04T19:23:20.592090428Z\",\n\t\"delete_token\": \"hzkjZ5BS0pNHxIsF6FmLqZjHnDxkAfCz\",\n\t\"deduplicated\": false\n}"
+                }
+
+
+                hr;
+                h2 { code {"/upload"} }
+                pre { r#"POST multipart/form-data{file, duration?, max_downloads?, password?, visibility?} -> JSON"# }
+                p {
+                    "Upload a file in a single request, for simple clients like "
+                    code{"curl -F file=@example.png"} ". The duration may be
+                    given in seconds or like " code{"6h"} " or " code{"1d12h"}
+                    ", and is the server's default if not set. Responds with
+                    the same JSON as " code{"/upload/chunked/<uuid>?finish"} "."
                 }
 
+                hr;
+                h2 { code {"/upload/remote"} }
+                pre { r#"POST JSON{"url":string, "duration":int?, "max_downloads":int?, "password":string?, "visibility":string?} -> JSON"# }
+                p {
+                    "Upload a file by having the server fetch it from a URL,
+                    if the server allows it. The duration is in seconds. The
+                    server won't fetch from private or local addresses.
+                    Responds with the same JSON as "
+                    code{"/upload/chunked/<uuid>?finish"} "."
+                }
 
                 hr;
                 h2 { code {"/info"} }
                 pre { r#"GET -> JSON"# }
                 p {
-                    "Returns the capabilities of the server."
+                    "Returns the capabilities of the server, and the URL
+                    clients should use to reach it. " code{"chunk_size"} " is
+                    the size of the chunks chunked uploads are split into, "
+                    code{"hash_algorithm"} " is either " code{"blake3"} " or "
+                    code{"sha256"} ", and the " code{"supports_"} " fields say which ways of
+                    uploading the server accepts: " code{"/upload/websocket"}
+                    ", " code{"/upload/chunked"} ", and " code{"/upload"} "."
+                }
+                p {"Example response:"}
+                pre {
+                    "{\n\t\"url\": \"https://example.com/\",\n\t\"max_filesize\": 5000000000,\n\t\"max_duration\": 259200,\n\t\"min_duration\": 300,\n\t\"default_duration\": 21600,\n\t\"allowed_durations\": [\n\t\t3600,\n\t\t21600,\n\t\t86400,\n\t\t172800\n\t],\n\t\"chunk_size\": 10000000,\n\t\"hash_algorithm\": \"blake3\",\n\t\"supports_websocket\": true,\n\t\"supports_chunked\": true,\n\t\"supports_multipart\": true\n}"
+                }
+
+                hr;
+                h2 { code {"/version"} }
+                pre { r#"GET -> JSON"# }
+                p {
+                    "Returns the version of the server. The commit it was
+                    built from, when it was built, and the version of Rust
+                    used are " code{"null"} " unless the server was built with
+                    the " code{"git_tag"} " feature."
                 }
                 p {"Example response:"}
                 pre {
-                    "{\n\t\"max_filesize\": 5000000000,\n\t\"max_duration\": 259200,\n\t\"default_duration\": 21600,\n\t\"allowed_durations\": [\n\t\t3600,\n\t\t21600,\n\t\t86400,\n\t\t172800\n\t]\n}"
+                    "{\n\t\"version\": \"0.2.0\",\n\t\"git_sha\": \"da4224ee6ad0de69f5d46c76015bddff072b3e29\",\n\t\"build_timestamp\": \"2024-11-04T13:23:20.592090428Z\",\n\t\"rustc_version\": \"1.82.0\"\n}"
                 }
 
                 hr;
                 h2 { code {"/info/<mmid>"} }
                 pre { r#"GET -> JSON"# }
                 p {
-                    "Returns information about a file by its MMID."
+                    "Returns information about a file by its MMID. Responds with "
+                    code{"403 Forbidden"} " if the file is password protected
+                    and the correct " code{"password"} " was not supplied, or "
+                    code{"410 Gone"} " if the file has expired or been
+                    downloaded as many times as it is allowed."
                 }
                 p {"Example response:"}
                 pre {
-                    "{\n\t\"mmid\": \"xNLF6ogx\",\n\t\"name\": \"1600-1200.jpg\",\n\t\"mime_type\": \"image/png\",\n\t\"hash\": \"2e8e0a493ef99dfd950e870e319213d33573f64ba32b5a5399dd6c79c7d5cf00\",\n\t\"upload_datetime\": \"2024-10-29T22:09:48.648562311Z\",\n\t\"expiry_datetime\": \"2024-10-30T04:09:48.648562311Z\"\n}"
+                    "{\n\t\"mmid\": \"xNLF6ogx\",\n\t\"name\": \"1600-1200.jpg\",\n\t\"mime_type\": \"image/png\",\n\t\"hash\": \"2e8e0a493ef99dfd950e870e319213d33573f64ba32b5a5399dd6c79c7d5cf00\",\n\t\"algo\": \"blake3\",\n\t\"size\": 1529013,\n\t\"upload_datetime\": \"2024-10-29T22:09:48.648562311Z\",\n\t\"expiry_datetime\": \"2024-10-30T04:09:48.648562311Z\"\n}"
                 }
 
                 hr;
@@ -137,6 +599,16 @@ pub fn api_info(settings: &State<Settings>) -> Markup {
                     ". Appending " code{"download"} " forces the browser to download
                     the file regardless of MIME type."
                 }
+                p {
+                    "Link preview bots, like the ones used by chat apps, are
+                    sent a page with OpenGraph tags describing the file
+                    instead of a redirect."
+                }
+                p {
+                    "Files which have expired or been downloaded as many times
+                    as they are allowed respond with " code{"410 Gone"} ",
+                    even before they are removed from the server."
+                }
                 p {"Example default response:"}
                 pre {"303: /f/xNLF6ogx/1600-1200.jpg"}
 
@@ -149,24 +621,375 @@ pub fn api_info(settings: &State<Settings>) -> Markup {
                 p {
                     "Returns the contents of the file corresponding to the
                     requested MMID, but with the corresponding filename so as
-                    to preserve it for downloads. Mostly for use by browsers."
+                    to preserve it for downloads. Mostly for use by browsers.
+                    Appending " code{"?download=true"} " forces the browser to
+                    download the file, otherwise it is shown inline when
+                    possible."
+                }
+                p {
+                    "If the filename is wrong, the server responds with "
+                    code{"301 Moved Permanently"} " to the correct one, or "
+                    code{"404 Not Found"} " if it is set up to require exact
+                    names."
+                }
+                p {
+                    "Both this and " code{"/f/<mmid>?noredir"} " support the "
+                    code{"Range"} " header for partial downloads, responding
+                    with " code{"206 Partial Content"} ". Only the first range
+                    of a multi-range request is sent."
+                }
+                p {
+                    "Both also answer " code{"HEAD"} " requests with the same
+                    headers, like " code{"Content-Length"} " and "
+                    code{"ETag"} ", but no body. These don't count as a
+                    download."
+                }
+                p {
+                    "Text and other compressible files are compressed with
+                    Brotli or gzip if the client sends a matching "
+                    code{"Accept-Encoding"} " header. Range requests are
+                    always sent uncompressed."
                 }
                 p {"Example response:"}
                 pre {
                     "<File Bytes>"
                 }
+
+                hr;
+                h2 { code {"/f/<mmid>?token=<delete_token>"} }
+                pre { r#"DELETE mmid=MMID token=String -> ()"# }
+                p {
+                    "Deletes a file before it expires. The token is the "
+                    code{"delete_token"} " returned when the upload was
+                    finished. Responds with " code{"404"} " if the MMID does
+                    not exist, or " code{"403"} " if the token is incorrect."
+                }
+
+                hr;
+                h2 { code {"/f/<mmid>"} }
+                pre { r#"PATCH mmid=MMID body=JSON -> JSON"# }
+                p {
+                    "Renames a file, using the " code{"delete_token"} " returned
+                    when the upload was finished. The new name can't contain
+                    path separators or control characters. Responds with the
+                    updated file information, like " code{"/info/<mmid>"} ",
+                    or " code{"410"} " if the file has expired."
+                }
+                p {"Example request body:"}
+                pre {
+                    r#"{
+  "token": "xDgFkbYmAVMX3m2hW5WxUWyaOMmbGjJk",
+  "name": "1600-1200.jpg"
+}"#
+                }
+
+                hr;
+                h2 { code {"/f/<mmid>/expiry"} }
+                pre { r#"PATCH mmid=MMID body=JSON -> JSON"# }
+                p {
+                    "Changes when a file expires, using the " code{"delete_token"}
+                    " returned when the upload was finished. The new expiry is
+                    the given duration from now, so it may be sooner than
+                    before. The duration is a string like " code{"6h"} " or "
+                    code{"1d12h"} ", and must follow the same rules as when
+                    uploading. Responds with the updated file information."
+                }
+                p {"Example request body:"}
+                pre {
+                    r#"{
+  "token": "xDgFkbYmAVMX3m2hW5WxUWyaOMmbGjJk",
+  "duration": "1d"
+}"#
+                }
+
+                hr;
+                h2 { code {"/f/<mmid>/copy"} }
+                pre { r#"POST mmid=MMID body=JSON -> JSON"# }
+                p {
+                    "Shares a file again under a new MMID which expires the
+                    given duration from now, using the " code{"delete_token"}
+                    " returned when the upload was finished. The copy shares
+                    the stored file, so nothing is uploaded again, and keeps
+                    the original's password and visibility. The duration must
+                    follow the same rules as when uploading. Responds with the
+                    new file's information, including its own "
+                    code{"delete_token"} "."
+                }
+                p {"Example request body:"}
+                pre {
+                    r#"{
+  "token": "xDgFkbYmAVMX3m2hW5WxUWyaOMmbGjJk",
+  "duration": "3d"
+}"#
+                }
+
+                hr;
+                h2 { code {"/p/<mmid>"} }
+                pre { r#"GET mmid=MMID -> HTML"# }
+                p {
+                    "A page for browsers which shows the file embedded in
+                    the page if it is an image, video, audio, or text file,
+                    along with its size, time until expiry, and a download
+                    button. Source code is syntax highlighted, using the
+                    theme given by " code{"?theme=light"} " or "
+                    code{"?theme=dark"} ". Like downloads, responds with "
+                    code{"410 Gone"} " if the file has expired."
+                }
+
+                hr;
+                h2 { code {"/thumb/<mmid>"} }
+                pre { r#"GET mmid=MMID -> JPEG"# }
+                p {
+                    "Returns a small JPEG preview of an image or video file.
+                    Responds with " code{"404"} " if the file is not an image
+                    or video, or a preview could not be made. Password
+                    protected files need the " code{"?password="} " query
+                    parameter."
+                }
+
+                hr;
+                h2 { code {"/health"} }
+                pre { r#"GET -> JSON"# }
+                p {
+                    "Returns whether the server is able to accept uploads,
+                    for use by load balancers. Responds with "
+                    code{"503 Service Unavailable"} " if the database is
+                    unusable, files cannot be written, the disk is nearly
+                    full, or the server is shutting down. A server in
+                    read-only mode still serves downloads, so it is reported
+                    as healthy, but with " code{"accepting_uploads"} " set to "
+                    code{"false"} "."
+                }
+                p {"Example response:"}
+                pre {
+                    "{\n\t\"status\": \"ok\",\n\t\"db_ok\": true,\n\t\"writable\": true,\n\t\"accepting_uploads\": true,\n\t\"read_only\": false,\n\t\"disk_free_bytes\": 78958809088\n}"
+                }
+
+                hr;
+                h2 { code {"/metrics"} }
+                pre { r#"GET -> Text"# }
+                p {
+                    "Returns counters about the server's activity in the "
+                    a target="_blank" href="//prometheus.io/docs/instrumenting/exposition_formats/" {"Prometheus"}
+                    " text format. Metrics are only available when the "
+                    code{"metrics_enabled"} " setting is turned on, and this
+                    responds with " code{"404"} " otherwise."
+                }
+
+                hr;
+                h2 { code {"/stats"} }
+                pre { r#"GET -> JSON"# }
+                p {
+                    "Returns how many files the server is hosting, their total
+                    size with identical files counted once, and how many of
+                    them were uploaded in the last 24 hours. Nothing about
+                    individual files is included. The numbers are updated at
+                    most once a minute by default. Responds with "
+                    code{"404"} " if stats are disabled on this server."
+                }
+                p {"Example response:"}
+                pre {
+                    "{\n\t\"files\": 152,\n\t\"bytes\": 1529013604,\n\t\"uploads_last_24h\": 17,\n\t\"updated\": \"2024-10-29T22:09:48.648562311Z\"\n}"
+                }
+
+                hr;
+                h2 { code {"/admin/list?page=<page>&per_page=<per_page>&sort=<sort>&visibility=<visibility>"} }
+                pre { r#"GET page=int per_page=int sort=upload|expiry visibility=public|unlisted|private -> JSON"# }
+                p {
+                    "Lists every file on the server. Requires the credentials of
+                    a user with " code{"admin = true"} " set, and responds with "
+                    code{"403"} " for other users or if authentication is
+                    disabled. Files are sorted by " code{"upload"} " time
+                    (newest first, the default) or by " code{"expiry"} "
+                    (soonest first). Pages start from 1, and contain 50
+                    files unless " code{"per_page"} " is given, up to 500.
+                    Only files with a certain " code{"visibility"} " are
+                    listed if it is given."
+                }
+                p {"Example response:"}
+                pre {
+                    r#"{
+  "total": 1,
+  "page": 1,
+  "per_page": 50,
+  "files": [
+    {
+      "mmid": "xNLF6ogx",
+      "name": "1600-1200.jpg",
+      "size": 151215,
+      "mime_type": "image/png",
+      "upload_datetime": "2024-10-29T22:09:48.648562311Z",
+      "expiry_datetime": "2024-10-30T22:09:48.648562311Z",
+      "visibility": "unlisted"
+    }
+  ]
+}"#
+                }
+
+                hr;
+                h2 { code {"/admin/search?q=<query>&fuzzy=<fuzzy>"} }
+                pre { r#"GET q=String fuzzy=bool page=int per_page=int -> JSON"# }
+                p {
+                    "Searches for files whose names contain the query, ignoring
+                    case. With " code{"fuzzy=true"} ", names which are similar
+                    to the query also match, with the closest matches first.
+                    Requires admin credentials, and is paginated and responds
+                    in the same way as " code{"/admin/list"} "."
+                }
+
+                hr;
+                h2 { code {"/admin/events?kind=<kind>&limit=<limit>"} }
+                pre { r#"GET kind=String limit=int -> JSON"# }
+                p {
+                    "Returns recent uploads, downloads, deletions, and expiry
+                    changes made by administrators, newest first. "
+                    code{"kind"} " may be " code{"upload"} ", "
+                    code{"download"} ", " code{"delete"} ", " code{"expire"}
+                    ", or " code{"extend"} " to only return one kind of
+                    event. Returns 100 events by default, and at
+                    most 500. Requires admin credentials."
+                }
+                p {"Example response:"}
+                pre {
+                    r#"[
+  {
+    "kind": "download",
+    "mmid": "xNLF6ogx",
+    "timestamp": "2024-10-29T22:15:02.163071208Z",
+    "ip": "203.0.113.7"
+  }
+]"#
+                }
+
+                hr;
+                h2 { code {"/admin/hashes?page=<page>&per_page=<per_page>"} }
+                pre { r#"GET page=int per_page=int -> JSON"# }
+                p {
+                    "Lists the files actually stored on the server, each with
+                    the MMIDs of the uploads sharing it, the most shared first.
+                    " code{"unique_bytes"} " is how much is stored, and "
+                    code{"logical_bytes"} " is how much would be stored if
+                    identical uploads weren't only stored once. Requires admin
+                    credentials, and is paginated in the same way as "
+                    code{"/admin/list"} "."
+                }
+                p {"Example response:"}
+                pre {
+                    r#"{
+  "total": 1,
+  "unique_bytes": 151215,
+  "logical_bytes": 302430,
+  "page": 1,
+  "per_page": 50,
+  "hashes": [
+    {
+      "hash": "2e8e0a493ef99dfd950e870e319213d33573f64ba32b5a5399dd6c79c7d5cf00",
+      "size": 151215,
+      "mmids": [
+        "xNLF6ogx",
+        "uVFNeajm"
+      ]
+    }
+  ]
+}"#
+                }
+
+                h2 { code {"/admin/reconcile"} }
+                pre { r#"POST -> JSON"# }
+                p {
+                    "Removes any entries whose file is missing from storage,
+                    such as when it was deleted by hand, and returns their
+                    MMIDs. Requires admin credentials. This can also be done
+                    each time the database is cleaned with the "
+                    code{"reconcile_on_cleanup"} " setting."
+                }
+                p {"Example response:"}
+                pre {
+                    r#"[
+  "xNLF6ogx",
+  "uVFNeajm"
+]"#
+                }
+
+                hr;
+                h2 { code {"/admin/readonly?enabled=<enabled>"} }
+                pre { r#"POST enabled=bool -> JSON"# }
+                p {
+                    "Switches read-only mode on or off. In read-only mode,
+                    uploads are refused with " code{"503 Service Unavailable"}
+                    ", including ones which are already in progress, while
+                    downloads keep working. This is useful while backing up
+                    or moving the server. Requires admin credentials. The
+                    server can also be started in read-only mode with the "
+                    code{"read_only"} " setting, which also switches it when
+                    changed and the settings are reloaded."
+                }
+                p {"Example response:"}
+                pre {
+                    "{\n\t\"read_only\": true\n}"
+                }
+
+                hr;
+                h2 { code {"/admin/reload"} }
+                pre { r#"POST -> JSON"# }
+                p {
+                    "Reloads the settings file without restarting the server,
+                    the same as sending the server process " code{"SIGHUP"} ".
+                    Settings such as the listen address, storage, and chunk
+                    size only take effect after a restart, so changes to them
+                    are ignored and listed in " code{"restart_needed"} ". An
+                    invalid settings file is rejected with "
+                    code{"400 Bad Request"} " and the current settings are
+                    kept. Requires admin credentials."
+                }
+                p {"Example response:"}
+                pre {
+                    "{\n\t\"restart_needed\": [\"chunk_size\"]\n}"
+                }
+
+                hr;
+                h2 { code {"/admin/f/<mmid>/expire?delete=<delete>"} }
+                pre { r#"POST mmid=MMID delete=bool -> JSON"# }
+                p {
+                    "Makes a file expire now, so it can no longer be downloaded
+                    and is removed the next time the database is cleaned. With "
+                    code{"delete=true"} ", it is removed straight away instead,
+                    along with its stored data if no other file shares it.
+                    Responds with the file's summary, like the ones from "
+                    code{"/admin/list"} ". Requires admin credentials, and is
+                    logged along with the administrator's name."
+                }
+
+                hr;
+                h2 { code {"/admin/f/<mmid>/extend"} }
+                pre { r#"POST mmid=MMID body=JSON -> JSON"# }
+                p {
+                    "Changes when a file expires to the given duration from
+                    now, like " code{"/f/<mmid>/expiry"} " but without a
+                    deletion token. The duration isn't limited to the ones
+                    allowed for uploads, and files which have expired but not
+                    been cleaned up yet can be rescued. Responds with the
+                    file's summary. Requires admin credentials, and is logged
+                    along with the administrator's name."
+                }
+                p {"Example request body:"}
+                pre {
+                    r#"{
+  "duration": "1w"
+}"#
+                }
             }
 
             hr;
-            (footer())
+            (footer(settings))
         }
     }
 }
 
 #[get("/about")]
-pub fn about() -> Markup {
+pub fn about(settings: &Settings) -> Markup {
     html! {
-        (head("Confetti-Box | About"))
+        (head(settings, &settings.server.page_title("About")))
 
         center {
             h1 { "What's this?" }
@@ -201,7 +1024,7 @@ pub fn about() -> Markup {
             }
 
             hr;
-            (footer())
+            (footer(settings))
         }
     }
 }
@@ -0,0 +1,212 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use chrono::{DateTime, Utc};
+use rocket::{
+    catch,
+    http::{Header, Status},
+    request::{FromRequest, Outcome},
+    serde::{json::Json, Serialize},
+    Request, Responder,
+};
+
+use crate::{database::Chunkbase, settings::Settings};
+
+/// A token bucket rate limiter, keyed by client IP address
+#[derive(Default, Debug)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    last_update: DateTime<Utc>,
+}
+
+impl RateLimiter {
+    /// Take a token from the bucket for this IP. If there are none left,
+    /// returns the number of seconds until another token will be available.
+    pub fn check(&self, ip: IpAddr, requests_per_minute: u32) -> Result<(), u64> {
+        let capacity = requests_per_minute as f64;
+        let per_second = capacity / 60.0;
+        let now = Utc::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // Forget about clients whose buckets have refilled completely, so the
+        // map doesn't grow forever
+        if buckets.len() > 1024 {
+            buckets.retain(|_, b| b.refilled(now, per_second) < capacity);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            last_update: now,
+        });
+        bucket.tokens = bucket.refilled(now, per_second).min(capacity);
+        bucket.last_update = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / per_second).ceil() as u64)
+        }
+    }
+}
+
+impl Bucket {
+    fn refilled(&self, now: DateTime<Utc>, per_second: f64) -> f64 {
+        let elapsed = (now - self.last_update).num_milliseconds() as f64 / 1000.0;
+        self.tokens + elapsed * per_second
+    }
+}
+
+/// The number of seconds a rate limited client should wait before retrying,
+/// stored in the request's local cache for the catcher to use
+struct RetryAfter(u64);
+
+/// A request guard which succeeds only if the client has not exceeded the
+/// configured request rate
+pub struct RateLimit {
+    pub ip: Option<IpAddr>,
+}
+
+/// A request guard for requests which start a new upload. In addition to the
+/// request rate, this also succeeds only if the client has not exceeded the
+/// configured number of concurrent uploads.
+pub struct UploadRateLimit {
+    pub ip: Option<IpAddr>,
+}
+
+/// The address a client is limited by. Clients can set headers to anything,
+/// so the address they connect from is used unless a trusted proxy has been
+/// configured to send theirs in a header.
+fn limited_ip(req: &Request<'_>, settings: &Settings) -> Option<IpAddr> {
+    settings
+        .rate_limit
+        .ip_header
+        .as_ref()
+        .and_then(|header| req.headers().get_one(header))
+        .and_then(|ips| ips.rsplit(',').next()?.trim().parse().ok())
+        .or_else(|| req.remote().map(|r| r.ip()))
+}
+
+fn check_rate(req: &Request<'_>, new_upload: bool) -> Result<Option<IpAddr>, u64> {
+    let settings = Settings::for_request(req);
+    let ip = limited_ip(req, settings);
+    let (true, Some(ip)) = (settings.rate_limit.enabled, ip) else {
+        return Ok(ip);
+    };
+
+    let limiter = req.rocket().state::<RateLimiter>().unwrap();
+    limiter.check(ip, settings.rate_limit.requests_per_minute)?;
+
+    if new_upload {
+        let chunk_db = req.rocket().state::<Arc<RwLock<Chunkbase>>>().unwrap();
+        let chunk_db = chunk_db.read().unwrap();
+        if chunk_db.count_for_ip(&ip) >= settings.rate_limit.concurrent_uploads {
+            let retry = chunk_db
+                .next_timeout_for_ip(&ip)
+                .map(|t| (t - Utc::now()).num_seconds().max(1) as u64)
+                .unwrap_or(1);
+            return Err(retry);
+        }
+    }
+
+    Ok(Some(ip))
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimit {
+    type Error = u64;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match check_rate(req, false) {
+            Ok(ip) => Outcome::Success(Self { ip }),
+            Err(retry) => {
+                req.local_cache(|| RetryAfter(retry));
+                Outcome::Error((Status::TooManyRequests, retry))
+            }
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UploadRateLimit {
+    type Error = u64;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match check_rate(req, true) {
+            Ok(ip) => Outcome::Success(Self { ip }),
+            Err(retry) => {
+                req.local_cache(|| RetryAfter(retry));
+                Outcome::Error((Status::TooManyRequests, retry))
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RateLimitedResponse {
+    status: bool,
+    message: String,
+
+    /// Number of seconds to wait before trying again
+    retry_after: u64,
+}
+
+#[derive(Responder)]
+#[response(status = 429)]
+pub struct TooManyRequests {
+    inner: Json<RateLimitedResponse>,
+    retry_after: Header<'static>,
+}
+
+/// Respond to rate limited requests with JSON explaining when to retry
+#[catch(429)]
+pub fn too_many_requests(req: &Request<'_>) -> TooManyRequests {
+    let retry_after = req.local_cache(|| RetryAfter(60)).0;
+
+    TooManyRequests {
+        inner: Json(RateLimitedResponse {
+            status: false,
+            message: format!("Too many requests, try again in {retry_after} seconds"),
+            retry_after,
+        }),
+        retry_after: Header::new("Retry-After", retry_after.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::asynchronous::Client;
+
+    use super::*;
+
+    #[rocket::async_test]
+    async fn headers_are_only_trusted_when_configured() {
+        let client = Client::untracked(rocket::build()).await.unwrap();
+        let remote = "10.0.0.1:4000".parse().unwrap();
+        let request = client
+            .get("/")
+            .remote(remote)
+            .header(Header::new("X-Real-IP", "192.0.2.7"))
+            .header(Header::new("X-Forwarded-For", "203.0.113.9, 192.0.2.8"));
+
+        // Anyone could have sent the header, so the connection is used
+        let mut settings = Settings::default();
+        assert_eq!(limited_ip(request.inner(), &settings), Some(remote.ip()));
+
+        settings.rate_limit.ip_header = Some("X-Real-IP".into());
+        assert_eq!(limited_ip(request.inner(), &settings), "192.0.2.7".parse().ok());
+
+        // Only the last address was added by the proxy
+        settings.rate_limit.ip_header = Some("X-Forwarded-For".into());
+        assert_eq!(limited_ip(request.inner(), &settings), "192.0.2.8".parse().ok());
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    error::Error,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect::Policy,
+    Client, Url,
+};
+use rocket::tokio::net::lookup_host;
+
+use crate::settings::RemoteUploadSettings;
+
+/// Networks which are only reachable from the server itself or its local
+/// network, so remote uploads never fetch from them unless they are allowed
+static PRIVATE_NETWORKS: LazyLock<Vec<IpNet>> = LazyLock::new(|| {
+    [
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "172.16.0.0/12",
+        "192.0.0.0/24",
+        "192.168.0.0/16",
+        "198.18.0.0/15",
+        "224.0.0.0/4",
+        "240.0.0.0/4",
+        "::/128",
+        "::1/128",
+        "fc00::/7",
+        "fe80::/10",
+        "ff00::/8",
+    ]
+    .iter()
+    .map(|n| n.parse().unwrap())
+    .collect()
+});
+
+/// Whether a remote upload may fetch from an address
+pub fn address_allowed(settings: &RemoteUploadSettings, ip: IpAddr) -> bool {
+    // IPv4 addresses can be written as IPv6 ones to sneak past the checks
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip,
+    };
+
+    if settings.blocked_networks.iter().any(|n| n.contains(&ip)) {
+        return false;
+    }
+    if settings.allowed_networks.iter().any(|n| n.contains(&ip)) {
+        return true;
+    }
+
+    !PRIVATE_NETWORKS.iter().any(|n| n.contains(&ip))
+}
+
+/// Whether a remote upload may fetch from a URL. Hostnames are checked once
+/// they are resolved, since they could point anywhere.
+pub fn url_allowed(settings: &RemoteUploadSettings, url: &Url) -> bool {
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+
+    match url.host_str() {
+        Some(host) => match host.trim_start_matches('[').trim_end_matches(']').parse() {
+            Ok(ip) => address_allowed(settings, ip),
+            Err(_) => true,
+        },
+        None => false,
+    }
+}
+
+/// Resolves hostnames while leaving out any addresses which remote uploads
+/// may not fetch from. Checking the addresses which are actually connected to
+/// means a hostname can't pass the check and then resolve somewhere else.
+struct FilteringResolver(Arc<RemoteUploadSettings>);
+
+impl Resolve for FilteringResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let settings = Arc::clone(&self.0);
+
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|a| address_allowed(&settings, a.ip()))
+                .collect();
+
+            if addrs.is_empty() {
+                return Err("Fetching from this address is not allowed".into());
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Create a client for fetching remote uploads, which refuses to connect to
+/// addresses that aren't allowed, even by following a redirect
+pub fn client(settings: &RemoteUploadSettings) -> reqwest::Result<Client> {
+    let settings = Arc::new(settings.clone());
+    let redirect_settings = Arc::clone(&settings);
+
+    Client::builder()
+        // A proxy would resolve hostnames itself, skipping the checks
+        .no_proxy()
+        .timeout(Duration::from_secs(settings.timeout_seconds))
+        .user_agent(concat!("confetti-box/", env!("CARGO_PKG_VERSION")))
+        .redirect(Policy::custom(move |attempt| {
+            if attempt.previous().len() >= 10 {
+                attempt.error("Too many redirects")
+            } else if url_allowed(&redirect_settings, attempt.url()) {
+                attempt.follow()
+            } else {
+                attempt.error("Redirected to an address which is not allowed")
+            }
+        }))
+        .dns_resolver(Arc::new(FilteringResolver(settings)))
+        .build()
+}
+
+/// The name of the file at a URL, from the last part of its path
+pub fn file_name(url: &Url) -> String {
+    url.path_segments()
+        .and_then(|mut s| s.next_back())
+        .and_then(|n| urlencoding::decode(n).ok())
+        .map(|n| n.into_owned())
+        .unwrap_or_default()
+}
+
+/// Describe why fetching a URL failed. The errors from `reqwest` only say what
+/// was being done, with the reason in their sources.
+pub fn describe_error(error: &reqwest::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(e) = source {
+        message.push_str(": ");
+        message.push_str(&e.to_string());
+        source = e.source();
+    }
+
+    message
+}
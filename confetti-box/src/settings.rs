@@ -1,43 +1,221 @@
 use std::{
     fs::{self, File},
     io::{self, Read, Write},
+    net::IpAddr,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
+use arc_swap::ArcSwap;
+use base64::{prelude::BASE64_STANDARD, Engine as _};
 use chrono::TimeDelta;
+use ipnet::IpNet;
+use log::{info, warn};
 use rocket::data::ToByteUnit;
-use rocket::serde::{Deserialize, Serialize};
+use rocket::{
+    http::ContentType,
+    request::{FromRequest, Outcome},
+    serde::{json, Deserialize, Serialize},
+    Request,
+};
 use serde_with::serde_as;
 
+use crate::{database::MMID_LENGTHS, utils::HashAlgorithm, UploadGate};
+
 /// A response to the client from the server
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(default)]
 pub struct Settings {
     /// Maximum filesize in bytes
     pub max_filesize: u64,
 
-    /// Maximum filesize in bytes
+    /// The size in bytes of the chunks which chunked uploads are split into.
+    /// Must be more than 0, and no more than `max_filesize`.
     pub chunk_size: u64,
 
-    /// Is overwiting already uploaded files with the same hash allowed, or is
-    /// this a no-op?
+    /// Treat the size given when starting a chunked or websocket upload as
+    /// the most the file may be, rather than its exact size, for clients
+    /// which don't know the final size ahead of time.
+    ///
+    /// The file ends at the first chunk shorter than `chunk_size`, which has
+    /// to be the last chunk sent. If every chunk is full, the file ends after
+    /// the furthest chunk recieved. Finishing the upload then only needs the
+    /// chunks up to that end rather than enough to fill the given size, so a
+    /// missing chunk at the end can't be detected.
+    pub upload_size_is_maximum: bool,
+
+    /// Maximum number of chunked and websocket uploads which can be in
+    /// progress at once across all clients, 0 for no limit. Each client is
+    /// also limited by `rate_limit.concurrent_uploads` when rate limiting is
+    /// enabled.
+    pub max_concurrent_chunks: usize,
+
+    /// Largest message in bytes which a websocket upload may send at once.
+    /// Each message is held in memory until it's written to the file, so
+    /// this bounds how much memory a single upload can take up, and a larger
+    /// message ends the upload with a `too_large` error.
+    ///
+    /// The website sends the file in 10 MB messages, or in messages of this
+    /// size if it's smaller. Other clients have to keep their messages within
+    /// it too.
+    pub websocket_max_frame_size: u64,
+
+    /// How long an upload in progress may go without receiving any data
+    /// before it's abandoned, in seconds for every 10 MB of `chunk_size`, so
+    /// that larger chunks get more time. Abandoned uploads are removed by a
+    /// cleanup task which runs every 30 seconds, so they may last up to that
+    /// much longer.
+    pub chunk_timeout_seconds: u64,
+
+    /// Whether an upload identical to a file which is already stored replaces
+    /// the stored copy, such as to repair one which was damaged. Otherwise the
+    /// stored copy is kept and the upload is discarded. Either way the upload
+    /// gets its own MMID, and the file is only stored once.
     pub overwrite: bool,
 
+    /// The algorithm used to hash uploaded files, either `blake3` or
+    /// `sha256`. Files are stored by their hash, so files uploaded before
+    /// changing this aren't deduplicated against ones uploaded after.
+    pub hash_algorithm: HashAlgorithm,
+
     /// Settings pertaining to duration information
     pub duration: DurationSettings,
 
+    /// How often to remove expired files from the database, in seconds
+    pub cleanup_interval_seconds: u64,
+
+    /// Whether cleaning the database also removes entries whose file is
+    /// missing from storage. This checks every stored file each time, so it
+    /// can be slow with a remote storage backend.
+    pub reconcile_on_cleanup: bool,
+
+    /// Start the server in read-only mode, where downloads keep working but
+    /// uploads are refused. This can also be switched at runtime by an
+    /// administrator, with `/admin/readonly`, or by changing it and reloading
+    /// the settings.
+    pub read_only: bool,
+
+    /// How long to wait for uploads which are in progress to finish when
+    /// shutting down, in seconds. No new uploads are accepted meanwhile.
+    pub shutdown_grace_seconds: u64,
+
     /// The path to the database file
     pub database_path: PathBuf,
 
     /// Temporary directory for stuff
     pub temp_dir: PathBuf,
 
-    /// Directory in which to store hosted files
+    /// Directory in which to store hosted files, when using local storage
     pub file_dir: PathBuf,
 
+    /// Settings pertaining to where hosted files are stored
+    pub storage: StorageSettings,
+
+    /// Settings pertaining to uploading files from other servers by URL
+    pub remote_upload: RemoteUploadSettings,
+
+    /// Settings pertaining to encrypting hosted files in storage. If this is
+    /// not set, then files are stored as they were uploaded.
+    ///
+    /// Encrypted files have to be decrypted by the server as they are sent,
+    /// so the OS can't send them straight from the disk with `sendfile`, and
+    /// downloads use more CPU. Cached thumbnails are not encrypted, and files
+    /// stored before encryption was turned on can't be read after.
+    pub encryption: Option<EncryptionSettings>,
+
+    /// Longest filename in bytes which will be stored. Longer names are
+    /// shortened when uploading, keeping their extension.
+    pub max_filename_length: usize,
+
+    /// MIME types which may be uploaded, like `image/png` or `image/*`. If
+    /// this is empty, then any type not in `blocked_mime_types` is allowed.
+    pub allowed_mime_types: Vec<String>,
+
+    /// MIME types which may not be uploaded, like `application/x-executable`
+    /// or `video/*`
+    pub blocked_mime_types: Vec<String>,
+
+    /// MIME types which are always sent as attachments and sandboxed, since
+    /// they could run scripts if a browser displayed them
+    pub attachment_only_types: Vec<String>,
+
+    /// The type files are sent as when their type isn't known, either from
+    /// their contents or their extension
+    pub default_content_type: String,
+
+    /// Number of characters in newly generated MMIDs, between 4 and 32
+    pub mmid_length: usize,
+
+    /// Maximum width and height of generated thumbnails, in pixels
+    pub thumbnail_size: u32,
+
+    /// Parts of the user agents of link preview bots, like `Discordbot`.
+    /// These are shown a page describing a file when they follow a link to
+    /// it, instead of being redirected to the file itself.
+    pub link_preview_bots: Vec<String>,
+
+    /// Respond with `404 Not Found` when a file is requested with the right
+    /// MMID but the wrong name. Otherwise the client is redirected to the
+    /// file's correct name, which helps when a shared link gets mangled.
+    pub strict_file_names: bool,
+
+    /// Largest file in bytes which will be syntax highlighted on its preview
+    /// page. Larger files are shown as plain text.
+    pub highlight_max_size: u64,
+
+    /// Maximum total size in bytes of all stored files. If this is not set,
+    /// then storage is only limited by `min_free_space`.
+    pub max_total_storage_bytes: Option<u64>,
+
+    /// What to do when an upload would go over `max_total_storage_bytes`
+    pub eviction_policy: EvictionPolicy,
+
+    /// Minimum free disk space in bytes. Uploads which would leave less than
+    /// this are rejected, and the health check reports the server as not
+    /// ready once there is less than this.
+    pub min_free_space: u64,
+
     /// Settings pertaining to the server configuration
     pub server: ServerSettings,
 
+    /// Settings pertaining to serving HTTPS directly. If this is not set, then
+    /// the server uses plain HTTP, and HTTPS has to be handled by a reverse
+    /// proxy.
+    pub tls: Option<TlsSettings>,
+
+    /// Settings pertaining to rate limiting uploads
+    pub rate_limit: RateLimitSettings,
+
+    /// Number of uploads, downloads, and deletions to keep in the activity
+    /// log for administrators. Set this to 0 to disable the log.
+    pub max_events: usize,
+
+    /// Expose server metrics in the Prometheus format at `/metrics`. They
+    /// aren't protected by a login, so this is off unless turned on.
+    pub metrics_enabled: bool,
+
+    /// Expose public stats about the hosted files at `/stats`
+    pub stats_enabled: bool,
+
+    /// How long the stats at `/stats` are kept before being worked out again,
+    /// in seconds
+    pub stats_cache_seconds: u64,
+
+    /// Settings pertaining to requests from browsers on other origins
+    pub cors: CorsSettings,
+
+    /// Settings pertaining to search engines and other crawlers
+    pub robots: RobotsSettings,
+
+    /// Settings pertaining to authentication. If this is not set, then no
+    /// authentication is required.
+    pub auth: Option<AuthSettings>,
+
+    /// Keys which allow uploading files when sent in the `X-Api-Key` header,
+    /// as an alternative to logging in as a user. If this is empty and `auth`
+    /// is not set, then anyone may upload.
+    pub api_keys: Vec<String>,
+
     #[serde(skip)]
     path: PathBuf,
 }
@@ -47,13 +225,63 @@ impl Default for Settings {
         Self {
             max_filesize: 25.megabytes().into(), // 1 MB
             chunk_size: 10.megabytes().into(),
+            upload_size_is_maximum: false,
+            max_concurrent_chunks: 1000,
+            websocket_max_frame_size: 16.megabytes().into(),
+            chunk_timeout_seconds: 30,
             overwrite: true,
+            hash_algorithm: HashAlgorithm::default(),
             duration: DurationSettings::default(),
+            cleanup_interval_seconds: 120,
+            reconcile_on_cleanup: false,
+            read_only: false,
+            shutdown_grace_seconds: 60,
             server: ServerSettings::default(),
+            tls: None,
+            rate_limit: RateLimitSettings::default(),
+            max_events: 10_000,
+            metrics_enabled: false,
+            stats_enabled: true,
+            stats_cache_seconds: 60,
+            cors: CorsSettings::default(),
+            robots: RobotsSettings::default(),
+            auth: None,
+            api_keys: Vec::new(),
             path: "./settings.toml".into(),
             database_path: "./database.mochi".into(),
             temp_dir: std::env::temp_dir(),
             file_dir: "./files/".into(),
+            storage: StorageSettings::default(),
+            encryption: None,
+            remote_upload: RemoteUploadSettings::default(),
+            max_filename_length: 255,
+            default_content_type: "application/octet-stream".into(),
+            mmid_length: 8,
+            allowed_mime_types: Vec::new(),
+            blocked_mime_types: Vec::new(),
+            attachment_only_types: [
+                "text/html",
+                "application/xhtml+xml",
+                "image/svg+xml",
+                "application/xml",
+                "text/xml",
+            ]
+            .map(String::from)
+            .to_vec(),
+            thumbnail_size: 256,
+            link_preview_bots: [
+                "Discordbot",
+                "Twitterbot",
+                "Slackbot",
+                "facebookexternalhit",
+            ]
+            .map(String::from)
+            .to_vec(),
+            strict_file_names: false,
+            highlight_max_size: 256.kilobytes().into(),
+            min_free_space: 500.megabytes().into(),
+            max_total_storage_bytes: None,
+            eviction_policy: EvictionPolicy::default(),
         }
     }
 }
@@ -72,12 +300,87 @@ impl Settings {
             File::open(path).unwrap().read_to_string(&mut input_str)?;
         }
 
-        let mut parsed_settings: Self = toml::from_str(&input_str).unwrap();
+        let mut parsed_settings: Self = toml::from_str(&input_str)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
         parsed_settings.path = path.as_ref().to_path_buf();
 
         Ok(parsed_settings)
     }
 
+    /// Check that the settings make sense together, returning a message
+    /// explaining the problem if they don't
+    pub fn validate(&self) -> Result<(), String> {
+        if !MMID_LENGTHS.contains(&self.mmid_length) {
+            return Err(format!(
+                "mmid_length must be between {} and {}",
+                MMID_LENGTHS.start(),
+                MMID_LENGTHS.end(),
+            ));
+        }
+        if self.chunk_size == 0 {
+            return Err("chunk_size must be more than 0".into());
+        }
+        if ContentType::parse_flexible(&self.default_content_type).is_none() {
+            return Err("default_content_type must be a valid MIME type".into());
+        }
+        if self.chunk_size > self.max_filesize {
+            return Err("chunk_size must not be more than max_filesize".into());
+        }
+        if self.duration.minimum < TimeDelta::zero()
+            || self.duration.minimum > self.duration.maximum
+        {
+            return Err("duration.minimum must be between 0 and duration.maximum".into());
+        }
+
+        Ok(())
+    }
+
+    /// The settings for a request. These are taken once per request, so a
+    /// request sees the same settings throughout even if they are reloaded
+    /// while it's being handled.
+    pub fn for_request<'r>(req: &'r Request<'_>) -> &'r Settings {
+        req.local_cache(|| req.rocket().state::<Arc<SharedSettings>>().unwrap().load())
+    }
+
+    /// Whether files of this MIME type may be uploaded
+    pub fn mime_type_allowed(&self, mime_type: &str) -> bool {
+        let matches = |pattern: &String| match pattern.strip_suffix("/*") {
+            Some(top) => mime_type
+                .split_once('/')
+                .is_some_and(|(t, _)| t.eq_ignore_ascii_case(top)),
+            None => pattern.eq_ignore_ascii_case(mime_type),
+        };
+
+        (self.allowed_mime_types.is_empty() || self.allowed_mime_types.iter().any(matches))
+            && !self.blocked_mime_types.iter().any(matches)
+    }
+
+    /// The URL clients use to reach the server, like
+    /// `https://example.com/filehost/`
+    pub fn base_url(&self) -> String {
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        format!("{scheme}://{}{}", self.server.domain, self.server.root_path)
+    }
+
+    /// How long an upload in progress may go without receiving any data,
+    /// scaled by the chunk size
+    pub fn chunk_timeout(&self) -> TimeDelta {
+        let scale = self.chunk_size.div_ceil(10_000_000).max(1);
+        let seconds = self.chunk_timeout_seconds.saturating_mul(scale);
+
+        // Larger durations than this can't be represented
+        TimeDelta::seconds(seconds.min(i64::MAX as u64 / 1000) as i64)
+    }
+
+    /// The local directory which fills up as files are uploaded. This is the
+    /// `file_dir` when storing files locally, or the `temp_dir` otherwise.
+    pub fn local_storage_dir(&self) -> &Path {
+        match self.storage {
+            StorageSettings::Local => &self.file_dir,
+            _ => &self.temp_dir,
+        }
+    }
+
     pub fn save(&self) -> Result<(), io::Error> {
         let out_path = &self.path.with_extension("new");
         let mut file = File::create(out_path)?;
@@ -90,15 +393,123 @@ impl Settings {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r Settings {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Settings::for_request(req))
+    }
+}
+
+/// The settings the server is running with, which can be replaced by reloading
+/// the settings file without restarting.
+///
+/// Only settings which can safely change while the server is running are
+/// reloaded, like limits, durations, and allowed types. Others, like where
+/// the server listens or where files are stored, keep the values the server
+/// was started with until it is restarted.
+pub struct SharedSettings(ArcSwap<Settings>);
+
+impl SharedSettings {
+    pub fn new(settings: Settings) -> Self {
+        Self(ArcSwap::from_pointee(settings))
+    }
+
+    /// The settings currently in use
+    pub fn load(&self) -> Arc<Settings> {
+        self.0.load_full()
+    }
+
+    /// Read the settings file again, and start using the settings in it which
+    /// can change while running. Returns the names of the settings which were
+    /// changed in the file but need a restart to take effect.
+    pub fn reload(&self, gate: &UploadGate) -> Result<Vec<&'static str>, io::Error> {
+        let current = self.load();
+        let mut new = Settings::open(&current.path)?;
+        new.validate()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        fn same<T: Serialize>(a: &T, b: &T) -> bool {
+            json::to_string(a).ok() == json::to_string(b).ok()
+        }
+
+        // Keep the current value of each setting which can't change while
+        // running, noting the ones which were changed in the file
+        let mut restart_needed = Vec::new();
+        macro_rules! keep_current {
+            ($($field:ident),* $(,)?) => {
+                $(
+                    if !same(&current.$field, &new.$field) {
+                        restart_needed.push(stringify!($field));
+                        new.$field = current.$field.clone();
+                    }
+                )*
+            };
+        }
+        keep_current!(
+            server,
+            tls,
+            database_path,
+            temp_dir,
+            file_dir,
+            storage,
+            encryption,
+            cors,
+            max_filesize,
+            chunk_size,
+            hash_algorithm,
+            cleanup_interval_seconds,
+            reconcile_on_cleanup,
+            shutdown_grace_seconds,
+        );
+
+        // Only follow the file if it changed, so switching read-only mode with
+        // the admin endpoint isn't undone by reloading for something else
+        if new.read_only != current.read_only {
+            gate.set_read_only(new.read_only);
+            info!("Read-only mode {}", if new.read_only { "enabled" } else { "disabled" });
+        }
+
+        self.0.store(Arc::new(new));
+
+        info!("Reloaded settings");
+        for name in &restart_needed {
+            warn!("Changes to {name} will only take effect after a restart");
+        }
+
+        Ok(restart_needed)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(crate = "rocket::serde")]
 pub struct ServerSettings {
     pub domain: String,
+
+    /// The address to listen on. This is either an IP address, or a Unix
+    /// domain socket like `unix:/run/confetti-box.sock` for use behind a
+    /// reverse proxy, which should send the client's address in the
+    /// `X-Real-IP` header, and `rate_limit.ip_header` should be set to it.
+    ///
+    /// Listening on `::` also accepts IPv4 connections on systems where IPv6
+    /// sockets are dual-stack by default, such as Linux.
     pub address: String,
     pub port: u16,
 
     /// The path to the root directory of the program, ex `/filehost/`
     pub root_path: String,
+
+    /// The name of the site, shown in page titles and on the home page in
+    /// place of "Confetti-Box"
+    pub site_title: Option<String>,
+
+    /// A line of text shown under the title on the home page
+    pub tagline: Option<String>,
+
+    /// HTML added to the footer of every page, like a link to terms of
+    /// service. This is inserted as is, so it must be trusted.
+    pub custom_footer_html: Option<String>,
 }
 
 impl Default for ServerSettings {
@@ -108,18 +519,80 @@ impl Default for ServerSettings {
             address: "127.0.0.1".into(),
             root_path: "/".into(),
             port: 8950,
+            site_title: None,
+            tagline: None,
+            custom_footer_html: None,
+        }
+    }
+}
+
+impl ServerSettings {
+    /// The name of the site, shown in page titles
+    pub fn site_title(&self) -> &str {
+        self.site_title.as_deref().unwrap_or("Confetti-Box")
+    }
+
+    /// The title of a page on the site, like `Confetti-Box | About`
+    pub fn page_title(&self, page: &str) -> String {
+        format!("{} | {page}", self.site_title())
+    }
+
+    /// An absolute path to something on the server, under the `root_path`
+    pub fn path(&self, path: &str) -> String {
+        format!("{}{path}", self.root_path.trim_end_matches('/'))
+    }
+
+    /// Where the server should listen, from its `address`
+    pub fn listen_address(&self) -> Result<ListenAddress, String> {
+        if let Some(path) = self.address.strip_prefix("unix:") {
+            if !cfg!(unix) {
+                return Err("Unix sockets are not supported on this platform".into());
+            }
+            if path.is_empty() {
+                return Err("The Unix socket path is empty".into());
+            }
+
+            return Ok(ListenAddress::Unix(path.into()));
         }
+
+        self.address.parse().map(ListenAddress::Ip).map_err(|_| {
+            format!(
+                "\"{}\" is not an IP address, or a Unix socket like \"unix:/path/to.sock\"",
+                self.address
+            )
+        })
     }
 }
 
+/// Where the server listens for connections
+pub enum ListenAddress {
+    Ip(IpAddr),
+    Unix(PathBuf),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TlsSettings {
+    /// The path to the PEM encoded certificate chain
+    pub cert_path: PathBuf,
+
+    /// The path to the PEM encoded private key for the certificate
+    pub key_path: PathBuf,
+}
+
 #[serde_as]
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct DurationSettings {
     /// Maximum file lifetime, seconds
     #[serde(default)]
     #[serde_as(as = "serde_with::DurationSeconds<i64>")]
     pub maximum: TimeDelta,
 
+    /// Minimum file lifetime, seconds. Files which expire sooner than this
+    /// would mostly just make work for the cleaner.
+    #[serde(default = "default_minimum_duration")]
+    #[serde_as(as = "serde_with::DurationSeconds<i64>")]
+    pub minimum: TimeDelta,
+
     /// Default file lifetime, seconds
     #[serde(default)]
     #[serde_as(as = "serde_with::DurationSeconds<i64>")]
@@ -139,6 +612,7 @@ impl Default for DurationSettings {
     fn default() -> Self {
         Self {
             maximum: TimeDelta::days(3),  // 72 hours
+            minimum: default_minimum_duration(),
             default: TimeDelta::hours(6), // 6 hours
             // 1 hour, 6 hours, 24 hours, and 48 hours
             allowed: vec![
@@ -151,3 +625,266 @@ impl Default for DurationSettings {
         }
     }
 }
+
+fn default_minimum_duration() -> TimeDelta {
+    TimeDelta::minutes(5)
+}
+
+impl DurationSettings {
+    /// The allowed duration closest to `duration`, to suggest when a duration
+    /// isn't allowed
+    pub fn closest_allowed(&self, duration: TimeDelta) -> Option<TimeDelta> {
+        self.allowed
+            .iter()
+            .min_by_key(|d| (**d - duration).abs())
+            .copied()
+    }
+
+    /// The durations to offer uploaders, and which one of them to select at
+    /// first. If the default isn't allowed, then the closest allowed duration
+    /// is selected instead, otherwise it is offered along with the others.
+    pub fn offered(&self) -> (Vec<TimeDelta>, TimeDelta) {
+        let mut offered = self.allowed.clone();
+        if offered.contains(&self.default) {
+            return (offered, self.default);
+        }
+
+        match self.closest_allowed(self.default) {
+            Some(closest) if self.restrict_to_allowed => (offered, closest),
+            _ => {
+                offered.push(self.default);
+                offered.sort();
+                (offered, self.default)
+            }
+        }
+    }
+}
+
+/// How to make room for an upload when storage is full
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+    /// Reject the upload
+    #[default]
+    Reject,
+
+    /// Remove the files which will expire soonest until the upload fits
+    EarliestExpiry,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    /// Enable rate limiting of the upload endpoints
+    pub enabled: bool,
+
+    /// Maximum number of upload requests (including individual chunks) a
+    /// single client IP may make per minute
+    pub requests_per_minute: u32,
+
+    /// Maximum number of uploads a single client IP may have in progress at
+    /// the same time
+    pub concurrent_uploads: usize,
+
+    /// A header which a trusted reverse proxy sets to the client's IP
+    /// address, like `X-Real-IP`. Clients can send any headers they like, so
+    /// without this they are limited by the address they connect from. When
+    /// behind a reverse proxy, including when listening on a Unix socket,
+    /// this has to be set, or every client shares the proxy's limits. If the
+    /// header has several addresses, like `X-Forwarded-For`, the last one is
+    /// used, which is the one added by the proxy.
+    pub ip_header: Option<String>,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            requests_per_minute: 600,
+            concurrent_uploads: 10,
+            ip_header: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct CorsSettings {
+    /// Origins which may use the API from a browser, like
+    /// `https://example.com`, or `*` for any origin. If this is empty, then
+    /// only pages on the same origin may use it.
+    pub allowed_origins: Vec<String>,
+
+    /// HTTP methods which other origins may use
+    pub allowed_methods: Vec<String>,
+
+    /// Request headers which other origins may send
+    pub allowed_headers: Vec<String>,
+
+    /// Allow other origins to send credentials, like the `Authorization`
+    /// header, with their requests
+    pub allow_credentials: bool,
+
+    /// How long browsers may cache the answer to a preflight request, in
+    /// seconds
+    pub max_age_seconds: u64,
+}
+
+impl Default for CorsSettings {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "POST", "PATCH", "DELETE"].map(String::from).to_vec(),
+            allowed_headers: ["Authorization", "Content-Type"].map(String::from).to_vec(),
+            allow_credentials: false,
+            max_age_seconds: 3600,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct AuthSettings {
+    /// Require authentication to download files and get file information, not
+    /// just to upload them
+    pub protect_downloads: bool,
+
+    /// Users which are allowed to log in
+    pub users: Vec<UserSettings>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UserSettings {
+    pub username: String,
+
+    /// The user's password hashed with Argon2, as a PHC string like
+    /// `$argon2id$v=19$m=19456,t=2,p=1$...`
+    pub password_hash: String,
+
+    /// Allow this user to access the administration endpoints
+    #[serde(default)]
+    pub admin: bool,
+}
+
+/// Where hosted files are stored
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageSettings {
+    /// Store files on the local disk, in the `file_dir`
+    #[default]
+    Local,
+
+    /// Store files in an S3 compatible object storage bucket
+    S3(S3Settings),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct RobotsSettings {
+    /// Ask search engines not to index hosted files. This serves a
+    /// `robots.txt` which disallows the paths in `disallow`, and sends
+    /// `X-Robots-Tag: noindex` with downloads and file information.
+    ///
+    /// Crawlers only look for `robots.txt` at the root of a domain, so when
+    /// the `root_path` isn't `/`, the reverse proxy has to serve it there.
+    pub noindex: bool,
+
+    /// Paths under the `root_path` which crawlers are asked not to visit
+    pub disallow: Vec<String>,
+}
+
+impl Default for RobotsSettings {
+    fn default() -> Self {
+        Self {
+            noindex: true,
+            disallow: vec!["/f/".into()],
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct RemoteUploadSettings {
+    /// Allow files to be uploaded by giving a URL for the server to fetch
+    pub enabled: bool,
+
+    /// How long fetching a file may take before it is given up on, in seconds
+    pub timeout_seconds: u64,
+
+    /// Networks which may be fetched from even though they are private, like
+    /// `10.1.0.0/16`. Loopback, private, and link-local addresses are never
+    /// fetched from otherwise, so the server can't be used to reach them.
+    pub allowed_networks: Vec<IpNet>,
+
+    /// Networks which may never be fetched from, like `203.0.113.0/24`
+    pub blocked_networks: Vec<IpNet>,
+}
+
+impl Default for RemoteUploadSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout_seconds: 300,
+            allowed_networks: Vec::new(),
+            blocked_networks: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct EncryptionSettings {
+    /// The base64 encoded 32 byte key to encrypt files with, like the output
+    /// of `openssl rand -base64 32`
+    pub key: Option<String>,
+
+    /// A file containing the key, instead of putting it in the settings
+    pub key_file: Option<PathBuf>,
+}
+
+impl EncryptionSettings {
+    /// Read the encryption key, from the `key_file` if `key` isn't set
+    pub fn load_key(&self) -> Result<[u8; 32], io::Error> {
+        let encoded = match (&self.key, &self.key_file) {
+            (Some(key), _) => key.clone(),
+            (None, Some(path)) => fs::read_to_string(path)?,
+            (None, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Either key or key_file must be set",
+                ))
+            }
+        };
+
+        BASE64_STANDARD
+            .decode(encoded.trim())
+            .ok()
+            .and_then(|k| k.try_into().ok())
+            .ok_or(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Key must be 32 bytes encoded as base64",
+            ))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct S3Settings {
+    /// Name of the bucket to store files in
+    pub bucket: String,
+
+    /// Region the bucket is in
+    pub region: String,
+
+    /// Custom endpoint URL, for S3 compatible services other than AWS
+    #[serde(default)]
+    pub endpoint: Option<String>,
+
+    pub access_key_id: String,
+    pub secret_access_key: String,
+
+    /// Use path style URLs (`endpoint/bucket/key`) instead of virtual hosted
+    /// style URLs (`bucket.endpoint/key`), required by some S3 compatible
+    /// services like MinIO
+    #[serde(default)]
+    pub force_path_style: bool,
+}
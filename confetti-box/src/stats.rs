@@ -0,0 +1,84 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use rocket::{
+    get,
+    serde::{json::Json, Serialize},
+    State,
+};
+
+use crate::{database::Mochibase, settings::Settings};
+
+/// Coarse numbers about the files hosted on the server, which are safe to
+/// show to anyone
+#[derive(Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Stats {
+    /// Number of files which can currently be downloaded
+    files: usize,
+    /// Total size of those files in bytes, counting identical files once
+    bytes: u64,
+    /// Number of those files which were uploaded in the last 24 hours
+    uploads_last_24h: usize,
+    /// When these numbers were worked out
+    updated: DateTime<Utc>,
+}
+
+impl Stats {
+    fn new(db: &Mochibase) -> Self {
+        let now = Utc::now();
+
+        let mut files = 0;
+        let mut bytes = 0;
+        let mut uploads_last_24h = 0;
+        let mut hashes = HashSet::new();
+        for entry in db.entries().filter(|e| !e.is_expired()) {
+            files += 1;
+            if hashes.insert(entry.hash()) {
+                bytes += entry.size();
+            }
+            if now - entry.upload_datetime() < TimeDelta::days(1) {
+                uploads_last_24h += 1;
+            }
+        }
+
+        Self {
+            files,
+            bytes,
+            uploads_last_24h,
+            updated: now,
+        }
+    }
+}
+
+/// The most recently worked out stats, so they aren't worked out again for
+/// every request
+#[derive(Default, Debug)]
+pub struct StatsCache(RwLock<Option<Stats>>);
+
+/// Get public stats about the files hosted on the server
+#[get("/stats")]
+pub fn stats(
+    settings: &Settings,
+    cache: &State<StatsCache>,
+    db: &State<Arc<RwLock<Mochibase>>>,
+) -> Option<Json<Stats>> {
+    if !settings.stats_enabled {
+        return None;
+    }
+
+    let max_age = TimeDelta::seconds(settings.stats_cache_seconds as i64);
+    if let Some(stats) = cache.0.read().unwrap().as_ref() {
+        if Utc::now() - stats.updated < max_age {
+            return Some(Json(stats.clone()));
+        }
+    }
+
+    let stats = Stats::new(&db.read().unwrap());
+    *cache.0.write().unwrap() = Some(stats.clone());
+
+    Some(Json(stats))
+}
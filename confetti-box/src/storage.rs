@@ -0,0 +1,230 @@
+use std::{
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+};
+
+use aws_sdk_s3::{
+    config::{BehaviorVersion, Credentials, Region},
+    error::DisplayErrorContext,
+    primitives::ByteStream,
+    Client,
+};
+use blake3::Hash;
+use rocket::tokio::{
+    fs::{self, File},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt},
+};
+
+use crate::{
+    encryption::EncryptedStorage,
+    settings::{S3Settings, Settings, StorageSettings},
+};
+
+/// A stream of the contents of a stored file
+pub type FileReader = Pin<Box<dyn AsyncRead + Send>>;
+
+/// Somewhere hosted files can be stored, keyed by their hash
+#[rocket::async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Move a completed upload from the local filesystem into storage. The
+    /// file at `source` no longer exists afterwards.
+    async fn put(&self, hash: &Hash, source: &Path) -> io::Result<()>;
+
+    /// Read a stored file, optionally only the given inclusive byte range
+    async fn get(&self, hash: &Hash, range: Option<(u64, u64)>) -> io::Result<FileReader>;
+
+    /// The size of a stored file in bytes
+    async fn size(&self, hash: &Hash) -> io::Result<u64>;
+
+    /// Remove a file from storage
+    async fn delete(&self, hash: &Hash) -> io::Result<()>;
+
+    /// Check whether a file is in storage
+    async fn exists(&self, hash: &Hash) -> io::Result<bool>;
+}
+
+/// Create the storage backend selected in the settings, encrypting files if
+/// encryption is enabled
+pub fn from_settings(settings: &Settings) -> Arc<dyn StorageBackend> {
+    let backend: Arc<dyn StorageBackend> = match &settings.storage {
+        StorageSettings::Local => Arc::new(LocalStorage::new(&settings.file_dir)),
+        StorageSettings::S3(s3) => Arc::new(S3Storage::new(s3)),
+    };
+
+    match &settings.encryption {
+        Some(encryption) => {
+            let key = encryption.load_key().expect("Failed to load encryption key");
+            Arc::new(EncryptedStorage::new(backend, key))
+        }
+        None => backend,
+    }
+}
+
+/// Stores files in a directory on the local disk
+pub struct LocalStorage {
+    file_dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new<P: AsRef<Path>>(file_dir: &P) -> Self {
+        Self {
+            file_dir: file_dir.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, hash: &Hash) -> PathBuf {
+        self.file_dir.join(hash.to_string())
+    }
+}
+
+#[rocket::async_trait]
+impl StorageBackend for LocalStorage {
+    async fn put(&self, hash: &Hash, source: &Path) -> io::Result<()> {
+        let dest = self.path(hash);
+
+        // Renaming fails if the temp directory is on another filesystem
+        if fs::rename(source, &dest).await.is_err() {
+            fs::copy(source, &dest).await?;
+            fs::remove_file(source).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn get(&self, hash: &Hash, range: Option<(u64, u64)>) -> io::Result<FileReader> {
+        let mut file = File::open(self.path(hash)).await?;
+
+        match range {
+            Some((start, end)) => {
+                file.seek(SeekFrom::Start(start)).await?;
+                Ok(Box::pin(file.take(end - start + 1)))
+            }
+            None => Ok(Box::pin(file)),
+        }
+    }
+
+    async fn size(&self, hash: &Hash) -> io::Result<u64> {
+        Ok(fs::metadata(self.path(hash)).await?.len())
+    }
+
+    async fn delete(&self, hash: &Hash) -> io::Result<()> {
+        fs::remove_file(self.path(hash)).await
+    }
+
+    async fn exists(&self, hash: &Hash) -> io::Result<bool> {
+        fs::try_exists(self.path(hash)).await
+    }
+}
+
+/// Stores files in an S3 compatible object storage bucket
+pub struct S3Storage {
+    client: Client,
+    bucket: String,
+}
+
+impl S3Storage {
+    pub fn new(settings: &S3Settings) -> Self {
+        let credentials = Credentials::new(
+            &settings.access_key_id,
+            &settings.secret_access_key,
+            None,
+            None,
+            "confetti-box",
+        );
+
+        let mut config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new(settings.region.clone()))
+            .credentials_provider(credentials)
+            .force_path_style(settings.force_path_style);
+
+        if let Some(endpoint) = &settings.endpoint {
+            config = config.endpoint_url(endpoint);
+        }
+
+        Self {
+            client: Client::from_conf(config.build()),
+            bucket: settings.bucket.clone(),
+        }
+    }
+}
+
+/// Convert an S3 error into an [`io::Error`], keeping the full error context
+fn s3_error<E: std::error::Error>(error: E) -> io::Error {
+    io::Error::other(DisplayErrorContext(error).to_string())
+}
+
+#[rocket::async_trait]
+impl StorageBackend for S3Storage {
+    async fn put(&self, hash: &Hash, source: &Path) -> io::Result<()> {
+        let body = ByteStream::from_path(source).await.map_err(s3_error)?;
+
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(hash.to_string())
+            .body(body)
+            .send()
+            .await
+            .map_err(s3_error)?;
+
+        fs::remove_file(source).await
+    }
+
+    async fn get(&self, hash: &Hash, range: Option<(u64, u64)>) -> io::Result<FileReader> {
+        let object = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(hash.to_string())
+            .set_range(range.map(|(start, end)| format!("bytes={start}-{end}")))
+            .send()
+            .await
+            .map_err(s3_error)?;
+
+        Ok(Box::pin(object.body.into_async_read()))
+    }
+
+    async fn size(&self, hash: &Hash) -> io::Result<u64> {
+        let head = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(hash.to_string())
+            .send()
+            .await
+            .map_err(s3_error)?;
+
+        Ok(head.content_length().unwrap_or_default().max(0) as u64)
+    }
+
+    async fn delete(&self, hash: &Hash) -> io::Result<()> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(hash.to_string())
+            .send()
+            .await
+            .map_err(s3_error)?;
+
+        Ok(())
+    }
+
+    async fn exists(&self, hash: &Hash) -> io::Result<bool> {
+        let result = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(hash.to_string())
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(false),
+            Err(e) => Err(s3_error(e)),
+        }
+    }
+}
@@ -2,78 +2,211 @@ use std::error::Error;
 
 use chrono::TimeDelta;
 
+/// Clean up a filename sent by a client, removing any leading directories and
+/// control characters and collapsing runs of whitespace. Names longer than
+/// `max_length` bytes are shortened, keeping the extension if possible.
+/// Returns [`None`] if there is no usable name left.
+pub fn sanitize_filename(name: &str, max_length: usize) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() || c.is_whitespace())
+        .collect();
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    let name = if name.len() > max_length {
+        match name.rsplit_once('.') {
+            // Only keep extensions which leave room for some of the name
+            Some((stem, extension)) if !stem.is_empty() && extension.len() < max_length / 2 => {
+                let stem = truncate(stem, max_length - extension.len() - 1).trim_end();
+                format!("{stem}.{extension}")
+            }
+            _ => truncate(&name, max_length).trim_end().to_string(),
+        }
+    } else {
+        name
+    };
+
+    if name.is_empty() || name == "." || name == ".." {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Shorten a string to at most `max_length` bytes, without splitting a
+/// character in half
+fn truncate(string: &str, max_length: usize) -> &str {
+    let mut end = max_length.min(string.len());
+    while !string.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    &string[..end]
+}
+
+/// Parse a duration string made up of one or more number and unit pairs, like
+/// `6h`, `90m`, or `1d12h`. Supported units are weeks (`w`), days (`d`), hours
+/// (`h`), minutes (`m`), and seconds (`s`).
 pub fn parse_time_string(string: &str) -> Result<TimeDelta, Box<dyn Error>> {
-    if string.len() > 7 {
+    if string.is_empty() {
         return Err("Not valid time string".into());
     }
 
-    let unit = string.chars().last();
-    let multiplier = if let Some(u) = unit {
-        if !u.is_ascii_alphabetic() {
-            return Err("Not valid time string".into());
+    let mut total = TimeDelta::zero();
+    let mut number = String::new();
+    for c in string.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
         }
 
-        match u {
+        let multiplier = match c {
+            'W' | 'w' => TimeDelta::weeks(1),
             'D' | 'd' => TimeDelta::days(1),
             'H' | 'h' => TimeDelta::hours(1),
             'M' | 'm' => TimeDelta::minutes(1),
             'S' | 's' => TimeDelta::seconds(1),
             _ => return Err("Not valid time string".into()),
-        }
-    } else {
-        return Err("Not valid time string".into());
-    };
+        };
 
-    let time = if let Ok(n) = string[..string.len() - 1].parse::<i32>() {
-        n
-    } else {
+        let time = match number.parse::<i32>() {
+            Ok(n) => n,
+            Err(_) => return Err("Not valid time string".into()),
+        };
+        number.clear();
+
+        total = multiplier
+            .checked_mul(time)
+            .and_then(|t| total.checked_add(&t))
+            .ok_or("Time string too large")?;
+    }
+
+    // A number with no unit after it
+    if !number.is_empty() {
         return Err("Not valid time string".into());
-    };
+    }
+
+    Ok(total)
+}
 
-    let final_time = multiplier * time;
+/// Units used when displaying durations, largest first
+const TIME_UNITS: [(u32, &str); 5] = [
+    (604800, "week"),
+    (86400, "day"),
+    (3600, "hour"),
+    (60, "minute"),
+    (1, "second"),
+];
 
-    Ok(final_time)
+fn pretty_unit(count: u32, unit: &str) -> String {
+    if count == 1 {
+        format!("{count}<br>{unit}")
+    } else {
+        format!("{count}<br>{unit}s")
+    }
 }
 
+/// Format a number of seconds as a human readable duration, like
+/// `1<br>day 6<br>hours`.
 pub fn to_pretty_time(seconds: u32) -> String {
-    let days = (seconds as f32 / 86400.0).floor();
-    let hour = ((seconds as f32 - (days * 86400.0)) / 3600.0).floor();
-    let mins = ((seconds as f32 - (hour * 3600.0) - (days * 86400.0)) / 60.0).floor();
-    let secs = seconds as f32 - (hour * 3600.0) - (mins * 60.0) - (days * 86400.0);
-
-    let days = if days == 0.0 {
-        "".to_string()
-    } else if days == 1.0 {
-        days.to_string() + "<br>day"
-    } else {
-        days.to_string() + "<br>days"
-    };
+    let mut remaining = seconds;
+    let mut parts = Vec::new();
+    for (size, unit) in TIME_UNITS {
+        let count = remaining / size;
+        remaining %= size;
 
-    let hour = if hour == 0.0 {
-        "".to_string()
-    } else if hour == 1.0 {
-        hour.to_string() + "<br>hour"
-    } else {
-        hour.to_string() + "<br>hours"
-    };
+        if count != 0 {
+            parts.push(pretty_unit(count, unit));
+        }
+    }
 
-    let mins = if mins == 0.0 {
-        "".to_string()
-    } else if mins == 1.0 {
-        mins.to_string() + "<br>minute"
-    } else {
-        mins.to_string() + "<br>minutes"
+    parts.join(" ")
+}
+
+/// Like [`to_pretty_time`], but only shows the single largest unit (rounded
+/// down), like `6<br>hours`.
+pub fn to_pretty_time_compact(seconds: u32) -> String {
+    TIME_UNITS
+        .iter()
+        .find(|(size, _)| seconds >= *size)
+        .map(|(size, unit)| pretty_unit(seconds / size, unit))
+        .unwrap_or_default()
+}
+
+/// Which kind of units to use when displaying a size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeUnits {
+    /// Powers of 1000, like kB and MB
+    Decimal,
+
+    /// Powers of 1024, like KiB and MiB
+    Binary,
+}
+
+/// Format a number of bytes as a human readable size, like `1.5 GB`. One
+/// decimal place is shown if the value is not a whole number.
+pub fn to_pretty_size(bytes: u64, units: SizeUnits) -> String {
+    let (base, names) = match units {
+        SizeUnits::Decimal => (1000.0, ["B", "kB", "MB", "GB", "TB", "PB", "EB"]),
+        SizeUnits::Binary => (1024.0, ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"]),
     };
 
-    let secs = if secs == 0.0 {
-        "".to_string()
-    } else if secs == 1.0 {
-        secs.to_string() + "<br>second"
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= base && unit < names.len() - 1 {
+        size /= base;
+        unit += 1;
+    }
+
+    let size = (size * 10.0).round() / 10.0;
+    if size.fract() == 0.0 {
+        format!("{size:.0} {}", names[unit])
     } else {
-        secs.to_string() + "<br>seconds"
-    };
+        format!("{size:.1} {}", names[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pretty_size_decimal() {
+        assert_eq!(to_pretty_size(0, SizeUnits::Decimal), "0 B");
+        assert_eq!(to_pretty_size(999, SizeUnits::Decimal), "999 B");
+        assert_eq!(to_pretty_size(1000, SizeUnits::Decimal), "1 kB");
+        assert_eq!(to_pretty_size(1023, SizeUnits::Decimal), "1 kB");
+        assert_eq!(to_pretty_size(1024, SizeUnits::Decimal), "1 kB");
+        assert_eq!(to_pretty_size(1_500_000_000, SizeUnits::Decimal), "1.5 GB");
+    }
 
-    (days + " " + &hour + " " + &mins + " " + &secs)
-        .trim()
-        .to_string()
+    #[test]
+    fn pretty_size_binary() {
+        assert_eq!(to_pretty_size(999, SizeUnits::Binary), "999 B");
+        assert_eq!(to_pretty_size(1000, SizeUnits::Binary), "1000 B");
+        assert_eq!(to_pretty_size(1023, SizeUnits::Binary), "1023 B");
+        assert_eq!(to_pretty_size(1024, SizeUnits::Binary), "1 KiB");
+        assert_eq!(to_pretty_size(1536, SizeUnits::Binary), "1.5 KiB");
+    }
+
+    #[test]
+    fn sanitize_filename_traversal() {
+        assert_eq!(sanitize_filename("../../etc/passwd", 255).as_deref(), Some("passwd"));
+        assert_eq!(sanitize_filename("..\\..\\windows\\win.ini", 255).as_deref(), Some("win.ini"));
+        assert_eq!(sanitize_filename("/etc/", 255), None);
+        assert_eq!(sanitize_filename("..", 255), None);
+        assert_eq!(sanitize_filename("uploads/..", 255), None);
+    }
+
+    #[test]
+    fn sanitize_filename_cleanup() {
+        assert_eq!(sanitize_filename("a\u{0}b\tc\n\nd.txt", 255).as_deref(), Some("ab c d.txt"));
+        assert_eq!(sanitize_filename("   ", 255), None);
+
+        let long = format!("{}.tar", "a".repeat(300));
+        let name = sanitize_filename(&long, 100).unwrap();
+        assert_eq!(name.len(), 100);
+        assert!(name.ends_with(".tar"));
+    }
 }
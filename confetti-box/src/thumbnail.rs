@@ -0,0 +1,176 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use blake3::Hash;
+use image::{codecs::jpeg::JpegEncoder, DynamicImage};
+use log::warn;
+use rocket::{
+    get,
+    http::ContentType,
+    tokio::{fs, io::AsyncReadExt, task},
+    State,
+};
+use uuid::Uuid;
+
+use crate::{
+    auth::DownloadAuth,
+    database::{Mmid, MochiFile, Mochibase},
+    endpoints::unlocked,
+    settings::Settings,
+    storage::StorageBackend,
+};
+
+/// The directory thumbnails are cached in
+pub fn thumbnail_dir(settings: &Settings) -> PathBuf {
+    settings.file_dir.join("thumbs")
+}
+
+fn thumbnail_path(thumb_dir: &Path, hash: &Hash) -> PathBuf {
+    thumb_dir.join(format!("{hash}.jpg"))
+}
+
+/// Remove the cached thumbnail for a file, if there is one
+pub async fn remove_thumbnail(thumb_dir: &Path, hash: &Hash) {
+    let path = thumbnail_path(thumb_dir, hash);
+    if fs::try_exists(&path).await.is_ok_and(|e| e) {
+        if let Err(e) = fs::remove_file(&path).await {
+            warn!("Failed to remove thumbnail: {}", e);
+        }
+    }
+}
+
+/// Get a small JPEG preview of an image or video
+#[get("/thumb/<mmid>?<password>")]
+pub async fn thumbnail(
+    db: &State<Arc<RwLock<Mochibase>>>,
+    storage: &State<Arc<dyn StorageBackend>>,
+    settings: &Settings,
+    user: DownloadAuth,
+    mmid: &str,
+    password: Option<&str>,
+) -> Option<(ContentType, Vec<u8>)> {
+    let mmid: Mmid = mmid.try_into().ok()?;
+    let entry = db.read().unwrap().get(&mmid).cloned()?;
+    if entry.is_expired() || !user.can_view(&entry) || !unlocked(&entry, password).await {
+        return None;
+    }
+
+    let thumb_dir = thumbnail_dir(settings);
+    let path = thumbnail_path(&thumb_dir, entry.hash());
+    if let Ok(cached) = fs::read(&path).await {
+        return Some((ContentType::JPEG, cached));
+    }
+
+    let image = decode(&entry, storage.inner().as_ref(), &settings.temp_dir).await?;
+    let size = settings.thumbnail_size;
+    let thumb = task::spawn_blocking(move || {
+        let thumb = image.thumbnail(size, size).into_rgb8();
+
+        let mut out = Vec::new();
+        JpegEncoder::new_with_quality(&mut out, 80)
+            .encode_image(&thumb)
+            .ok()?;
+        Some(out)
+    })
+    .await
+    .ok()??;
+
+    // Write to a temporary name first so a partially written thumbnail is
+    // never served to another request
+    let temp_path = thumb_dir.join(format!("{}.tmp", Uuid::new_v4()));
+    let cached = async {
+        fs::create_dir_all(&thumb_dir).await?;
+        fs::write(&temp_path, &thumb).await?;
+        fs::rename(&temp_path, &path).await
+    };
+    if let Err(e) = cached.await {
+        warn!("Failed to cache thumbnail: {}", e);
+        let _ = fs::remove_file(&temp_path).await;
+    }
+
+    Some((ContentType::JPEG, thumb))
+}
+
+/// Decode the first frame of a stored file, if it is a supported image or video
+async fn decode(
+    entry: &MochiFile,
+    storage: &dyn StorageBackend,
+    temp_dir: &Path,
+) -> Option<DynamicImage> {
+    let mime = entry.mime_type();
+    if mime.starts_with("image/") {
+        let mut data = Vec::new();
+        storage
+            .get(entry.hash(), None)
+            .await
+            .ok()?
+            .read_to_end(&mut data)
+            .await
+            .ok()?;
+
+        return task::spawn_blocking(move || image::load_from_memory(&data).ok())
+            .await
+            .ok()?;
+    }
+
+    if mime.starts_with("video/") {
+        return video_frame(entry, storage, temp_dir).await;
+    }
+
+    None
+}
+
+/// Grab the first frame of a video using `ffmpeg`
+#[cfg(feature = "ffmpeg")]
+async fn video_frame(
+    entry: &MochiFile,
+    storage: &dyn StorageBackend,
+    temp_dir: &Path,
+) -> Option<DynamicImage> {
+    use std::process::{Command, Stdio};
+
+    // The video may not be on the local disk, so make a copy ffmpeg can read
+    let source = temp_dir.join(Uuid::new_v4().to_string());
+    let mut reader = storage.get(entry.hash(), None).await.ok()?;
+    let mut file = fs::File::create(&source).await.ok()?;
+    let copied = rocket::tokio::io::copy(&mut reader, &mut file).await;
+    drop(file);
+
+    let frame = match copied {
+        Ok(_) => {
+            let source = source.clone();
+            task::spawn_blocking(move || {
+                let output = Command::new("ffmpeg")
+                    .args(["-v", "error", "-i"])
+                    .arg(&source)
+                    .args(["-frames:v", "1", "-f", "image2pipe", "-vcodec", "png", "-"])
+                    .stdin(Stdio::null())
+                    .output()
+                    .ok()?;
+
+                if !output.status.success() {
+                    return None;
+                }
+                image::load_from_memory(&output.stdout).ok()
+            })
+            .await
+            .ok()
+            .flatten()
+        }
+        Err(_) => None,
+    };
+
+    let _ = fs::remove_file(&source).await;
+    frame
+}
+
+#[cfg(not(feature = "ffmpeg"))]
+async fn video_frame(
+    _entry: &MochiFile,
+    _storage: &dyn StorageBackend,
+    _temp_dir: &Path,
+) -> Option<DynamicImage> {
+    None
+}
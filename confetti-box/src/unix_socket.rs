@@ -0,0 +1,76 @@
+use std::{
+    fs,
+    net::Ipv4Addr,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+};
+
+use log::{error, info};
+use rocket::{
+    fairing::AdHoc,
+    tokio::{
+        self, io,
+        net::{TcpStream, UnixListener},
+        select,
+    },
+    Shutdown,
+};
+
+/// Serves the server on a Unix socket. Rocket can only listen on TCP, so each
+/// connection to the socket is passed along to the port Rocket is listening
+/// on, which should only be reachable locally.
+pub struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocket {
+    /// Start listening on a Unix socket, replacing one left behind by a
+    /// previous run of the server
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+
+        Ok(Self {
+            listener: UnixListener::bind(path)?,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// A fairing which starts passing along connections once Rocket has
+    /// launched, and stops when it shuts down
+    pub fn fairing(self) -> AdHoc {
+        AdHoc::on_liftoff("Unix Socket", |rocket| {
+            let (port, shutdown) = (rocket.config().port, rocket.shutdown());
+            Box::pin(async move {
+                tokio::spawn(self.forward(port, shutdown));
+            })
+        })
+    }
+
+    async fn forward(self, port: u16, shutdown: Shutdown) {
+        info!("Listening on Unix socket {}", self.path.display());
+        loop {
+            select! {
+                connection = self.listener.accept() => {
+                    let Ok((mut client, _)) = connection else {
+                        continue;
+                    };
+
+                    tokio::spawn(async move {
+                        match TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await {
+                            Ok(mut server) => {
+                                let _ = io::copy_bidirectional(&mut client, &mut server).await;
+                            }
+                            Err(e) => error!("Failed to pass along Unix socket connection: {e}"),
+                        }
+                    });
+                }
+                _ = shutdown.clone() => break,
+            }
+        }
+
+        let _ = fs::remove_file(&self.path);
+    }
+}
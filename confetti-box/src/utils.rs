@@ -1,10 +1,171 @@
 use blake3::Hash;
-use std::path::Path;
+use rocket::{
+    serde::{Deserialize, Serialize},
+    tokio::{
+        sync::mpsc,
+        task::{self, JoinHandle},
+    },
+};
+use sha2::{Digest, Sha256};
+use std::{fmt, fs::File, io, path::Path};
 
-/// Get the Blake3 hash of a file, without reading it all into memory
-pub async fn hash_file<P: AsRef<Path>>(input: &P) -> Result<Hash, std::io::Error> {
-    let mut hasher = blake3::Hasher::new();
-    hasher.update_mmap_rayon(input)?;
+use crate::settings::Settings;
 
-    Ok(hasher.finalize())
+/// The algorithm used to hash uploaded files, which also decides where they
+/// are kept in storage.
+///
+/// Both algorithms give a 32 byte digest, so either one is held in a
+/// [`blake3::Hash`], which compares in constant time and formats as hex.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum HashAlgorithm {
+    #[default]
+    Blake3,
+    Sha256,
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Blake3 => write!(f, "blake3"),
+            Self::Sha256 => write!(f, "sha256"),
+        }
+    }
+}
+
+/// A running hash using one of the [`HashAlgorithm`]s
+#[derive(Debug, Clone)]
+pub enum FileHasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
+}
+
+impl FileHasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Blake3 => Self::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Sha256 => Self::Sha256(Sha256::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Blake3(h) => {
+                h.update(data);
+            }
+            Self::Sha256(h) => h.update(data),
+        }
+    }
+
+    /// Get the hash of everything added so far
+    pub fn finalize(&self) -> Hash {
+        match self {
+            Self::Blake3(h) => h.finalize(),
+            Self::Sha256(h) => Hash::from_bytes(h.clone().finalize().into()),
+        }
+    }
+}
+
+/// Get the hash of a file, without reading it all into memory
+pub async fn hash_file<P: AsRef<Path>>(input: &P, algorithm: HashAlgorithm) -> Result<Hash, io::Error> {
+    match algorithm {
+        HashAlgorithm::Blake3 => {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update_mmap_rayon(input)?;
+
+            Ok(hasher.finalize())
+        }
+        HashAlgorithm::Sha256 => {
+            let path = input.as_ref().to_path_buf();
+            task::spawn_blocking(move || {
+                let mut hasher = Sha256::new();
+                io::copy(&mut File::open(path)?, &mut hasher)?;
+
+                Ok(Hash::from_bytes(hasher.finalize().into()))
+            })
+            .await
+            .map_err(io::Error::other)?
+        }
+    }
+}
+
+/// A hasher which runs on a blocking thread, so hashing a file as it arrives
+/// doesn't hold up the async tasks handling requests
+pub struct BackgroundHasher {
+    sender: mpsc::Sender<Vec<u8>>,
+    handle: JoinHandle<Hash>,
+}
+
+impl BackgroundHasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        // Bounded, so a slow hasher slows down the upload rather than
+        // buffering the whole file in memory
+        let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(16);
+        let handle = task::spawn_blocking(move || {
+            let mut hasher = FileHasher::new(algorithm);
+            while let Some(data) = receiver.blocking_recv() {
+                hasher.update(&data);
+            }
+
+            hasher.finalize()
+        });
+
+        Self { sender, handle }
+    }
+
+    /// Add the next part of the data to the hash
+    pub async fn update(&self, data: Vec<u8>) -> Result<(), io::Error> {
+        self.sender
+            .send(data)
+            .await
+            .map_err(|_| io::Error::other("Hashing thread stopped"))
+    }
+
+    /// Wait for all of the data to be hashed, and get the hash
+    pub async fn finalize(self) -> Result<Hash, io::Error> {
+        drop(self.sender);
+        self.handle.await.map_err(io::Error::other)
+    }
+}
+
+/// Check whether there is enough disk space to upload a file of `size` bytes
+/// while still leaving `min_free_space` bytes free, both in the temporary
+/// directory and where the file will be stored.
+pub fn has_space_for(settings: &Settings, size: u64) -> bool {
+    let needed = size.saturating_add(settings.min_free_space);
+
+    [settings.temp_dir.as_path(), settings.local_storage_dir()]
+        .iter()
+        .all(|dir| fs4::available_space(dir).is_ok_and(|free| free >= needed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rocket::async_test]
+    async fn running_hash_matches_file_hash() {
+        let path = std::env::temp_dir().join(format!("confetti-hash-{}", uuid::Uuid::new_v4()));
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        for algorithm in [HashAlgorithm::Blake3, HashAlgorithm::Sha256] {
+            // Uneven pieces, like chunks which don't line up with the hash's blocks
+            let mut hasher = FileHasher::new(algorithm);
+            for piece in data.chunks(777) {
+                hasher.update(piece);
+            }
+
+            let background = BackgroundHasher::new(algorithm);
+            for piece in data.chunks(4096) {
+                background.update(piece.to_vec()).await.unwrap();
+            }
+
+            let expected = hash_file(&path, algorithm).await.unwrap();
+            assert_eq!(hasher.finalize(), expected, "{algorithm} running hash differs");
+            assert_eq!(background.finalize().await.unwrap(), expected, "{algorithm} background hash differs");
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
 }
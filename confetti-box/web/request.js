@@ -4,6 +4,12 @@ const TOO_LARGE_TEXT = "Too large!";
 const ZERO_TEXT = "File is blank!";
 const ERROR_TEXT = "Error!";
 
+// The path the server is running under, like "/filehost", or "" at the root
+const ROOT_PATH = document.currentScript.dataset.rootPath ?? "";
+
+// The largest websocket message the server accepts
+const MAX_FRAME_SIZE = parseInt(document.currentScript.dataset.maxFrameSize ?? "10000000");
+
 async function formSubmit() {
     const form = document.getElementById("uploadForm");
     const files = form.elements.fileUpload.files;
@@ -121,7 +127,7 @@ async function uploadFileChunked(file, duration, maxSize) {
     // Get preliminary upload information
     let chunkedResponse;
     try {
-        const response = await fetch("/upload/chunked", {
+        const response = await fetch(ROOT_PATH + "/upload/chunked", {
             method: "POST",
             body: JSON.stringify({
                 "name": file.name,
@@ -145,7 +151,7 @@ async function uploadFileChunked(file, duration, maxSize) {
     for (let chunk_num = 0; chunk_num < Math.floor(file.size / chunkedResponse.chunk_size) + 1; chunk_num ++) {
         const offset = Math.floor(chunk_num * chunkedResponse.chunk_size);
         const chunk = file.slice(offset, offset + chunkedResponse.chunk_size);
-        const url = "/upload/chunked/" + chunkedResponse.uuid + "?chunk=" + chunk_num;
+        const url = ROOT_PATH + "/upload/chunked/" + chunkedResponse.uuid + "?chunk=" + chunk_num;
         const ID = progressValues.push(0);
 
         let upload = new Promise(function (resolve, reject) {
@@ -177,7 +183,7 @@ async function uploadFileChunked(file, duration, maxSize) {
     await Promise.allSettled(chunkUploads);
 
     // Finish the request and update the progress box
-    const result = await fetch("/upload/chunked/" + chunkedResponse.uuid + "?finish");
+    const result = await fetch(ROOT_PATH + "/upload/chunked/" + chunkedResponse.uuid + "?finish");
     let responseJson = null;
     if (result.status == 200) {
         responseJson = await result.json()
@@ -206,10 +212,10 @@ async function uploadFileWebsocket(file, duration, maxSize) {
         new_uri = "ws:";
     }
     new_uri += "//" + loc.host;
-    new_uri += "/upload/websocket?name=" + file.name +"&size=" + file.size + "&duration=" + parseInt(duration);
+    new_uri += ROOT_PATH + "/upload/websocket?name=" + file.name +"&size=" + file.size + "&duration=" + parseInt(duration);
     const socket = new WebSocket(new_uri);
 
-    const chunkSize = 10_000_000;
+    const chunkSize = Math.min(10_000_000, MAX_FRAME_SIZE);
     socket.addEventListener("open", (_event) => {
         for (let chunk_num = 0; chunk_num < Math.floor(file.size / chunkSize) + 1; chunk_num ++) {
             const offset = Math.floor(chunk_num * chunkSize);
@@ -224,7 +230,12 @@ async function uploadFileWebsocket(file, duration, maxSize) {
     return new Promise(function(resolve, reject) {
         socket.addEventListener("message", (event) => {
             const response = JSON.parse(event.data);
-            if (response.mmid == null) {
+            if (response.error != null) {
+                console.error("Upload failed", response);
+                const errorText = response.error === "too_large" ? TOO_LARGE_TEXT : ERROR_TEXT;
+                makeErrored(progressBar, progressText, linkRow, errorText);
+                resolve();
+            } else if (response.mmid == null) {
                 const progress = parseInt(response);
                 uploadProgressWebsocket(progress, progressBar, progressText, file.size);
             } else {
@@ -304,7 +315,7 @@ function makeFinished(progressBar, progressText, linkRow, response) {
     progressText.textContent = "";
     const link = progressText.appendChild(document.createElement("a"));
     link.textContent = response.mmid;
-    link.href = "/f/" + response.mmid;
+    link.href = ROOT_PATH + "/f/" + response.mmid;
     link.target = "_blank";
 
     let button = linkRow.appendChild(document.createElement("button"));
@@ -316,7 +327,7 @@ function makeFinished(progressBar, progressText, linkRow, response) {
             clearTimeout(buttonTimeout);
         }
         navigator.clipboard.writeText(
-                window.location.protocol + "//" + window.location.host + "/f/" + mmid
+                window.location.protocol + "//" + window.location.host + ROOT_PATH + "/f/" + mmid
         );
         button.textContent = "✅";
         buttonTimeout = setTimeout(function() {
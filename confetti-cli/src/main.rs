@@ -1,16 +1,18 @@
-use std::{error::Error, fs, io::{self, Read, Write}, os::unix::fs::MetadataExt, path::{Path, PathBuf}};
+use std::{cmp, collections::{BTreeMap, HashMap}, error::Error, fs, io::{self, IsTerminal, Read, Write}, ops::RangeInclusive, os::unix::fs::MetadataExt, path::{Path, PathBuf}};
 
 use chrono::{DateTime, Datelike, Local, Month, TimeDelta, Timelike, Utc};
 
 use indicatif::{ProgressBar, ProgressStyle};
 use owo_colors::OwoColorize;
-use reqwest::Client;
+use qrcode::{render::unicode, QrCode};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
-use tokio::{fs::{create_dir, File}, io::{AsyncReadExt, AsyncWriteExt}, task::JoinSet};
+use tokio::{fs::File, io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt}, task::JoinSet};
 use uuid::Uuid;
-use clap::{arg, builder::{styling::RgbColor, Styles}, Parser, Subcommand};
-use anyhow::{anyhow, bail, Context as _, Result};
+use clap::{builder::{styling::RgbColor, Styles}, Parser, Subcommand, ValueEnum};
+use anyhow::{anyhow, bail, Result};
 
 const CLAP_STYLE: Styles = Styles::styled()
     .header(RgbColor::on_default(RgbColor(197,229,207)).italic())
@@ -21,6 +23,12 @@ const CLAP_STYLE: Styles = Styles::styled()
 
 const DEBUG_CONFIG: &str = "test/config.toml";
 const DEBUG_DOWNLOAD_DIR: &str = "test/downloads/";
+const DEFAULT_HISTORY_LENGTH: usize = 50;
+const DEFAULT_REQUEST_ATTEMPTS: u32 = 4;
+const DEFAULT_PROFILE: &str = "default";
+
+/// The lengths of MMIDs the server accepts
+const MMID_LENGTHS: RangeInclusive<usize> = 4..=32;
 
 #[derive(Parser)]
 #[command(name = "confetti_cli")]
@@ -29,6 +37,22 @@ const DEBUG_DOWNLOAD_DIR: &str = "test/downloads/";
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Use the server profile with this name instead of the current one
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    /// Connect to this server for this run only, without saving it
+    #[arg(long, value_name = "url", global = true)]
+    server: Option<String>,
+
+    /// Log in with this username for this run only, without saving it
+    #[arg(long, global = true)]
+    username: Option<String>,
+
+    /// Log in with this password for this run only, without saving it
+    #[arg(long, global = true)]
+    password: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -36,13 +60,25 @@ enum Commands {
     /// Upload files
     #[command(visible_alias="u")]
     Upload {
-        /// Filename(s) to upload
+        /// Filename(s) to upload, or `-` to upload from stdin
         #[arg(value_name = "file(s)", required = true)]
         files: Vec<PathBuf>,
 
-        /// Expiration length of the uploaded file
+        /// Expiration length of the uploaded file, like `6h` or `1d12h`
         #[arg(short, long, default_value = "6h")]
         duration: String,
+
+        /// The name to give a file uploaded from stdin
+        #[arg(short, long)]
+        name: Option<String>,
+
+        /// Copy the URLs of the uploaded files to the clipboard
+        #[arg(short, long)]
+        copy: bool,
+
+        /// Show a QR code of each uploaded file's URL
+        #[arg(long)]
+        qr: bool,
     },
 
     /// Set config options
@@ -59,10 +95,31 @@ enum Commands {
         /// Set the directory to download into by default
         #[arg(value_name="directory", short_alias='d', long, required = false)]
         dl_dir: Option<String>,
+        /// Set how many recent uploads to remember
+        #[arg(value_name="length", long, required = false)]
+        history_length: Option<usize>,
+        /// Set whether to always copy the URLs of uploaded files to the clipboard
+        #[arg(value_name="bool", long, required = false)]
+        copy_urls: Option<bool>,
+        /// Set how many times to try requests to the server before giving up
+        #[arg(value_name="attempts", long, required = false)]
+        request_attempts: Option<u32>,
     },
 
-    /// Get server information manually
-    Info,
+    /// Manage profiles for connecting to different servers
+    #[command(subcommand)]
+    Profile(ProfileCommand),
+
+    /// Show recently uploaded files
+    #[command(visible_alias="h")]
+    History,
+
+    /// Get server information manually, or information about a file
+    Info {
+        /// MMID or link of the file to show information about
+        #[arg(value_name = "mmid")]
+        mmid: Option<String>,
+    },
 
     /// Download files
     #[command(visible_alias="d")]
@@ -71,8 +128,67 @@ enum Commands {
         #[arg(value_name = "mmid(s)", required = true)]
         mmids: Vec<String>,
         #[arg(short, long, value_name = "out", required = false)]
-        out_directory: Option<PathBuf>
+        out_directory: Option<PathBuf>,
+        /// Don't check that the downloaded files match their hash
+        #[arg(long)]
+        no_verify: bool,
+        /// What to do when a file with the same name is already downloaded
+        #[arg(long, value_enum, default_value_t = OnConflict::Rename)]
+        on_conflict: OnConflict,
+    },
+
+    /// Delete files early, using the deletion tokens saved when they were uploaded
+    Delete {
+        /// MMID(s) to delete
+        #[arg(value_name = "mmid(s)", required = true)]
+        mmids: Vec<String>,
+    },
+
+    /// List files stored on the server, which requires an admin login
+    #[command(visible_alias="l")]
+    List {
+        /// The order to list files in
+        #[arg(short, long, value_enum, default_value_t = ListSort::Upload)]
+        sort: ListSort,
+
+        /// The maximum number of files to list
+        #[arg(short, long, default_value_t = 50)]
+        limit: usize,
+    },
+}
+
+#[derive(Subcommand)]
+enum ProfileCommand {
+    /// List all profiles
+    List,
+
+    /// Create a new profile, which can then be configured with `--profile <name> set`
+    Create {
+        name: String,
     },
+
+    /// Switch the profile which is used by default
+    Switch {
+        name: String,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OnConflict {
+    /// Add a number to the name, like `name (1).ext`
+    Rename,
+    /// Replace the existing file
+    Overwrite,
+    /// Don't download the file
+    Skip,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ListSort {
+    /// Most recently uploaded first
+    Upload,
+    /// Soonest to expire first
+    Expiry,
 }
 
 #[tokio::main]
@@ -80,11 +196,34 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
     let mut config = Config::open().unwrap();
 
+    // Profiles are created by setting options for them
+    if let Some(name) = &cli.profile {
+        if !config.profiles.contains_key(name) && !matches!(cli.command, Commands::Set { .. }) {
+            exit_error(
+                format!("Profile {} does not exist", name.truecolor(234, 129, 100)),
+                Some(format!("Please create it using the {} command", "profile create".truecolor(246,199,219).bold())),
+                None,
+            );
+        }
+        config.selected = Some(name.clone());
+    }
+
+    if cli.server.is_some() || cli.username.is_some() || cli.password.is_some() {
+        if matches!(cli.command, Commands::Set { .. } | Commands::Profile(_)) {
+            exit_error(
+                "Options for a single run can't be changed with this command".to_string(),
+                Some(format!("Please use {} without --server, --username or --password", "set".truecolor(246,199,219).bold())),
+                None,
+            );
+        }
+        config.override_profile(cli.server.as_deref(), cli.username.as_deref(), cli.password.as_deref());
+    }
+
     match &cli.command {
-        Commands::Upload { files, duration } => {
-            if config.url.is_empty() {
+        Commands::Upload { files, duration, name, copy, qr } => {
+            if config.profile().url.is_empty() {
                 exit_error(
-                    format!("URL is empty"),
+                    "URL is empty".to_string(),
                     Some(format!("Please set it using the {} command", "set".truecolor(246,199,219).bold())),
                     None,
                 );
@@ -92,64 +231,146 @@ async fn main() -> Result<()> {
 
             get_info_if_expired(&mut config).await?;
 
+            // Files are always uploaded in chunks
+            if config.profile().info.as_ref().unwrap().supports_chunked == Some(false) {
+                exit_error(
+                    "This server does not accept chunked uploads".to_string(),
+                    Some("Please upload files through its website instead".to_string()),
+                    None,
+                );
+            }
+
             let client = Client::new();
-            let duration = match parse_time_string(&duration) {
+            let duration = match parse_time_string(duration) {
                 Ok(d) => d,
                 Err(e) => return Err(anyhow!("Invalid duration: {e}")),
             };
 
-            if !config.info.as_ref().unwrap().allowed_durations.contains(&duration.num_seconds()) {
-                let pretty_durations: Vec<String> = config.info.as_ref()
-                    .unwrap()
-                    .allowed_durations
-                    .clone()
+            let info = config.profile().info.as_ref().unwrap();
+            if let Some(min_duration) = info.min_duration.filter(|m| duration.num_seconds() < *m) {
+                exit_error(
+                    "Duration too short.".to_string(),
+                    Some(format!("Files must be kept for at least {}", pretty_time_short(min_duration))),
+                    None,
+                );
+            }
+
+            let allowed_durations = &info.allowed_durations;
+            if !allowed_durations.contains(&duration.num_seconds()) {
+                let pretty_durations: Vec<String> = allowed_durations
                     .iter()
                     .map(|d| pretty_time_short(*d))
                     .collect();
 
+                // Suggest whichever allowed duration is closest to the one given
+                let message = match allowed_durations
+                    .iter()
+                    .min_by_key(|d| (**d - duration.num_seconds()).abs())
+                {
+                    Some(d) => format!("Duration not allowed. Did you mean {}?", pretty_time_short(*d)),
+                    None => "Duration not allowed.".to_string(),
+                };
+
                 exit_error(
-                    format!("Duration not allowed."),
-                    Some(format!("Please choose from:")),
+                    message,
+                    Some("Please choose from:".to_string()),
                     Some(pretty_durations)
                 );
             }
 
             println!("Uploading...");
+            let mut urls = Vec::new();
             for path in files {
-                if !path.try_exists().is_ok_and(|t| t) {
-                    print_error_line(format!("The file {:#?} does not exist", path.truecolor(234, 129, 100)));
-                    continue;
-                }
+                // The size of an upload has to be known before it starts, so
+                // stdin is read into a temporary file first
+                let from_stdin = path.as_os_str() == "-";
+                let (path, name) = if from_stdin {
+                    let temp_path = std::env::temp_dir().join(format!("confetti-stdin-{}", Uuid::new_v4()));
+                    if let Err(e) = read_stdin(&temp_path).await {
+                        let _ = fs::remove_file(&temp_path);
+                        print_error_line(format!("Failed to read stdin: {e}"));
+                        continue;
+                    }
+
+                    let name = name.clone().unwrap_or_else(|| {
+                        format!("stdin-{}.bin", Local::now().format("%Y%m%d-%H%M%S"))
+                    });
+                    (temp_path, name)
+                } else {
+                    if !path.try_exists().is_ok_and(|t| t) {
+                        print_error_line(format!("The file {:#?} does not exist", path.truecolor(234, 129, 100)));
+                        continue;
+                    }
+
+                    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                    (path.clone(), name)
+                };
 
-                let name = path.file_name().unwrap().to_string_lossy();
-                let response = upload_file(
-                    name.into_owned(),
+                let result = upload_file(
+                    name,
                     &path,
                     &client,
-                    &config.url,
+                    &mut config,
                     duration,
-                    &config.login
-                ).await.with_context(|| "Failed to upload").unwrap();
-
-                let datetime: DateTime<Local> = DateTime::from(response.expiry_datetime);
-                let date = format!(
-                    "{} {}",
-                    Month::try_from(u8::try_from(datetime.month()).unwrap()).unwrap().name(),
-                    datetime.day(),
-                );
-                let time = format!("{:02}:{:02}", datetime.hour(), datetime.minute());
+                ).await;
+                if from_stdin {
+                    let _ = fs::remove_file(&path);
+                }
+
+                let response = match result {
+                    Ok(r) => r,
+                    Err(e) => {
+                        let path = if from_stdin { Path::new("stdin") } else { &path };
+                        print_error_line(format!("Failed to upload {:#?}: {e}", path.truecolor(234, 129, 100)));
+                        continue;
+                    }
+                };
+
+                // Remember the token so the file can be deleted later
+                if !response.delete_token.is_empty() {
+                    config.delete_tokens.insert(response.mmid.clone(), response.delete_token.clone());
+                }
+                config.history.push(HistoryEntry {
+                    mmid: response.mmid.clone(),
+                    name: response.name.clone(),
+                    url: config.profile().url.clone() + "/f/" + &response.mmid.0,
+                    expiry_datetime: response.expiry_datetime,
+                    delete_token: response.delete_token.clone(),
+                });
+                config.trim_history();
+                config.save().unwrap();
+
+                let url = config.profile().url.clone() + "/f/" + &response.mmid.0;
+                if response.deduplicated {
+                    println!("{:>8} An identical file was already on the server", "Note:".truecolor(174,196,223).bold());
+                }
                 println!(
-                    "{:>8} {}, {} (in {})\n{:>8} {}",
-                    "Expires:".truecolor(174,196,223).bold(), date, time, pretty_time_long(duration.num_seconds()),
-                    "URL:".truecolor(174,196,223).bold(), (config.url.clone() + "/f/" + &response.mmid.0).underline()
+                    "{:>8} {} (in {})\n{:>8} {}",
+                    "Expires:".truecolor(174,196,223).bold(), pretty_date(response.expiry_datetime), pretty_time_long(duration.num_seconds()),
+                    "URL:".truecolor(174,196,223).bold(), url.underline()
                 );
+
+                // A QR code is only useful if it's going to be looked at
+                if *qr && io::stdout().is_terminal() {
+                    print_qr_code(&url, &response.name);
+                }
+                urls.push(url);
+            }
+
+            if (*copy || config.copy_urls) && !urls.is_empty() {
+                // Headless systems often have no clipboard, which isn't worth
+                // failing over since the URLs were printed anyway
+                match arboard::Clipboard::new().and_then(|mut c| c.set_text(urls.join("\n"))) {
+                    Ok(()) => println!("{:>8} Copied to clipboard", "Note:".truecolor(174,196,223).bold()),
+                    Err(e) => print_warning_line(format!("Could not copy to the clipboard: {e}")),
+                }
             }
         }
-        Commands::Download { mmids, out_directory } => {
+        Commands::Download { mmids, out_directory, no_verify, on_conflict } => {
             let out_directory = if let Some(dir) = out_directory {
                 dir
             } else {
-                let ddir = &config.download_directory;
+                let ddir = &config.profile().download_directory;
                 if ddir.as_os_str().is_empty() {
                     exit_error(
                         "Default download directory is empty".into(),
@@ -167,108 +388,174 @@ async fn main() -> Result<()> {
                 }
             };
 
-            let url = &config.url;
+            let url = &config.profile().url;
+            let client = Client::new();
+            let mut failed = false;
             for mmid in mmids {
-                let mmid = if mmid.len() != 8 {
-                    if mmid.contains(format!("{url}/f/").as_str()) {
-                        let mmid = mmid.replace(format!("{url}/f/").as_str(), "");
-                        if mmid.len() != 8 {
-                            exit_error("{mmid} is not a valid MMID".into(), Some("MMID must be 8 characters long".into()), None)
-                        } else {
-                            mmid
-                        }
-                    } else {
-                        exit_error("{mmid} is not a valid MMID".into(), Some("MMID must be 8 characters long".into()), None)
+                let mmid = match parse_mmid(mmid) {
+                    Some(m) => m.0,
+                    None => {
+                        print_error_line(format!(
+                            "{mmid} is not a valid MMID, it must be {} to {} letters or numbers, or a link to a file",
+                            MMID_LENGTHS.start(),
+                            MMID_LENGTHS.end(),
+                        ));
+                        failed = true;
+                        continue;
                     }
-                } else {
-                    unimplemented!();
                 };
 
-                let client = Client::new();
+                let request = |path: String| {
+                    let request = client.get(format!("{url}{path}"));
+                    match &config.profile().login {
+                        Some(login) => request.basic_auth(&login.user, Some(&login.pass)),
+                        None => request,
+                    }
+                };
 
-                let info = if let Ok(file) = if let Some(login) = &config.login {
-                    client.get(format!("{}/info/{mmid}", url))
-                    .basic_auth(&login.user, Some(&login.pass))
-                } else {
-                    client.get(format!("{}/info/{mmid}", url))
-                }
-                .send()
-                .await
-                .unwrap()
-                .json::<MochiFile>()
-                .await {
-                    file
-                } else {
-                    exit_error(format!("File with MMID {mmid} was not found"), None, None)
+                let info = match get_file_info(&config, &client, &mmid).await {
+                    Ok(i) => i,
+                    Err(message) => {
+                        print_error_line(message);
+                        failed = true;
+                        continue;
+                    }
                 };
 
-                let mut file_res = if let Some(login) = &config.login {
-                    client.get(format!("{}/f/{mmid}", config.url))
-                    .basic_auth(&login.user, Some(&login.pass))
-                } else {
-                    client.get(format!("{}/f/{mmid}", config.url))
+                let mut file_res = match send_with_retry(request(format!("/f/{mmid}")), config.request_attempts).await {
+                    Ok(r) => r,
+                    Err(e) => {
+                        print_error_line(format!("Failed to download {mmid}: {}", describe_error(&e)));
+                        failed = true;
+                        continue;
+                    }
+                };
+                if let Err(message) = check_download_status(&mmid, file_res.status()) {
+                    print_error_line(message);
+                    failed = true;
+                    continue;
                 }
-                .send()
-                .await
-                .unwrap();
 
-                let out_directory = out_directory.join(info.name);
-                let mut out_file: File = tokio::fs::OpenOptions::new()
+                let mut out_directory = out_directory.join(info.name);
+                if out_directory.exists() {
+                    match on_conflict {
+                        OnConflict::Rename => out_directory = unused_path(&out_directory),
+                        OnConflict::Overwrite => (),
+                        OnConflict::Skip => {
+                            println!("Skipped \"{}\", as it already exists", out_directory.display());
+                            continue;
+                        }
+                    }
+                }
+
+                let mut out_file: File = match tokio::fs::OpenOptions::new()
                     .create(true)
-                    .append(true)
+                    .write(true)
+                    .truncate(true)
                     .read(true)
                     .open(&out_directory).await
-                    .unwrap();
-
-                let progress_bar = ProgressBar::new(100);
-
-                progress_bar.set_style(ProgressStyle::with_template(
-                    &format!("{} {} {{bar:40.cyan/blue}} {{pos:>3}}% {{msg}}","Saving".bold(), &out_directory.file_name().unwrap().to_string_lossy().truecolor(246,199,219))
-                ).unwrap());
+                {
+                    Ok(f) => f,
+                    Err(e) => {
+                        print_error_line(format!("Could not create {}: {e}", out_directory.display()));
+                        failed = true;
+                        continue;
+                    }
+                };
 
-                let mut chunk_size = 0;
-                let file_size = file_res.content_length().unwrap();
-                let mut first = true;
+                let prefix = format!("{} {}", "Saving".bold(), &out_directory.file_name().unwrap().to_string_lossy().truecolor(246,199,219));
+                // Compressed downloads have no length, but the file info does
+                let length = file_res.content_length().or((info.size != 0).then_some(info.size));
+                let progress_bar = match length {
+                    Some(file_size) => {
+                        let bar = ProgressBar::new(file_size);
+                        bar.set_style(ProgressStyle::with_template(
+                            "{prefix} {bar:40.cyan/blue} {percent:>3}% {bytes}/{total_bytes} ({bytes_per_sec}, {eta})"
+                        ).unwrap());
+                        bar
+                    }
+                    // Without a length the progress can't be known, so just
+                    // show that something is happening
+                    None => {
+                        let bar = ProgressBar::new_spinner();
+                        bar.set_style(ProgressStyle::with_template(
+                            "{prefix} {spinner} {bytes} ({bytes_per_sec})"
+                        ).unwrap());
+                        bar
+                    }
+                };
+                progress_bar.set_prefix(prefix);
 
-                let mut i = 0;
-                while let Some(next) = file_res.chunk().await.unwrap() {
-                    i+=1;
-                    if first {
-                        chunk_size = next.len() as u64;
-                        first = false
+                let result: Result<()> = async {
+                    while let Some(next) = file_res.chunk().await.map_err(|e| anyhow!(describe_error(&e)))? {
+                        out_file.write_all(&next).await?;
+                        progress_bar.inc(next.len() as u64);
                     }
-                    out_file.write(&next).await.unwrap();
+                    out_file.flush().await?;
+                    Ok(())
+                }.await;
+                progress_bar.finish_and_clear();
 
-                    progress_bar.set_position(f64::trunc(((i as f64 * chunk_size as f64) / file_size as f64) * 200.0) as u64);
+                // Don't leave a partial file behind which looks like the real one
+                if let Err(e) = result {
+                    print_error_line(format!("Failed to download {mmid}: {e}"));
+                    let _ = tokio::fs::remove_file(&out_directory).await;
+                    failed = true;
+                    continue;
                 }
-                progress_bar.finish_and_clear();
 
                 println!("Downloaded to \"{}\"", out_directory.display());
+
+                if !no_verify {
+                    match hash_file(&out_directory, info.algo).await {
+                        Ok(hash) if hash.to_hex().as_str() == info.hash => {
+                            println!("[{}] - Verified", "✓".bright_green());
+                        }
+                        Ok(_) => {
+                            print_error_line(format!(
+                                "{} does not match the file on the server, it may be incomplete or corrupted",
+                                out_directory.display().truecolor(234, 129, 100)
+                            ));
+                            failed = true;
+                        }
+                        Err(e) => {
+                            print_error_line(format!("Could not verify {}: {e}", out_directory.display()));
+                            failed = true;
+                        }
+                    }
+                }
+            }
+
+            if failed {
+                std::process::exit(1);
             }
         }
         Commands::Set {
             username,
             password,
             url,
-            dl_dir
+            dl_dir,
+            history_length,
+            copy_urls,
+            request_attempts,
         } => {
-            if username.is_none() && password.is_none() && url.is_none() && dl_dir.is_none() {
+            if username.is_none() && password.is_none() && url.is_none() && dl_dir.is_none() && history_length.is_none() && copy_urls.is_none() && request_attempts.is_none() {
                 exit_error(
-                    format!("Please provide an option to set"),
-                    Some(format!("Allowed options:")),
-                    Some(vec!["--username".into(), "--password".into(), "--url".into(), "--dl-dir".into()]),
+                    "Please provide an option to set".to_string(),
+                    Some("Allowed options:".to_string()),
+                    Some(vec!["--username".into(), "--password".into(), "--url".into(), "--dl-dir".into(), "--history-length".into(), "--copy-urls".into(), "--request-attempts".into()]),
                 );
             }
 
             if let Some(u) = username {
                 if u.is_empty() {
-                    exit_error(format!("Username cannot be blank!"), None, None);
+                    exit_error("Username cannot be blank!".to_string(), None, None);
                 }
 
-                if let Some(l) = config.login.as_mut() {
+                if let Some(l) = config.profile_mut().login.as_mut() {
                     l.user = u.clone();
                 } else {
-                    config.login = Login {
+                    config.profile_mut().login = Login {
                         user: u.clone(),
                         pass: "".into()
                     }.into();
@@ -279,13 +566,13 @@ async fn main() -> Result<()> {
             }
             if let Some(p) = password {
                 if p.is_empty() {
-                    exit_error(format!("Password cannot be blank"), None, None);
+                    exit_error("Password cannot be blank".to_string(), None, None);
                 }
 
-                if let Some(l) = config.login.as_mut() {
+                if let Some(l) = config.profile_mut().login.as_mut() {
                     l.pass = p.clone();
                 } else {
-                    config.login = Login {
+                    config.profile_mut().login = Login {
                         user: "".into(),
                         pass: p.clone()
                     }.into();
@@ -296,22 +583,22 @@ async fn main() -> Result<()> {
             }
             if let Some(url) = url {
                 if url.is_empty() {
-                    exit_error(format!("URL cannot be blank"), None, None);
+                    exit_error("URL cannot be blank".to_string(), None, None);
                 }
 
-                let url = if url.chars().last() == Some('/') {
+                let url = if url.ends_with('/') {
                     url.split_at(url.len() - 1).0
                 } else {
                     url
                 };
 
-                config.url = url.to_string();
+                config.profile_mut().url = url.to_string();
                 config.save().unwrap();
                 println!("URL set to \"{url}\"");
             }
             if let Some(mut dir) = dl_dir.clone() {
                 if dir.is_empty() {
-                    exit_error(format!("Download directory cannot be blank"), None, None);
+                    exit_error("Download directory cannot be blank".to_string(), None, None);
                 }
                 if dir.as_str() == "default" {
                     dir = directories::UserDirs::new()
@@ -321,7 +608,7 @@ async fn main() -> Result<()> {
                     .to_string_lossy()
                     .to_string();
                 }
-                if dir.chars().last() != Some('/') {
+                if !dir.ends_with('/') {
                     dir.push('/');
                 }
 
@@ -330,19 +617,235 @@ async fn main() -> Result<()> {
                     exit_error(format!("Directory {dir} does not exist"), None, None)
                 }
 
-                config.download_directory = _dir;
+                config.profile_mut().download_directory = _dir;
                 config.save().unwrap();
                 println!("Download directory set to \"{dir}\"");
             }
+            if let Some(length) = history_length {
+                config.history_length = *length;
+                config.trim_history();
+                config.save().unwrap();
+                println!("History length set to {length}");
+            }
+            if let Some(copy) = copy_urls {
+                config.copy_urls = *copy;
+                config.save().unwrap();
+                println!("Copying URLs after uploading {}", if *copy { "enabled" } else { "disabled" });
+            }
+            if let Some(attempts) = request_attempts {
+                if *attempts == 0 {
+                    exit_error("Request attempts must be at least 1".to_string(), None, None);
+                }
+
+                config.request_attempts = *attempts;
+                config.save().unwrap();
+                println!("Request attempts set to {attempts}");
+            }
+        }
+        Commands::Profile(ProfileCommand::List) => {
+            for (name, profile) in &config.profiles {
+                let marker = if *name == config.current { "*" } else { " " };
+                let url = if profile.url.is_empty() { "(no URL set)" } else { &profile.url };
+                println!("{} {} {}", marker.bright_green(), name.truecolor(246,199,219).bold(), url);
+            }
+        }
+        Commands::Profile(ProfileCommand::Create { name }) => {
+            if name.is_empty() {
+                exit_error("Profile name cannot be blank".to_string(), None, None);
+            }
+            if config.profiles.contains_key(name) {
+                exit_error(format!("Profile {name} already exists"), None, None);
+            }
+
+            config.profiles.insert(name.clone(), Profile::default());
+            config.save().unwrap();
+            println!("Created profile \"{name}\"");
+        }
+        Commands::Profile(ProfileCommand::Switch { name }) => {
+            if !config.profiles.contains_key(name) {
+                exit_error(
+                    format!("Profile {name} does not exist"),
+                    Some("Available profiles:".to_string()),
+                    Some(config.profiles.keys().cloned().collect()),
+                );
+            }
+
+            config.current = name.clone();
+            config.save().unwrap();
+            println!("Switched to profile \"{name}\"");
         }
-        Commands::Info => {
+        Commands::History => {
+            if config.history.is_empty() {
+                println!("No files have been uploaded yet");
+            }
+
+            let now = Utc::now();
+            for entry in config.history.iter().rev() {
+                let expiry = if entry.expiry_datetime <= now {
+                    "expired".truecolor(181,66,127).italic().to_string()
+                } else {
+                    format!("expires in {}", pretty_time_short((entry.expiry_datetime - now).num_seconds()))
+                };
+
+                println!(
+                    "{} {} ({})\n{:>8} {}",
+                    entry.mmid.0.truecolor(246,199,219),
+                    entry.name,
+                    expiry,
+                    "URL:".truecolor(174,196,223).bold(),
+                    entry.url.underline(),
+                );
+            }
+        }
+        Commands::Delete { mmids } => {
+            if config.profile().url.is_empty() {
+                exit_error(
+                    "URL is empty".to_string(),
+                    Some(format!("Please set it using the {} command", "set".truecolor(246,199,219).bold())),
+                    None,
+                );
+            }
+
+            let client = Client::new();
+            for mmid in mmids {
+                let Some(mmid) = parse_mmid(mmid) else {
+                    print_error_line(format!("{} is not a valid MMID", mmid.truecolor(234, 129, 100)));
+                    continue;
+                };
+                let Some(token) = config.delete_tokens.get(&mmid) else {
+                    print_error_line(format!(
+                        "No deletion token saved for {}, it may have been uploaded from somewhere else",
+                        mmid.0.truecolor(234, 129, 100)
+                    ));
+                    continue;
+                };
+
+                let request = client.delete(format!("{}/f/{}", config.profile().url, mmid.0))
+                    .query(&[("token", token)]);
+                let request = if let Some(l) = &config.profile().login {
+                    request.basic_auth(&l.user, l.pass.clone().into())
+                } else {
+                    request
+                };
+
+                let status = match request.send().await {
+                    Ok(r) => r.status(),
+                    Err(e) => {
+                        print_error_line(format!("Failed to delete {}: {e}", mmid.0.truecolor(234, 129, 100)));
+                        continue;
+                    }
+                };
+
+                match status.as_u16() {
+                    200 => {
+                        println!("[{}] - Deleted {}", "✓".bright_green(), mmid.0.truecolor(246,199,219));
+                        config.delete_tokens.remove(&mmid);
+                    }
+                    403 => print_error_line(format!("The server rejected the deletion token for {}", mmid.0.truecolor(234, 129, 100))),
+                    404 => {
+                        print_error_line(format!("{} was not found, it may have already expired", mmid.0.truecolor(234, 129, 100)));
+                        config.delete_tokens.remove(&mmid);
+                    }
+                    _ => print_error_line(format!("Failed to delete {} ({status})", mmid.0.truecolor(234, 129, 100))),
+                }
+            }
+            config.save().unwrap();
+        }
+        Commands::List { sort, limit } => {
+            if config.profile().url.is_empty() {
+                exit_error(
+                    "URL is empty".to_string(),
+                    Some(format!("Please set it using the {} command", "set".truecolor(246,199,219).bold())),
+                    None,
+                );
+            }
+
+            let (files, total) = match list_files(&config, *sort, *limit).await {
+                Ok(f) => f,
+                Err(e) => exit_error("Failed to list files!".to_string(), Some(e.to_string()), None),
+            };
+
+            println!(
+                "{:<8}  {:>9}  {:<12}  {}",
+                "MMID".truecolor(174,196,223).bold(),
+                "Size".truecolor(174,196,223).bold(),
+                "Expires In".truecolor(174,196,223).bold(),
+                "Name".truecolor(174,196,223).bold(),
+            );
+
+            let now = Utc::now();
+            for file in &files {
+                let remaining = (file.expiry_datetime - now).num_seconds().max(0);
+                println!(
+                    "{:<8}  {:>9}  {:<12}  {}",
+                    file.mmid.0.truecolor(246,199,219),
+                    pretty_size(file.size),
+                    pretty_time_short(remaining),
+                    file.name,
+                );
+            }
+            println!("Showing {} of {} files", files.len(), total);
+        }
+        Commands::Info { mmid: None } => {
             let info = match get_info(&config).await {
                 Ok(i) => i,
-                Err(e) => exit_error(format!("Failed to get server information!"), Some(e.to_string()), None),
+                Err(e) => exit_error("Failed to get server information!".to_string(), Some(e.to_string()), None),
             };
-            config.info = Some(info);
+            config.profile_mut().info = Some(info);
             config.save().unwrap();
         }
+        Commands::Info { mmid: Some(mmid) } => {
+            if config.profile().url.is_empty() {
+                exit_error(
+                    "URL is empty".to_string(),
+                    Some(format!("Please set it using the {} command", "set".truecolor(246,199,219).bold())),
+                    None,
+                );
+            }
+
+            let mmid = match parse_mmid(mmid) {
+                Some(m) => m.0,
+                None => exit_error(
+                    format!("{mmid} is not a valid MMID"),
+                    Some(format!(
+                        "It must be {} to {} letters or numbers, or a link to a file",
+                        MMID_LENGTHS.start(),
+                        MMID_LENGTHS.end(),
+                    )),
+                    None,
+                ),
+            };
+
+            let info = match get_file_info(&config, &Client::new(), &mmid).await {
+                Ok(i) => i,
+                Err(message) => exit_error(message, None, None),
+            };
+
+            let now = Utc::now();
+            let size = if info.size == 0 {
+                "Unknown".to_string()
+            } else {
+                format!("{} ({} bytes)", pretty_size(info.size), info.size)
+            };
+            let uploaded = format!(
+                "{} ({} ago)",
+                pretty_date(info.upload_datetime),
+                pretty_time_long((now - info.upload_datetime).num_seconds().max(0)),
+            );
+            let expires = format!(
+                "{} (in {})",
+                pretty_date(info.expiry_datetime),
+                pretty_time_long((info.expiry_datetime - now).num_seconds().max(0)),
+            );
+
+            println!("{:>9} {}", "Name:".truecolor(174,196,223).bold(), info.name);
+            println!("{:>9} {}", "MMID:".truecolor(174,196,223).bold(), info.mmid.0.truecolor(246,199,219));
+            println!("{:>9} {}", "Size:".truecolor(174,196,223).bold(), size);
+            println!("{:>9} {}", "Type:".truecolor(174,196,223).bold(), info.mime_type);
+            println!("{:>9} {} ({})", "Hash:".truecolor(174,196,223).bold(), info.hash, info.algo);
+            println!("{:>9} {}", "Uploaded:".truecolor(174,196,223).bold(), uploaded);
+            println!("{:>9} {}", "Expires:".truecolor(174,196,223).bold(), expires);
+        }
     }
 
     Ok(())
@@ -355,139 +858,407 @@ enum UploadError {
 
     #[error("error on reqwest transaction: {0}")]
     Reqwest(#[from] reqwest::Error),
+
+    #[error("failed to read file: {0}")]
+    Io(#[from] io::Error),
 }
 
+/// How many times to try sending a chunk before giving up on the upload
+const CHUNK_ATTEMPTS: u32 = 4;
+
+/// Upload a file using the chunked upload endpoints. If an earlier upload of
+/// the same file was interrupted, only the chunks the server is missing are
+/// sent.
 async fn upload_file<P: AsRef<Path>>(
     name: String,
     path: &P,
     client: &Client,
-    url: &String,
+    config: &mut Config,
     duration: TimeDelta,
-    login: &Option<Login>,
 ) -> Result<MochiFile, UploadError> {
-    let mut file = File::open(path).await.unwrap();
-    let size = file.metadata().await.unwrap().size() as u64;
-
-    let ChunkedResponse {status, message, uuid, chunk_size} = {
-        client.post(format!("{url}/upload/chunked/"))
-            .json(
-                &ChunkedInfo {
-                    name: name.clone(),
-                    size,
-                    expire_duration: duration.num_seconds() as u64,
-                }
-            )
-            .basic_auth(&login.as_ref().unwrap().user, login.as_ref().unwrap().pass.clone().into())
-            .send()
-            .await?
-            .json()
-            .await?
-    };
+    let url = config.profile().url.clone();
+    let login = config.profile().login.clone();
+    let mut file = File::open(path).await?;
+    let size = file.metadata().await?.size();
+
+    // The hash identifies the file for resuming, and lets the server check
+    // that it recieved the file intact, so it has to use the server's
+    // algorithm
+    let algo = config.profile().info.as_ref().and_then(|i| i.hash_algorithm).unwrap_or_default();
+    let hash = hash_file(path.as_ref(), algo).await?.to_hex().to_string();
+
+    // Check whether there is an unfinished upload of this file. Its name and
+    // duration can't be changed once started, so an upload with different
+    // ones is started over.
+    let mut resumed = None;
+    if let Some(i) = config.unfinished_uploads.iter().position(|p| p.url == url && p.hash == hash) {
+        let unfinished = config.unfinished_uploads[i].clone();
+        if unfinished.name == name && unfinished.duration == duration.num_seconds() {
+            let uuid = unfinished.uuid;
+            let response = with_login(client.get(format!("{url}/upload/chunked/{uuid}?status")), login.as_ref())
+                .send()
+                .await?;
 
-    if !status {
-        return Err(UploadError::InvalidRequest(message));
+            if response.status().is_success() {
+                resumed = Some((uuid, response.json::<ChunkedStatus>().await?));
+            }
+        }
+
+        if resumed.is_none() {
+            config.unfinished_uploads.remove(i);
+            config.save().unwrap();
+        }
     }
 
-    let mut i = 0;
-    let post_url = format!("{url}/upload/chunked/{}", uuid.unwrap());
+    let (uuid, chunk_size, received_chunks) = match resumed {
+        Some((uuid, status)) => {
+            println!("Resuming upload of \"{}\"", name);
+            (uuid, status.chunk_size, status.received_chunks)
+        }
+        None => {
+            let ChunkedResponse {status, message, uuid, chunk_size, ..} = {
+                let request = client.post(format!("{url}/upload/chunked/"))
+                    .json(
+                        &ChunkedInfo {
+                            name: name.clone(),
+                            size,
+                            expire_duration: duration.num_seconds() as u64,
+                        }
+                    );
+                with_login(request, login.as_ref())
+                    .send()
+                    .await?
+                    .json()
+                    .await?
+            };
+
+            if !status {
+                return Err(UploadError::InvalidRequest(message));
+            }
+
+            // Save the upload so it can be resumed if this process is stopped
+            config.unfinished_uploads.push(UnfinishedUpload {
+                url: url.clone(),
+                hash: hash.clone(),
+                name: name.clone(),
+                duration: duration.num_seconds(),
+                uuid: uuid.unwrap(),
+            });
+            config.save().unwrap();
+
+            (uuid.unwrap(), chunk_size.unwrap(), Vec::new())
+        }
+    };
+
+    let chunk_count = size.div_ceil(chunk_size);
+    let chunk_len = |i: u64| cmp::min(chunk_size, size - i * chunk_size);
+    let post_url = format!("{url}/upload/chunked/{uuid}");
     let mut request_set = JoinSet::new();
-    let bar = ProgressBar::new(100);
+    let bar = ProgressBar::new(size);
     bar.set_style(ProgressStyle::with_template(
-        &format!("{} {{bar:40.cyan/blue}} {{pos:>3}}% {{msg}}", name)
+        &format!("{} {{bar:40.cyan/blue}} {{bytes}}/{{total_bytes}} ({{bytes_per_sec}}) {{msg}}", name)
     ).unwrap());
-    loop {
-        // Read the next chunk into a buffer
-        let mut chunk = vec![0u8; chunk_size.unwrap() as usize];
-        let bytes_read = fill_buffer(&mut chunk, &mut file).await.unwrap();
-        if bytes_read == 0 {
-            break;
+    bar.set_position(received_chunks.iter().map(|i| chunk_len(*i)).sum());
+
+    let mut failed = false;
+    for i in 0..chunk_count {
+        if received_chunks.contains(&i) {
+            continue;
         }
-        let chunk = chunk[..bytes_read].to_owned();
+
+        // Read the next chunk into a buffer
+        let mut chunk = vec![0u8; chunk_size as usize];
+        file.seek(io::SeekFrom::Start(i * chunk_size)).await?;
+        let bytes_read = fill_buffer(&mut chunk, &mut file).await?;
+        chunk.truncate(bytes_read);
 
         request_set.spawn({
             let post_url = post_url.clone();
-            let user = login.as_ref().unwrap().user.clone();
-            let pass = login.as_ref().unwrap().pass.clone();
+            let login = login.clone();
             // Reuse the client for all the threads
             let client = Client::clone(client);
 
             async move {
-                client.post(&post_url)
-                    .query(&[("chunk", i)])
-                    .basic_auth(&user, pass.into())
-                    .body(chunk)
-                    .send()
-                    .await
+                (send_chunk(&client, &post_url, i, chunk, login.as_ref()).await, i)
             }
         });
 
-        i += 1;
-
         // Limit the number of concurrent uploads to 5
         if request_set.len() >= 5 {
             bar.set_message("");
-            request_set.join_next().await;
+            if let Some(result) = request_set.join_next().await {
+                match result {
+                    Ok((true, i)) => bar.inc(chunk_len(i)),
+                    _ => failed = true,
+                }
+            }
             bar.set_message("⏳");
         }
-
-        let percent = f64::trunc(((i as f64 * chunk_size.unwrap() as f64) / size as f64) * 100.0);
-        if percent <= 100. {
-            bar.set_position(percent as u64);
-        }
     }
 
     // Wait for all remaining uploads to finish
-    loop {
-        if let Some(t) = request_set.join_next().await {
-            match t {
-                Ok(_) => (),
-                Err(_) => todo!(),
-            }
-        } else {
-            break
+    while let Some(result) = request_set.join_next().await {
+        match result {
+            Ok((true, i)) => bar.inc(chunk_len(i)),
+            _ => failed = true,
         }
     }
     bar.finish_and_clear();
+
+    if failed {
+        return Err(UploadError::InvalidRequest(
+            "Some chunks could not be uploaded, try again to resume the upload".into(),
+        ));
+    }
     println!("[{}] - \"{}\"", "✓".bright_green(), name);
 
-    Ok(
-        client.get(format!("{url}/upload/chunked/{}?finish", uuid.unwrap()))
-            .basic_auth(&login.as_ref().unwrap().user, login.as_ref().unwrap().pass.clone().into())
+    // Let the server verify that it recieved the file intact
+    let request = client.get(format!("{url}/upload/chunked/{uuid}?finish"))
+        .query(&[("expected_hash", hash.as_str())]);
+    let response = with_login(request, login.as_ref())
+        .send()
+        .await?;
+
+    // The upload can't be resumed once the server has tried to finish it
+    config.unfinished_uploads.retain(|p| p.uuid != uuid);
+    config.save().unwrap();
+
+    if !response.status().is_success() {
+        return Err(UploadError::InvalidRequest(response.text().await?));
+    }
+
+    Ok(response.json::<MochiFile>().await?)
+}
+
+/// Send a request, trying again up to `attempts` times in total with an
+/// increasing delay if the server can't be reached or has a temporary
+/// problem. Other error responses are returned straight away, as trying
+/// again won't change them.
+async fn send_with_retry(request: RequestBuilder, attempts: u32) -> reqwest::Result<Response> {
+    let attempts = attempts.max(1);
+    let mut attempt = 0;
+    loop {
+        if attempt > 0 {
+            tokio::time::sleep(std::time::Duration::from_secs(1 << attempt)).await;
+        }
+        attempt += 1;
+
+        // Requests without a streaming body can always be cloned
+        let Some(this_request) = request.try_clone() else {
+            return request.send().await;
+        };
+        let last = attempt >= attempts;
+        match this_request.send().await {
+            Ok(r) if r.status().is_server_error() && !last => (),
+            Err(e) if (e.is_connect() || e.is_timeout() || e.is_request()) && !last => (),
+            result => return result,
+        }
+    }
+}
+
+/// Describe why a request failed. The errors from `reqwest` only say what
+/// was being done, with the reason in their sources.
+fn describe_error(error: &reqwest::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(e) = source {
+        message.push_str(": ");
+        message.push_str(&e.to_string());
+        source = e.source();
+    }
+
+    message
+}
+
+/// Add the login to a request, for servers which need one
+fn with_login(request: RequestBuilder, login: Option<&Login>) -> RequestBuilder {
+    match login {
+        Some(login) => request.basic_auth(&login.user, Some(&login.pass)),
+        None => request,
+    }
+}
+
+/// Send a single chunk of a file, retrying if the connection fails or the
+/// server has a temporary problem. Returns whether the chunk was sent.
+async fn send_chunk(client: &Client, url: &str, chunk: u64, data: Vec<u8>, login: Option<&Login>) -> bool {
+    for attempt in 0..CHUNK_ATTEMPTS {
+        if attempt > 0 {
+            tokio::time::sleep(std::time::Duration::from_secs(1 << attempt)).await;
+        }
+
+        let request = client.post(url)
+            .query(&[("chunk", chunk)])
+            .body(data.clone());
+        let response = with_login(request, login)
             .send()
-            .await.unwrap()
-            .json::<MochiFile>()
-            .await?
-    )
+            .await;
+
+        match response {
+            Ok(r) if r.status().is_success() => return true,
+            // Errors other than server errors won't be fixed by trying again
+            Ok(r) if !r.status().is_server_error() => return false,
+            _ => (),
+        }
+    }
+
+    false
+}
+
+/// Read all of stdin into a file
+async fn read_stdin(path: &Path) -> Result<u64, io::Error> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut file = fs::File::create(path)?;
+        io::copy(&mut io::stdin().lock(), &mut file)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Get the hash of a file, without reading it all into memory
+async fn hash_file(path: &Path, algo: HashAlgorithm) -> Result<blake3::Hash, io::Error> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let file = fs::File::open(path)?;
+        match algo {
+            HashAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                hasher.update_reader(file)?;
+                Ok(hasher.finalize())
+            }
+            // Both algorithms give 32 bytes, so either fits in a Blake3 hash
+            HashAlgorithm::Sha256 => {
+                let mut hasher = Sha256::new();
+                io::copy(&mut io::BufReader::new(file), &mut hasher)?;
+                Ok(blake3::Hash::from_bytes(hasher.finalize().into()))
+            }
+        }
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Get the information the server has about the file `mmid`, or a message
+/// explaining why it couldn't be found
+async fn get_file_info(config: &Config, client: &Client, mmid: &str) -> Result<MochiFile, String> {
+    let url = &config.profile().url;
+    let request = client.get(format!("{url}/info/{mmid}"));
+    let request = match &config.profile().login {
+        Some(login) => request.basic_auth(&login.user, Some(&login.pass)),
+        None => request,
+    };
+
+    let res = send_with_retry(request, config.request_attempts)
+        .await
+        .map_err(|e| format!("Could not get information about {mmid}: {}", describe_error(&e)))?;
+    check_download_status(mmid, res.status())?;
+
+    res.json::<MochiFile>()
+        .await
+        .map_err(|e| format!("Could not get information about {mmid}: {}", describe_error(&e)))
+}
+
+/// Explain why a request about the file `mmid` failed, going by the status
+/// the server responded with
+fn check_download_status(mmid: &str, status: StatusCode) -> Result<(), String> {
+    match status {
+        s if s.is_success() => Ok(()),
+        StatusCode::NOT_FOUND => Err(format!("File with MMID {mmid} was not found")),
+        StatusCode::GONE => Err(format!("File with MMID {mmid} has expired")),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(format!(
+            "Access to {mmid} was denied, it may need a login or a password"
+        )),
+        s => Err(format!(
+            "Failed to download {mmid}: the server responded with {} - {}",
+            s.as_str(),
+            s.canonical_reason().unwrap_or_default()
+        )),
+    }
+}
+
+/// Find a path like `path` which doesn't exist yet, by adding a number to the
+/// end of the file name, before its extension if it has one
+fn unused_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+
+    (1..)
+        .map(|n| path.with_file_name(format!("{stem} ({n}){extension}")))
+        .find(|p| !p.exists())
+        .unwrap()
+}
+
+/// Get up to `limit` files from the server's admin file list, along with the
+/// total number of files on the server
+async fn list_files(config: &Config, sort: ListSort, limit: usize) -> Result<(Vec<FileSummary>, usize)> {
+    let client = Client::new();
+    let sort = match sort {
+        ListSort::Upload => "upload",
+        ListSort::Expiry => "expiry",
+    };
+    // The server won't send more than this many files at once
+    let per_page = limit.clamp(1, 500);
+
+    let mut files = Vec::new();
+    let mut page = 1;
+    loop {
+        let request = client.get(format!("{}/admin/list", config.profile().url))
+            .query(&[("sort", sort)])
+            .query(&[("page", page), ("per_page", per_page)]);
+        let request = if let Some(l) = &config.profile().login {
+            request.basic_auth(&l.user, l.pass.clone().into())
+        } else {
+            request
+        };
+
+        let response = request.send().await?;
+        match response.status().as_u16() {
+            401 => bail!("Got access denied! Maybe you need a username and password?"),
+            403 => bail!("This user is not allowed to list files, or the server has no admins"),
+            _ => (),
+        }
+        let list = response.error_for_status()?.json::<FilePage>().await?;
+
+        let finished = list.files.len() < per_page;
+        files.extend(list.files);
+        if finished || files.len() >= limit {
+            files.truncate(limit);
+            return Ok((files, list.total));
+        }
+        page += 1;
+    }
 }
 
 async fn get_info_if_expired(config: &mut Config) -> Result<()> {
     let now = Utc::now();
-    if !config.info_fetch.is_none() && !config.info_fetch.is_some_and(|e| e <= now) {
+    if config.profile().info_fetch.is_some() && config.profile().info_fetch.is_none_or(|e| e > now) {
         // Not yet ready to get a new batch of info
         return Ok(())
     }
     println!("{}", "Getting new server info...".truecolor(255,249,184));
 
-    let info = get_info(&config).await?;
-    config.info = Some(info);
-    config.info_fetch = Some(now + TimeDelta::days(2));
+    let info = get_info(config).await?;
+    config.profile_mut().info = Some(info);
+    config.profile_mut().info_fetch = Some(now + TimeDelta::days(2));
     config.save().unwrap();
 
     Ok(())
 }
 
 async fn get_info(config: &Config) -> Result<ServerInfo> {
-    let url = config.url.clone();
+    let url = config.profile().url.clone();
     let client = Client::new();
 
     let get_info = client.get(format!("{url}/info"));
-    let get_info = if let Some(l) = &config.login {
+    let get_info = if let Some(l) = &config.profile().login {
         get_info.basic_auth(&l.user, l.pass.clone().into())
     } else {
         get_info
     };
 
-    let info = get_info.send().await.unwrap();
+    let info = send_with_retry(get_info, config.request_attempts)
+        .await
+        .map_err(|e| anyhow!("Could not reach the server: {}", describe_error(&e)))?;
     if info.status() == 401 {
         let err = info.error_for_status().unwrap_err();
         bail!(
@@ -523,19 +1294,36 @@ async fn fill_buffer<S: AsyncReadExt + Unpin>(buffer: &mut [u8], mut stream: S)
     Ok(bytes_read)
 }
 
-#[derive(Debug)]
-struct Upload {
-    file: File,
-    name: String,
-    duration: i64,
-}
-
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 struct ServerInfo {
     max_filesize: u64,
     max_duration: i64,
+    /// Older servers don't have a minimum duration
+    min_duration: Option<i64>,
     default_duration: i64,
     allowed_durations: Vec<i64>,
+    /// Older servers don't say which ways of uploading they accept
+    supports_chunked: Option<bool>,
+    /// Older servers always hash files with Blake3
+    hash_algorithm: Option<HashAlgorithm>,
+}
+
+/// The algorithm a server hashes files with
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum HashAlgorithm {
+    #[default]
+    Blake3,
+    Sha256,
+}
+
+impl std::fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Blake3 => write!(f, "blake3"),
+            Self::Sha256 => write!(f, "sha256"),
+        }
+    }
 }
 
 #[derive(Serialize, Debug)]
@@ -545,6 +1333,13 @@ pub struct ChunkedInfo {
     pub expire_duration: u64,
 }
 
+/// The progress of an unfinished chunked upload
+#[derive(Deserialize, Debug)]
+pub struct ChunkedStatus {
+    chunk_size: u64,
+    received_chunks: Vec<u64>,
+}
+
 #[derive(Serialize, Deserialize, Default, Debug)]
 pub struct ChunkedResponse {
     status: bool,
@@ -555,8 +1350,13 @@ pub struct ChunkedResponse {
 
     /// Valid max chunk size in bytes
     chunk_size: Option<u64>,
+
+    /// The closest allowed duration in seconds, if the one given wasn't
+    #[serde(default)]
+    suggested_duration: Option<i64>,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct MochiFile {
     /// A unique identifier describing this file
@@ -568,29 +1368,123 @@ pub struct MochiFile {
     /// The MIME type of the file
     mime_type: String,
 
-    /// The Blake3 hash of the file
+    /// The hash of the file
     hash: String,
 
+    /// The algorithm `hash` was made with, which older servers don't send
+    #[serde(default)]
+    algo: HashAlgorithm,
+
+    /// The size of the file in bytes, which older servers don't send
+    #[serde(default)]
+    size: u64,
+
     /// The datetime when the file was uploaded
     upload_datetime: DateTime<Utc>,
 
     /// The datetime when the file is set to expire
     expiry_datetime: DateTime<Utc>,
+
+    /// A secret token which allows the uploader to delete the file early
+    #[serde(default)]
+    delete_token: String,
+
+    /// Whether the server already had an identical file, which older servers
+    /// don't send
+    #[serde(default)]
+    deduplicated: bool,
+}
+
+/// A file in the server's admin file list
+#[derive(Deserialize, Debug)]
+struct FileSummary {
+    mmid: Mmid,
+    name: String,
+    size: u64,
+    expiry_datetime: DateTime<Utc>,
+}
+
+/// A single page of the server's admin file list
+#[derive(Deserialize, Debug)]
+struct FilePage {
+    total: usize,
+    files: Vec<FileSummary>,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 #[derive(Deserialize, Serialize)]
 pub struct Mmid(String);
 
+impl TryFrom<&str> for Mmid {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        if !MMID_LENGTHS.contains(&value.len()) {
+            return Err(());
+        }
+
+        if value.chars().any(|c| !c.is_ascii_alphanumeric()) {
+            return Err(());
+        }
+
+        Ok(Self(value.to_owned()))
+    }
+}
+
+/// Get the MMID out of either a bare MMID like `xNLF6ogx`, or a link to a file
+/// like `https://example.com/f/xNLF6ogx` or `https://example.com/f/xNLF6ogx/name.jpg`
+fn parse_mmid(input: &str) -> Option<Mmid> {
+    let input = input.trim();
+    let mmid = match input.rsplit_once("/f/") {
+        Some((_, path)) => path.split(['/', '?', '#']).next()?,
+        None => input,
+    };
+
+    Mmid::try_from(mmid).ok()
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 struct Login {
     user: String,
     pass: String
 }
 
-#[derive(Deserialize, Serialize, Debug, Default)]
+/// A file which was uploaded from the CLI
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct HistoryEntry {
+    mmid: Mmid,
+    name: String,
+    url: String,
+    expiry_datetime: DateTime<Utc>,
+    #[serde(default)]
+    delete_token: String,
+}
+
+/// An upload which was started but not finished, so it can be resumed
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct UnfinishedUpload {
+    /// The server the upload was started on
+    url: String,
+    /// The hash of the file being uploaded
+    hash: String,
+    name: String,
+    /// How long the file is to be kept for, in seconds
+    duration: i64,
+    uuid: Uuid,
+}
+
+fn default_history_length() -> usize {
+    DEFAULT_HISTORY_LENGTH
+}
+
+fn default_request_attempts() -> u32 {
+    DEFAULT_REQUEST_ATTEMPTS
+}
+
+/// The settings for connecting to a single server
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(default)]
-struct Config {
+struct Profile {
     url: String,
     login: Option<Login>,
     /// The time when the info was last fetched
@@ -599,19 +1493,61 @@ struct Config {
     download_directory: PathBuf,
 }
 
+impl Default for Profile {
+    fn default() -> Self {
+        let download_directory = if cfg!(debug_assertions) {
+            PathBuf::from(DEBUG_DOWNLOAD_DIR)
+        } else {
+            PathBuf::from(directories::UserDirs::new().unwrap().download_dir().unwrap_or(Path::new("")))
+        };
+
+        Profile {
+            url: String::new(),
+            login: None,
+            info_fetch: None,
+            info: None,
+            download_directory,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(default)]
+struct Config {
+    /// The name of the profile to use when none is given
+    current: String,
+    /// Settings for each server, by name
+    profiles: BTreeMap<String, Profile>,
+    /// The profile given with `--profile` for this run, if any
+    #[serde(skip)]
+    selected: Option<String>,
+    /// A copy of the profile being used with the options given for this run
+    /// applied, if any were. This is never saved.
+    #[serde(skip)]
+    temporary: Option<Profile>,
+    /// Tokens for deleting files which were uploaded from here
+    delete_tokens: HashMap<Mmid, String>,
+    /// How many recent uploads to remember
+    #[serde(default = "default_history_length")]
+    history_length: usize,
+    /// How many times to try requests to the server before giving up
+    #[serde(default = "default_request_attempts")]
+    request_attempts: u32,
+    /// Recently uploaded files, oldest first
+    history: Vec<HistoryEntry>,
+    /// Unfinished uploads which can be resumed
+    unfinished_uploads: Vec<UnfinishedUpload>,
+    /// Whether to copy the URLs of uploaded files to the clipboard
+    copy_urls: bool,
+}
+
 impl Config {
     fn open() -> Result<Self, Box<dyn std::error::Error>> {
         let c = if cfg!(debug_assertions) {
             if let Ok(str) = fs::read_to_string(DEBUG_CONFIG) {
                 str
             } else {
-                let c = Config {
-                    url: String::new(),
-                    login: None,
-                    info_fetch: None,
-                    info: None,
-                    download_directory: PathBuf::from(DEBUG_DOWNLOAD_DIR)
-                };
+                let c = Config::new();
                 c.save().unwrap();
                 return Ok(c);
             }
@@ -630,6 +1566,7 @@ impl Config {
 
                 fs::OpenOptions::new()
                 .create(true)
+                .truncate(false)
                 .write(true)
                 .read(true)
                 .open(path.join("config.toml"))
@@ -638,13 +1575,7 @@ impl Config {
                 .unwrap();
 
                 if buf.is_empty() {
-                    let c = Config {
-                        url: String::new(),
-                        login: None,
-                        info: None,
-                        info_fetch: None,
-                        download_directory: PathBuf::from(directories::UserDirs::new().unwrap().download_dir().unwrap_or(Path::new("")))
-                    };
+                    let c = Config::new();
                     c.save().unwrap();
 
                     // dbg!(path);
@@ -657,7 +1588,92 @@ impl Config {
             }
         };
 
-        Ok(toml::from_str::<Config>(c.as_str()).unwrap())
+        let mut config = toml::from_str::<Config>(c.as_str()).unwrap();
+
+        // Older configs only had settings for one server, directly in the
+        // config, so move them into a profile
+        if config.profiles.is_empty() {
+            let profile = toml::from_str::<Profile>(c.as_str()).unwrap();
+            config.current = DEFAULT_PROFILE.to_string();
+            config.profiles.insert(DEFAULT_PROFILE.to_string(), profile);
+            config.save().unwrap();
+        }
+
+        Ok(config)
+    }
+
+    fn new() -> Self {
+        Config {
+            current: DEFAULT_PROFILE.to_string(),
+            profiles: BTreeMap::from([(DEFAULT_PROFILE.to_string(), Profile::default())]),
+            history_length: DEFAULT_HISTORY_LENGTH,
+            request_attempts: DEFAULT_REQUEST_ATTEMPTS,
+            ..Default::default()
+        }
+    }
+
+    /// The name of the profile being used
+    fn profile_name(&self) -> &str {
+        self.selected.as_deref().unwrap_or(&self.current)
+    }
+
+    /// The settings for the server being used
+    fn profile(&self) -> &Profile {
+        match &self.temporary {
+            Some(p) => p,
+            None => &self.profiles[self.profile_name()],
+        }
+    }
+
+    /// The settings for the server being used, creating a new profile if it
+    /// doesn't exist yet
+    fn profile_mut(&mut self) -> &mut Profile {
+        match self.temporary {
+            Some(ref mut p) => p,
+            None => {
+                let name = self.profile_name().to_string();
+                self.profiles.entry(name).or_default()
+            }
+        }
+    }
+
+    /// Use a different server or login for this run only, without changing
+    /// the saved profile
+    fn override_profile(&mut self, url: Option<&str>, user: Option<&str>, pass: Option<&str>) {
+        let mut profile = self.profile().clone();
+        if let Some(url) = url {
+            let url = url.strip_suffix('/').unwrap_or(url);
+
+            // The cached info and the login are for a different server, and
+            // the login shouldn't be sent to this one
+            if url != profile.url {
+                profile.info = None;
+                profile.info_fetch = None;
+                profile.login = None;
+            }
+            profile.url = url.to_string();
+        }
+
+        if user.is_some() || pass.is_some() {
+            let login = profile.login.get_or_insert(Login {
+                user: "".into(),
+                pass: "".into(),
+            });
+            if let Some(u) = user {
+                login.user = u.to_string();
+            }
+            if let Some(p) = pass {
+                login.pass = p.to_string();
+            }
+        }
+
+        self.temporary = Some(profile);
+    }
+
+    /// Forget the oldest uploads if there are more than `history_length`
+    fn trim_history(&mut self) {
+        let excess = self.history.len().saturating_sub(self.history_length);
+        self.history.drain(..excess);
     }
 
     fn save(&self) -> Result<(), ()> {
@@ -685,37 +1701,49 @@ impl Config {
     }
 }
 
+/// Parse a duration string made up of one or more number and unit pairs, like
+/// `6h`, `90m`, or `1d12h`. Supported units are weeks (`w`), days (`d`), hours
+/// (`h`), minutes (`m`), and seconds (`s`).
 fn parse_time_string(string: &str) -> Result<TimeDelta, Box<dyn Error>> {
-    if string.len() > 7 {
+    if string.is_empty() {
         return Err("Not valid time string".into());
     }
 
-    let unit = string.chars().last();
-    let multiplier = if let Some(u) = unit {
-        if !u.is_ascii_alphabetic() {
-            return Err("Not valid time string".into());
+    let mut total = TimeDelta::zero();
+    let mut number = String::new();
+    for c in string.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
         }
 
-        match u {
+        let multiplier = match c {
+            'W' | 'w' => TimeDelta::weeks(1),
             'D' | 'd' => TimeDelta::days(1),
             'H' | 'h' => TimeDelta::hours(1),
             'M' | 'm' => TimeDelta::minutes(1),
             'S' | 's' => TimeDelta::seconds(1),
             _ => return Err("Not valid time string".into()),
-        }
-    } else {
-        return Err("Not valid time string".into());
-    };
+        };
 
-    let time = if let Ok(n) = string[..string.len() - 1].parse::<i32>() {
-        n
-    } else {
-        return Err("Not valid time string".into());
-    };
+        let time = match number.parse::<i32>() {
+            Ok(n) => n,
+            Err(_) => return Err("Not valid time string".into()),
+        };
+        number.clear();
+
+        total = multiplier
+            .checked_mul(time)
+            .and_then(|t| total.checked_add(&t))
+            .ok_or("Time string too large")?;
+    }
 
-    let final_time = multiplier * time;
+    // A number with no unit after it
+    if !number.is_empty() {
+        return Err("Not valid time string".into());
+    }
 
-    Ok(final_time)
+    Ok(total)
 }
 
 fn pretty_time_short(seconds: i64) -> String {
@@ -777,6 +1805,34 @@ fn pretty_time_long(seconds: i64) -> String {
     .to_string()
 }
 
+/// Show a datetime in local time, like `October 17, 14:02`
+fn pretty_date(datetime: DateTime<Utc>) -> String {
+    let datetime: DateTime<Local> = DateTime::from(datetime);
+    format!(
+        "{} {}, {:02}:{:02}",
+        Month::try_from(u8::try_from(datetime.month()).unwrap()).unwrap().name(),
+        datetime.day(),
+        datetime.hour(),
+        datetime.minute(),
+    )
+}
+
+fn pretty_size(bytes: u64) -> String {
+    let units = ["B", "kB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < units.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{bytes} {}", units[0])
+    } else {
+        format!("{size:.1} {}", units[unit])
+    }
+}
+
 fn exit_error(main_message: String, fix: Option<String>, fix_values: Option<Vec<String>>) -> ! {
     print_error_line(main_message);
 
@@ -798,6 +1854,76 @@ fn exit_error(main_message: String, fix: Option<String>, fix_values: Option<Vec<
     std::process::exit(1)
 }
 
+/// Print a QR code of `url` to the terminal, with a caption under it
+fn print_qr_code(url: &str, caption: &str) {
+    let code = match QrCode::new(url) {
+        Ok(c) => c,
+        Err(e) => {
+            print_warning_line(format!("Could not make a QR code: {e}"));
+            return;
+        }
+    };
+
+    // Terminals usually draw light text on a dark background, so the blocks
+    // are drawn for the light parts of the code to make it come out right
+    let image = code.render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build();
+    println!("{image}");
+    println!("{}", caption.truecolor(246,199,219));
+}
+
 fn print_error_line(message: String) {
     eprintln!("{}: {message}", "Error".truecolor(181,66,127).italic().underline());
 }
+
+fn print_warning_line(message: String) {
+    eprintln!("{}: {message}", "Warning".truecolor(234,129,100).italic().underline());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_mmids_and_links() {
+        let expected = Mmid("xNLF6ogx".into());
+        for input in [
+            "xNLF6ogx",
+            "  xNLF6ogx\n",
+            "https://example.com/f/xNLF6ogx",
+            "https://example.com/f/xNLF6ogx/name.jpg",
+            "https://example.com/f/xNLF6ogx/",
+            "https://example.com/filehost/f/xNLF6ogx?download=true",
+        ] {
+            assert_eq!(parse_mmid(input), Some(expected.clone()), "{input:?}");
+        }
+
+        for input in ["", "xN", "https://example.com/f/", "https://example.com/about", "xNLF-6ogx"] {
+            assert_eq!(parse_mmid(input), None, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn server_flags_are_global() {
+        let cli = Cli::try_parse_from([
+            "confetti_cli", "upload", "--server", "https://example.com", "--username", "user", "--password", "pass", "party.txt",
+        ])
+        .unwrap();
+        assert_eq!(cli.server.as_deref(), Some("https://example.com"));
+        assert_eq!(cli.username.as_deref(), Some("user"));
+        assert_eq!(cli.password.as_deref(), Some("pass"));
+        assert!(matches!(cli.command, Commands::Upload { .. }));
+    }
+
+    #[test]
+    fn old_pending_uploads_are_ignored() {
+        // They used to be kept by hash alone, which couldn't tell servers apart
+        let config: Config = toml::from_str(
+            "[pending_uploads]\nabc123 = \"67e55044-10b1-426f-9247-bb680e5fe0c8\"\n",
+        )
+        .unwrap();
+        assert!(config.unfinished_uploads.is_empty());
+    }
+}